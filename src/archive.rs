@@ -0,0 +1,72 @@
+use std::{
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+    process,
+};
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
+
+use crate::writer::ProgramError;
+
+/// A ZIP archive that rendered results are collected in with `--zip`,
+/// instead of being written to the output directory. Nothing is written
+/// until the archive is finished.
+pub(crate) struct Archive {
+    path: PathBuf,
+    entries: Vec<(String, Vec<u8>)>,
+}
+
+impl Archive {
+    pub(crate) fn new(path: &Path) -> Archive {
+        Archive {
+            path: path.to_path_buf(),
+            entries: Vec::new(),
+        }
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Adds a file to the archive, at `name` relative to its root.
+    pub(crate) fn add(&mut self, name: String, contents: Vec<u8>) -> Result<(), ProgramError> {
+        if self.entries.iter().any(|(existing, _)| *existing == name) {
+            return Err(ProgramError::DuplicateOutputFile(PathBuf::from(name)));
+        }
+        self.entries.push((name, contents));
+        Ok(())
+    }
+
+    fn write_to(&self, file: File) -> zip::result::ZipResult<()> {
+        let mut zip = ZipWriter::new(file);
+        let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+        for (name, contents) in &self.entries {
+            zip.start_file(name.as_str(), options)?;
+            zip.write_all(contents)?;
+        }
+        zip.finish()?.sync_all()?;
+        Ok(())
+    }
+
+    /// Writes the archive. It's written next to its final location first and
+    /// then moved into place, so that it's never seen half-written.
+    pub(crate) fn finish(self) -> Result<usize, ProgramError> {
+        let mut name = self.path.file_name().unwrap_or_default().to_os_string();
+        name.push(format!(".{}.tmp", process::id()));
+        let temporary = self.path.with_file_name(name);
+
+        let written = File::create(&temporary)
+            .map_err(zip::result::ZipError::from)
+            .and_then(|file| self.write_to(file))
+            .map_err(|e| ProgramError::CannotWriteArchive(self.path.clone(), e.to_string()))
+            .and_then(|()| {
+                fs::rename(&temporary, &self.path)
+                    .map_err(|e| ProgramError::CannotWriteArchive(self.path.clone(), e.to_string()))
+            });
+        if written.is_err() {
+            let _ = fs::remove_file(&temporary);
+        }
+
+        written.map(|()| self.entries.len())
+    }
+}
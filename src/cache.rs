@@ -0,0 +1,66 @@
+use log::warn;
+use sha2::{Digest, Sha256};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::values::Mappings;
+
+/// The SHA-256 digest of `data`, in lowercase hex.
+pub(crate) fn hex_digest(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Remembers what each output file was last rendered from, so that outputs
+/// whose template and mappings haven't changed can be skipped.
+pub(crate) struct Cache {
+    dir: PathBuf,
+}
+
+impl Cache {
+    pub(crate) fn new(dir: PathBuf) -> Cache {
+        Cache { dir }
+    }
+
+    /// Identifies everything that goes into an output file's contents.
+    pub(crate) fn key(template: &str, mappings: &Mappings, settings: &str) -> String {
+        let mut data = template.as_bytes().to_vec();
+        data.extend(format!("\0{:?}\0{}", mappings, settings).as_bytes());
+        hex_digest(&data)
+    }
+
+    fn entry(&self, output_file: &Path) -> PathBuf {
+        let output_file = output_file
+            .canonicalize()
+            .unwrap_or_else(|_| output_file.to_path_buf());
+        self.dir
+            .join(hex_digest(output_file.to_string_lossy().as_bytes()))
+    }
+
+    /// Whether `output_file` exists as a regular file and was last rendered
+    /// from `key`. Pipes and devices are never considered up to date.
+    pub(crate) fn is_fresh(&self, output_file: &Path, key: &str) -> bool {
+        output_file.is_file()
+            && fs::read_to_string(self.entry(output_file))
+                .map(|stored| stored == key)
+                .unwrap_or(false)
+    }
+
+    /// Records that `output_file` was rendered from `key`. A cache that can't
+    /// be written only costs time on the next run, so failures are warned
+    /// about rather than treated as errors.
+    pub(crate) fn store(&self, output_file: &Path, key: &str) {
+        let stored =
+            fs::create_dir_all(&self.dir).and_then(|_| fs::write(self.entry(output_file), key));
+        if stored.is_err() {
+            warn!(
+                "Couldn't update the cache in {:?} for {:?}.",
+                self.dir, output_file
+            );
+        }
+    }
+}
@@ -0,0 +1,70 @@
+use handlebars::JsonValue;
+use log::info;
+use std::{
+    process::{Command, Stdio},
+    str::FromStr,
+};
+
+use crate::writer::ProgramError;
+
+/// A mapping whose value is the output of a command, such as
+/// `commit="git rev-parse HEAD"`. Like a post-hook, the command is split at
+/// whitespace rather than run through a shell.
+#[derive(Debug, Clone)]
+pub(crate) struct ValueCommand {
+    key: String,
+    program: String,
+    args: Vec<String>,
+}
+
+impl FromStr for ValueCommand {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (key, command) = match s.find('=') {
+            Some(i) if i > 0 => (&s[..i], &s[i + 1..]),
+            _ => return Err(format!("Expected key=\"command\", not {:?}.", s)),
+        };
+        let mut words = command.split_whitespace().map(String::from);
+        match words.next() {
+            Some(program) => Ok(ValueCommand {
+                key: key.to_string(),
+                program,
+                args: words.collect(),
+            }),
+            None => Err(format!("The command for {:?} is empty.", key)),
+        }
+    }
+}
+
+impl ValueCommand {
+    /// Runs the command and returns its key along with its output, with the
+    /// whitespace around it removed. The command's stderr is passed through.
+    pub(crate) fn run(&self) -> Result<(String, JsonValue), ProgramError> {
+        let failed = |reason: String| ProgramError::ValueCommandFailed(self.key.clone(), reason);
+        info!(
+            "Running {:?} {:?} for the value of {:?}.",
+            self.program, self.args, self.key
+        );
+
+        let output = Command::new(&self.program)
+            .args(&self.args)
+            .stdin(Stdio::null())
+            .stderr(Stdio::inherit())
+            .output()
+            .map_err(|e| failed(format!("couldn't run {:?}: {}", self.program, e)))?;
+        if !output.status.success() {
+            return Err(failed(format!(
+                "{:?} exited with {}",
+                self.program, output.status
+            )));
+        }
+
+        let value = String::from_utf8(output.stdout)
+            .map_err(|_| failed(format!("the output of {:?} isn't UTF-8", self.program)))?;
+        Ok((
+            self.key.clone(),
+            JsonValue::String(value.trim().to_string()),
+        ))
+    }
+}
@@ -0,0 +1,178 @@
+use handlebars::JsonValue;
+
+use crate::values::Mappings;
+
+/// The annotation that lists the keys a template requires, as in
+/// `{{! replacer:requires = host, port }}`.
+const REQUIRES: &str = "replacer:requires";
+
+/// The annotation that names a template's output file, as in
+/// `{{! replacer:output = {{env}}/app.conf }}`.
+const OUTPUT: &str = "replacer:output";
+
+/// Where the `}}` that closes a `{{! ... }}` comment is in `text`, skipping
+/// over `{{ ... }}` pairs inside it, so that annotations can contain
+/// expressions.
+fn closing_braces(text: &str) -> Option<usize> {
+    let mut depth = 0;
+    let mut i = 0;
+    while i < text.len() {
+        if text[i..].starts_with("{{") {
+            depth += 1;
+            i += 2;
+        } else if text[i..].starts_with("}}") {
+            if depth == 0 {
+                return Some(i);
+            }
+            depth -= 1;
+            i += 2;
+        } else {
+            i += text[i..].chars().next().map_or(1, char::len_utf8);
+        }
+    }
+    None
+}
+
+/// If `text` starts with a comment (`{{! ... }}` or `{{!-- ... --}}`),
+/// returns its contents and its length including the delimiters.
+fn comment_at(text: &str) -> Option<(&str, usize)> {
+    let (open, close) = if text.starts_with("{{!--") {
+        ("{{!--", "--}}")
+    } else if text.starts_with("{{!") {
+        ("{{!", "}}")
+    } else {
+        return None;
+    };
+    let end = match close {
+        "}}" => closing_braces(&text[open.len()..])?,
+        _ => text[open.len()..].find(close)?,
+    } + open.len();
+    Some((&text[open.len()..end], end + close.len()))
+}
+
+/// Removes every comment that `strip` accepts and that has nothing but
+/// spaces and tabs around it on its lines, together with those lines and
+/// their line break. Comments that share a line with anything else are left
+/// for Handlebars to remove, which keeps the whitespace around them.
+pub(crate) fn strip_lines(template: &str, strip: impl Fn(&str) -> bool) -> String {
+    let is_blank = |c: char| c == ' ' || c == '\t' || c == '\r';
+    let mut result = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while !rest.is_empty() {
+        let line_end = rest.find('\n').map_or(rest.len(), |i| i + 1);
+        let start = rest.find(|c: char| !is_blank(c)).unwrap_or(rest.len());
+        let standalone = comment_at(&rest[start..])
+            .filter(|(comment, _)| strip(comment))
+            .and_then(|(_, len)| {
+                let after = rest[start + len..].trim_start_matches(is_blank);
+                match after.strip_prefix('\n') {
+                    Some(after) => Some(after),
+                    None if after.is_empty() => Some(after),
+                    None => None,
+                }
+            });
+
+        match standalone {
+            Some(after) => rest = after,
+            None => {
+                result.push_str(&rest[..line_end]);
+                rest = &rest[line_end..];
+            }
+        }
+    }
+
+    result
+}
+
+/// The tags that open and close a `comment` block.
+const COMMENT_BLOCK_TAGS: [&str; 2] = ["{{#comment}}", "{{/comment}}"];
+
+/// Takes the `comment` block tags that are alone on their lines out of those
+/// lines, so that the lines don't leave blank lines behind whether the
+/// block's contents are kept or not.
+pub(crate) fn unwrap_comment_blocks(template: &str) -> String {
+    template
+        .split_inclusive('\n')
+        .map(|line| {
+            let tag = line.trim();
+            if COMMENT_BLOCK_TAGS.contains(&tag) {
+                tag
+            } else {
+                line
+            }
+        })
+        .collect()
+}
+
+/// The value of a comment, if it's an annotation called `name`.
+fn annotation<'c>(comment: &'c str, name: &str) -> Option<&'c str> {
+    comment
+        .trim()
+        .strip_prefix(name)?
+        .trim_start()
+        .strip_prefix('=')
+        .map(str::trim)
+}
+
+pub(crate) fn is_annotation(comment: &str) -> bool {
+    [REQUIRES, OUTPUT]
+        .iter()
+        .any(|name| annotation(comment, name).is_some())
+}
+
+/// The values of every annotation called `name` in `template`, in order.
+fn annotations<'t>(template: &'t str, name: &str) -> Vec<&'t str> {
+    let mut values = Vec::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{!") {
+        match comment_at(&rest[start..]) {
+            Some((comment, len)) => {
+                values.extend(annotation(comment, name));
+                rest = &rest[start + len..];
+            }
+            None => break,
+        }
+    }
+
+    values
+}
+
+/// Every key that the annotations in `template` require, in order.
+pub(crate) fn required_keys(template: &str) -> Vec<String> {
+    annotations(template, REQUIRES)
+        .into_iter()
+        .flat_map(|keys| keys.split(','))
+        .map(str::trim)
+        .filter(|key| !key.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// The output path that `template` declares for itself, if it does. It's
+/// a template of its own, to be rendered with the same mappings. If there's
+/// more than one, the first one counts.
+pub(crate) fn output_path(template: &str) -> Option<&str> {
+    annotations(template, OUTPUT).into_iter().next()
+}
+
+/// Whether `key` is in `mappings`. Dotted keys, like `db.host`, are looked
+/// up in nested mappings.
+pub(crate) fn has_key(mappings: &Mappings, key: &str) -> bool {
+    let mut parts = key.split('.');
+    let mut value = match parts.next().and_then(|first| mappings.get(first)) {
+        Some(value) => value,
+        None => return false,
+    };
+    for part in parts {
+        value = match value {
+            JsonValue::Object(object) => match object.get(part) {
+                Some(value) => value,
+                None => return false,
+            },
+            _ => return false,
+        };
+    }
+    !value.is_null()
+}
@@ -0,0 +1,88 @@
+use handlebars::JsonValue;
+use log::debug;
+use std::collections::BTreeMap;
+
+use crate::engine::Engine;
+use crate::redact::Redaction;
+use crate::values::Mappings;
+use crate::writer::ProgramError;
+
+/// Finds the order to compute `key` and the computed values it depends on
+/// in, adding them to `order`. `visiting` holds the keys whose dependencies
+/// are being resolved, so that a key that depends on itself is a cycle.
+fn resolve<'c>(
+    key: &'c str,
+    dependencies: &BTreeMap<&'c str, Vec<&'c str>>,
+    visiting: &mut Vec<&'c str>,
+    order: &mut Vec<&'c str>,
+) -> Result<(), ProgramError> {
+    if order.contains(&key) {
+        return Ok(());
+    }
+    if let Some(start) = visiting.iter().position(|visited| *visited == key) {
+        let mut cycle: Vec<String> = visiting[start..]
+            .iter()
+            .map(|key| key.to_string())
+            .collect();
+        cycle.push(key.to_string());
+        return Err(ProgramError::ComputedCycle(cycle));
+    }
+
+    visiting.push(key);
+    for dependency in &dependencies[key] {
+        resolve(dependency, dependencies, visiting, order)?;
+    }
+    visiting.pop();
+    order.push(key);
+    Ok(())
+}
+
+/// Renders the `computed` entries of the config file against `mappings` and
+/// adds the results to them, as strings. Entries can use each other, and are
+/// rendered after the ones they use. Keys that the mappings already have are
+/// left as they are, so that any other source can override a computed value.
+pub(crate) fn apply(
+    computed: &BTreeMap<String, String>,
+    mappings: &mut Mappings,
+    redaction: &Redaction,
+    engine: &dyn Engine,
+) -> Result<(), ProgramError> {
+    let pending: BTreeMap<&str, &str> = computed
+        .iter()
+        .filter(|(key, _)| !mappings.contains_key(*key))
+        .map(|(key, template)| (key.as_str(), template.as_str()))
+        .collect();
+
+    let mut dependencies = BTreeMap::new();
+    for (key, template) in &pending {
+        let failed = |e: ProgramError| ProgramError::CannotCompute(key.to_string(), e.to_string());
+        let mut used: Vec<&str> = Vec::new();
+        for variable in engine.variables(template).map_err(failed)? {
+            // Only the first part of a path like `db.host` is a key.
+            let name = variable.split('.').next().unwrap_or(&variable);
+            if let Some((dependency, _)) = pending.get_key_value(name) {
+                if !used.contains(dependency) {
+                    used.push(dependency);
+                }
+            }
+        }
+        dependencies.insert(*key, used);
+    }
+
+    let mut order = Vec::new();
+    for key in pending.keys() {
+        resolve(key, &dependencies, &mut Vec::new(), &mut order)?;
+    }
+
+    for key in order {
+        let value = engine
+            .render(pending[key], mappings)
+            .map_err(|e| ProgramError::CannotCompute(key.to_string(), e.to_string()))?;
+        match redaction.mask(key) {
+            Some(mask) => debug!("Computed {:?} as {}.", key, mask),
+            None => debug!("Computed {:?} as {:?}.", key, value),
+        }
+        mappings.insert(key.to_string(), JsonValue::String(value));
+    }
+    Ok(())
+}
@@ -0,0 +1,235 @@
+use std::{fmt, fs, path::Path};
+
+use crate::sqlite;
+use crate::writer::ProgramError;
+use crate::{
+    deserialize, engine_options, expand_env, expand_input_files, fetch_values, file_entries,
+    find_config_file, read_template, read_values_file, resolve_replacements_files,
+    split_front_matter, values_dir_files, Config, Opts,
+};
+
+/// Keeps track of the checks that have been run, printing each one as it
+/// finishes.
+#[derive(Default)]
+struct Checkup {
+    failures: usize,
+}
+
+impl Checkup {
+    fn pass(&mut self, what: impl fmt::Display) {
+        println!("[ok]   {}", what);
+    }
+
+    fn skip(&mut self, what: impl fmt::Display) {
+        println!("[skip] {}", what);
+    }
+
+    fn fail(&mut self, what: impl fmt::Display, error: &ProgramError, hint: &str) {
+        self.failures += 1;
+        println!("[fail] {}: {}", what, error);
+        println!("       {}", hint);
+    }
+}
+
+/// Whether files can be written to `dir`, or to the directories it would be
+/// created in if it doesn't exist. This only looks at the permissions, so
+/// nothing is written.
+fn is_writable(dir: &Path) -> bool {
+    let existing = dir
+        .ancestors()
+        .map(|ancestor| {
+            if ancestor.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                ancestor
+            }
+        })
+        .find(|ancestor| ancestor.exists());
+    existing
+        .and_then(|dir| fs::metadata(dir).ok())
+        .is_some_and(|metadata| metadata.is_dir() && !metadata.permissions().readonly())
+}
+
+fn check_config(checkup: &mut Checkup, opts: &Opts) {
+    if let Some(parent) = &opts.each_dir {
+        match fs::read_dir(parent) {
+            Ok(_) => checkup.pass(format!("the directory {:?} can be read", parent)),
+            Err(_) => checkup.fail(
+                format!("the directory {:?}", parent),
+                &ProgramError::FileNotFound(parent.clone()),
+                "Check the path given with --each-dir.",
+            ),
+        }
+        return;
+    }
+
+    let config_file = match find_config_file(&opts.config_files) {
+        Ok(config_file) => config_file,
+        Err(e) => {
+            return checkup.fail(
+                "the config file",
+                &e,
+                "Check the paths given with -c; they're relative to the current directory.",
+            )
+        }
+    };
+    let what = format!("the config file {:?}", config_file);
+    let config = match deserialize::<Config>(config_file) {
+        Ok(config) => config,
+        Err(e) => {
+            return checkup.fail(
+                what,
+                &e,
+                "The config file must be a YAML mapping with at least an output_dir.",
+            )
+        }
+    };
+    let config = match expand_env(config, opts) {
+        Ok(config) => config,
+        Err(e) => {
+            return checkup.fail(
+                what,
+                &e,
+                "Set the environment variable, or pass --allow-unset-env to treat it as empty.",
+            )
+        }
+    };
+    checkup.pass(format!("the config file {:?} is valid", config_file));
+
+    if is_writable(&config.output_dir) {
+        checkup.pass(format!(
+            "the output directory {:?} is writable",
+            config.output_dir
+        ));
+    } else {
+        checkup.fail(
+            format!("the output directory {:?}", config.output_dir),
+            &ProgramError::CannotCreateOutputDirectories(config.output_dir.clone()),
+            "Pick an output_dir that exists (or can be created) and that you can write to.",
+        );
+    }
+}
+
+fn check_replacements_files(checkup: &mut Checkup, opts: &Opts) {
+    if opts.stdin_json_values {
+        return checkup.skip("the mappings are read from stdin when rendering");
+    }
+    if let (Some(database), Some(query)) = (&opts.values_sqlite, &opts.values_query) {
+        return match sqlite::read_values(database, query) {
+            Ok(_) => checkup.pass(format!("the mappings can be read from {:?}", database)),
+            Err(e) => checkup.fail(
+                format!("the database {:?}", database),
+                &e,
+                "Check that the database exists and that the query returns a key and a value column.",
+            ),
+        };
+    }
+    if let Some(url) = &opts.values_url {
+        return match fetch_values(url, opts) {
+            Ok(_) => checkup.pass(format!("the mappings can be fetched from {:?}", url)),
+            Err(e) => checkup.fail(
+                format!("the values URL {:?}", url),
+                &e,
+                "Check that the URL is reachable, that any --values-header it needs is given, and that --values-format matches what it serves.",
+            ),
+        };
+    }
+
+    let files = match &opts.values_dir {
+        Some(dir) => match values_dir_files(dir) {
+            Ok(files) => files,
+            Err(e) => {
+                return checkup.fail(
+                    format!("the values directory {:?}", dir),
+                    &e,
+                    "Check that the directory exists and has a .yaml file for every output.",
+                )
+            }
+        },
+        None => match resolve_replacements_files(opts) {
+            Ok(files) => files,
+            Err(e) => {
+                return checkup.fail(
+                    "the replacements files",
+                    &e,
+                    "Pass --profile, or check that the file exists for the profile you picked.",
+                )
+            }
+        },
+    };
+    if opts.each_dir.is_some() {
+        return checkup.skip("the replacements files are looked for in each subdirectory");
+    }
+
+    for file in files {
+        match read_values_file(&file, opts).and_then(|value| file_entries(value, &file, opts)) {
+            Ok(_) => checkup.pass(format!("the replacements file {:?} can be read", file)),
+            Err(e @ ProgramError::FileNotFound(_)) => checkup.fail(
+                format!("the replacements file {:?}", file),
+                &e,
+                "Check the paths given with -i; they're relative to the current directory.",
+            ),
+            Err(e) => checkup.fail(
+                format!("the replacements file {:?}", file),
+                &e,
+                "It must be a YAML mapping of keys to values (or a list of them with --multi).",
+            ),
+        }
+    }
+}
+
+fn check_templates(checkup: &mut Checkup, opts: &Opts) {
+    let engine = match opts.engine.build(engine_options(opts, None)) {
+        Ok(engine) => engine,
+        Err(e) => {
+            return checkup.fail(
+                "the template engine",
+                &e,
+                "Check --helpers-dir, --allow-helpers, and --file-root.",
+            )
+        }
+    };
+    let inputs = match expand_input_files(opts) {
+        Ok(inputs) => inputs,
+        Err(e) => {
+            return checkup.fail(
+                "the templates",
+                &e,
+                "Check the paths and patterns given with -f (and --template-dir).",
+            )
+        }
+    };
+
+    for input in inputs {
+        let parsed = read_template(&input.path).and_then(|contents| {
+            let (_, template) = split_front_matter(&contents, &input.path, opts)?;
+            engine.referenced_keys(template)
+        });
+        match parsed {
+            Ok(_) => checkup.pass(format!("the template {:?} can be parsed", input.path)),
+            Err(e) => checkup.fail(
+                format!("the template {:?}", input.path),
+                &e,
+                "Fix the template so that it can be parsed.",
+            ),
+        }
+    }
+}
+
+/// Checks that everything a run needs is in place, without rendering or
+/// writing anything, and prints the result of every check to stdout.
+/// Returns whether they all passed.
+pub(crate) fn run(opts: &Opts) -> bool {
+    let mut checkup = Checkup::default();
+    check_config(&mut checkup, opts);
+    check_replacements_files(&mut checkup, opts);
+    check_templates(&mut checkup, opts);
+
+    if checkup.failures > 0 {
+        println!("{} check(s) failed.", checkup.failures);
+        false
+    } else {
+        println!("Everything looks fine.");
+        true
+    }
+}
@@ -0,0 +1,75 @@
+use std::{convert::TryFrom, str::FromStr};
+
+/// The encodings that output files can be written in.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) enum OutputEncoding {
+    #[default]
+    Utf8,
+    /// ISO 8859-1, which covers exactly the first 256 Unicode code points.
+    Latin1,
+    /// UTF-16, little endian and without a byte order mark.
+    Utf16Le,
+}
+
+impl FromStr for OutputEncoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "utf-8" | "utf8" => Ok(OutputEncoding::Utf8),
+            "latin1" | "latin-1" | "iso-8859-1" => Ok(OutputEncoding::Latin1),
+            "utf-16le" | "utf16le" => Ok(OutputEncoding::Utf16Le),
+            _ => Err(format!(
+                "Unknown encoding {:?}; expected one of \"utf-8\", \"latin1\", or \"utf-16le\".",
+                s
+            )),
+        }
+    }
+}
+
+impl OutputEncoding {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            OutputEncoding::Utf8 => "UTF-8",
+            OutputEncoding::Latin1 => "Latin-1",
+            OutputEncoding::Utf16Le => "UTF-16LE",
+        }
+    }
+
+    /// Encodes `text`. Characters that the encoding can't represent are
+    /// replaced with `?` if `lossy` is set; otherwise the first one is
+    /// returned as the error.
+    pub(crate) fn encode(self, text: &str, lossy: bool) -> Result<Vec<u8>, char> {
+        match self {
+            OutputEncoding::Utf8 => Ok(text.as_bytes().to_vec()),
+            OutputEncoding::Latin1 => text
+                .chars()
+                .map(|c| match u8::try_from(u32::from(c)) {
+                    Ok(byte) => Ok(byte),
+                    Err(_) if lossy => Ok(b'?'),
+                    Err(_) => Err(c),
+                })
+                .collect(),
+            OutputEncoding::Utf16Le => Ok(text
+                .encode_utf16()
+                .flat_map(|unit| unit.to_le_bytes())
+                .collect()),
+        }
+    }
+
+    /// Decodes `bytes` for display, replacing anything that isn't valid in
+    /// the encoding.
+    pub(crate) fn decode(self, bytes: &[u8]) -> String {
+        match self {
+            OutputEncoding::Utf8 => String::from_utf8_lossy(bytes).into_owned(),
+            OutputEncoding::Latin1 => bytes.iter().map(|&byte| char::from(byte)).collect(),
+            OutputEncoding::Utf16Le => {
+                let units: Vec<u16> = bytes
+                    .chunks(2)
+                    .map(|pair| u16::from_le_bytes([pair[0], *pair.get(1).unwrap_or(&0)]))
+                    .collect();
+                String::from_utf16_lossy(&units)
+            }
+        }
+    }
+}
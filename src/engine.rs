@@ -0,0 +1,524 @@
+use handlebars::{
+    Context, Handlebars, JsonRender, Output, RenderContext, Renderable, Template, TemplateError,
+    TemplateRenderError,
+};
+use log::trace;
+use std::{collections::BTreeSet, io, path::PathBuf, str::FromStr};
+
+use crate::helpers::Usage;
+use crate::locale::Locale;
+use crate::redact::Redaction;
+use crate::snippet::Snippet;
+use crate::values::Mappings;
+use crate::writer::ProgramError;
+use crate::{comments, helpers, inspect, wasm};
+
+/// A way of turning a template and a set of mappings into a rendered result.
+pub(crate) trait Engine {
+    fn render(&self, template: &str, mappings: &Mappings) -> Result<String, ProgramError>;
+
+    /// The keys that `template` substitutes directly, in the order they
+    /// appear and once per appearance.
+    fn referenced_keys(&self, template: &str) -> Result<Vec<String>, ProgramError>;
+
+    /// Every key that `template` uses, including the ones it only passes to
+    /// helpers.
+    fn variables(&self, template: &str) -> Result<Vec<String>, ProgramError> {
+        self.referenced_keys(template)
+    }
+
+    /// The source that's actually rendered for `template`, once the engine
+    /// has removed what isn't meant to be rendered.
+    fn preprocess(&self, template: &str) -> String {
+        template.to_string()
+    }
+
+    /// The elements that `template` is parsed into, as a debug
+    /// representation, for engines that parse templates into a tree.
+    fn syntax_tree(&self, _template: &str) -> Result<String, ProgramError> {
+        Err(ProgramError::NoSyntaxTree)
+    }
+
+    /// Describes the engine and the settings that change what it renders,
+    /// for the cache to tell outputs rendered with different ones apart.
+    fn settings(&self) -> String;
+
+    /// Whether what `template` renders to depends on nothing but the
+    /// template, the mappings, and the engine's settings, so that its
+    /// output can be cached.
+    fn is_cacheable(&self, _template: &str) -> bool {
+        true
+    }
+}
+
+/// The template engines that can be selected from the command line.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum EngineKind {
+    Handlebars,
+    Envsubst,
+}
+
+impl FromStr for EngineKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "handlebars" => Ok(EngineKind::Handlebars),
+            "envsubst" => Ok(EngineKind::Envsubst),
+            other => Err(format!(
+                "Unknown engine {:?}; expected one of \"handlebars\" or \"envsubst\".",
+                other
+            )),
+        }
+    }
+}
+
+/// What to do with references to keys that aren't in the mappings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum OnMissing {
+    /// Fail to render the template.
+    #[default]
+    Error,
+    /// Render nothing in their place.
+    Empty,
+    /// Leave the reference in the output as it was written, for a later
+    /// rendering pass to fill in.
+    Keep,
+}
+
+impl FromStr for OnMissing {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(OnMissing::Error),
+            "empty" => Ok(OnMissing::Empty),
+            "keep" => Ok(OnMissing::Keep),
+            other => Err(format!(
+                "Unknown policy {:?}; expected one of \"error\", \"empty\", or \"keep\".",
+                other
+            )),
+        }
+    }
+}
+
+/// Settings for the template engines. Engines ignore the settings that
+/// don't apply to them.
+#[derive(Default)]
+pub(crate) struct EngineOptions {
+    /// If set, the only helpers templates may use.
+    pub(crate) allowed_helpers: Option<BTreeSet<String>>,
+    /// Whether to log every substituted variable and its value.
+    pub(crate) trace_render: bool,
+    /// Keys whose values must never be logged.
+    pub(crate) redact: Redaction,
+    /// What to do with references to missing keys.
+    pub(crate) on_missing: OnMissing,
+    /// A directory of WASM modules to register as helpers.
+    pub(crate) helpers_dir: Option<PathBuf>,
+    /// Where to count how often each helper is called, if anywhere.
+    pub(crate) helper_usage: Option<Usage>,
+    /// What to seed the random helpers with, if anything.
+    pub(crate) seed: Option<u64>,
+    /// The directory that `iffile` paths are relative to, if it isn't the
+    /// current one.
+    pub(crate) file_root: Option<PathBuf>,
+    /// Whether to remove comments that are alone on their lines, along with
+    /// the lines.
+    pub(crate) strip_comments: bool,
+    /// Whether to render the contents of `comment` blocks, rather than
+    /// leave them out.
+    pub(crate) keep_comments: bool,
+    /// The profile given with `--profile`, which `ifprofile` blocks check.
+    pub(crate) profile: Option<String>,
+    /// The locale that the `number` and `date` helpers format values for.
+    pub(crate) locale: Option<Locale>,
+    /// Whether to disable the helpers that read the environment or the file
+    /// system.
+    pub(crate) safe: bool,
+}
+
+impl EngineOptions {
+    /// The options that change what templates render to, for `settings`.
+    fn settings(&self) -> String {
+        format!(
+            "on-missing={:?}:seed={:?}:strip-comments={}:keep-comments={}:locale={:?}:profile={:?}",
+            self.on_missing,
+            self.seed,
+            self.strip_comments,
+            self.keep_comments,
+            self.locale,
+            self.profile
+        )
+    }
+
+    fn trace_substitution(&self, key: &str, value: Option<&str>) {
+        if !self.trace_render {
+            return;
+        }
+
+        match value {
+            Some(value) => match self.redact.mask(key) {
+                Some(mask) => trace!("{} -> {}", key, mask),
+                None => trace!("{} -> {:?}", key, value),
+            },
+            None => trace!("{} -> <not a top-level mapping>", key),
+        }
+    }
+}
+
+impl EngineKind {
+    pub(crate) fn build(self, options: EngineOptions) -> Result<Box<dyn Engine>, ProgramError> {
+        Ok(match self {
+            EngineKind::Handlebars => Box::new(HandlebarsEngine::new(options)?),
+            EngineKind::Envsubst => Box::new(EnvsubstEngine { options }),
+        })
+    }
+}
+
+/// Renders templates as Handlebars, in strict mode and with replacer's own
+/// helpers registered.
+pub(crate) struct HandlebarsEngine {
+    handlebars: Handlebars<'static>,
+    options: EngineOptions,
+}
+
+impl HandlebarsEngine {
+    pub(crate) fn new(options: EngineOptions) -> Result<Self, ProgramError> {
+        let mut handlebars = Handlebars::new();
+        handlebars.set_strict_mode(options.on_missing == OnMissing::Error);
+        let custom = match &options.helpers_dir {
+            Some(dir) => wasm::load(dir)?,
+            None => Vec::new(),
+        };
+        helpers::register(&mut handlebars, custom, &options)?;
+        if options.on_missing == OnMissing::Keep {
+            helpers::keep_missing(&mut handlebars);
+        }
+
+        Ok(HandlebarsEngine {
+            handlebars,
+            options,
+        })
+    }
+
+    fn is_helper(&self, name: &str) -> bool {
+        self.handlebars.get_helper(name).is_some()
+    }
+
+    fn trace_substitutions(&self, template: &Template, mappings: &Mappings) {
+        for key in inspect::substitutions(template, &|name| self.is_helper(name)) {
+            let value = mappings.get(&key).map(JsonRender::render);
+            self.options.trace_substitution(&key, value.as_deref());
+        }
+    }
+
+    fn check_helpers(
+        &self,
+        template: &Template,
+        allowed: &BTreeSet<String>,
+    ) -> Result<(), ProgramError> {
+        let disallowed: Vec<String> = inspect::helpers_used(template, &|name| self.is_helper(name))
+            .into_iter()
+            .filter(|name| !allowed.contains(name))
+            .collect();
+
+        if disallowed.is_empty() {
+            Ok(())
+        } else {
+            Err(ProgramError::DisallowedHelpers(disallowed))
+        }
+    }
+
+    /// Renders `template` like `render_template` does, but with its
+    /// triple-stash expressions rewritten so that the ones whose keys are
+    /// missing are kept too.
+    fn render_keeping_raw(
+        &self,
+        template: &str,
+        mappings: &Mappings,
+    ) -> Result<String, ProgramError> {
+        let failed = |e: TemplateRenderError| render_error(template, e);
+        let mut compiled = Template::compile(template).map_err(|e| failed(e.into()))?;
+        helpers::keep_missing_raw(&mut compiled.elements);
+        let context = Context::wraps(mappings).map_err(|e| failed(e.into()))?;
+        let mut rendered = StringOutput(String::new());
+        compiled
+            .render(
+                &self.handlebars,
+                &context,
+                &mut RenderContext::new(None),
+                &mut rendered,
+            )
+            .map_err(|e| failed(e.into()))?;
+        Ok(rendered.0)
+    }
+
+    fn check_safe(&self, template: &Template) -> Result<(), ProgramError> {
+        let unsafe_helpers: Vec<String> =
+            inspect::helpers_used(template, &|name| self.is_helper(name))
+                .into_iter()
+                .filter(|name| helpers::UNSAFE.contains(&name.as_str()))
+                .collect();
+
+        if unsafe_helpers.is_empty() {
+            Ok(())
+        } else {
+            Err(ProgramError::UnsafeHelpers(unsafe_helpers))
+        }
+    }
+}
+
+/// The error for a template that fails to render.
+fn render_error(template: &str, e: TemplateRenderError) -> ProgramError {
+    match e {
+        TemplateRenderError::TemplateError(err) => invalid_template(template, &err),
+        TemplateRenderError::RenderError(e) => {
+            if e.desc.starts_with("Variable") {
+                ProgramError::MissingKey(e.desc)
+            } else if e.desc.starts_with("Template not found") {
+                ProgramError::InvalidTemplate("Couldn't recognize template.".to_string(), None)
+            } else {
+                let snippet = Snippet::at(template, e.line_no, e.column_no);
+                ProgramError::RenderError(e.desc, snippet)
+            }
+        }
+        TemplateRenderError::IOError(_, _) => {
+            ProgramError::RenderError(String::from("I/O Error when rendering template."), None)
+        }
+    }
+}
+
+/// Collects rendered output in a string.
+struct StringOutput(String);
+
+impl Output for StringOutput {
+    fn write(&mut self, segment: &str) -> Result<(), io::Error> {
+        self.0.push_str(segment);
+        Ok(())
+    }
+}
+
+/// The error for a template that can't be parsed, with the part of it
+/// where parsing failed.
+fn invalid_template(template: &str, e: &TemplateError) -> ProgramError {
+    ProgramError::InvalidTemplate(
+        e.reason.to_string(),
+        Snippet::at(template, e.line_no, e.column_no),
+    )
+}
+
+impl Engine for HandlebarsEngine {
+    fn referenced_keys(&self, template: &str) -> Result<Vec<String>, ProgramError> {
+        let compiled = Template::compile(template).map_err(|e| invalid_template(template, &e))?;
+        Ok(inspect::substitutions(&compiled, &|name| {
+            self.is_helper(name)
+        }))
+    }
+
+    fn variables(&self, template: &str) -> Result<Vec<String>, ProgramError> {
+        let compiled = Template::compile(template).map_err(|e| invalid_template(template, &e))?;
+        Ok(inspect::variables(&compiled, &|name| self.is_helper(name)))
+    }
+
+    fn preprocess(&self, template: &str) -> String {
+        let template = if self.options.strip_comments {
+            comments::strip_lines(template, |_| true)
+        } else {
+            comments::strip_lines(template, comments::is_annotation)
+        };
+        comments::unwrap_comment_blocks(&template)
+    }
+
+    fn syntax_tree(&self, template: &str) -> Result<String, ProgramError> {
+        let template = self.preprocess(template);
+        let compiled = Template::compile(&template).map_err(|e| invalid_template(&template, &e))?;
+        Ok(format!("{:#?}", compiled.elements))
+    }
+
+    fn settings(&self) -> String {
+        format!("handlebars:{}", self.options.settings())
+    }
+
+    /// Templates that use the helpers that read the environment or the file
+    /// system can render differently with the same mappings.
+    fn is_cacheable(&self, template: &str) -> bool {
+        match Template::compile(self.preprocess(template)) {
+            Ok(compiled) => inspect::helpers_used(&compiled, &|name| self.is_helper(name))
+                .iter()
+                .all(|name| !helpers::UNSAFE.contains(&name.as_str())),
+            Err(_) => false,
+        }
+    }
+
+    fn render(&self, template: &str, mappings: &Mappings) -> Result<String, ProgramError> {
+        let missing: Vec<String> = comments::required_keys(template)
+            .into_iter()
+            .filter(|key| !comments::has_key(mappings, key))
+            .collect();
+        if !missing.is_empty() {
+            return Err(ProgramError::MissingKey(format!(
+                "The template requires keys that aren't in the mappings: {}.",
+                missing.join(", ")
+            )));
+        }
+
+        let template = self.preprocess(template);
+        let template = template.as_str();
+        if self.options.allowed_helpers.is_some() || self.options.safe || self.options.trace_render
+        {
+            let compiled =
+                Template::compile(template).map_err(|e| invalid_template(template, &e))?;
+            if self.options.safe {
+                self.check_safe(&compiled)?;
+            }
+            if let Some(allowed) = &self.options.allowed_helpers {
+                self.check_helpers(&compiled, allowed)?;
+            }
+            if self.options.trace_render {
+                self.trace_substitutions(&compiled, mappings);
+            }
+        }
+
+        if self.options.on_missing == OnMissing::Keep {
+            return self.render_keeping_raw(template, mappings);
+        }
+        self.handlebars
+            .render_template(template, mappings)
+            .map_err(|e| render_error(template, e))
+    }
+}
+
+/// Replaces `${KEY}` and `$KEY` references with their mappings, like the
+/// `envsubst` utility. Nothing else in the template is interpreted.
+pub(crate) struct EnvsubstEngine {
+    options: EngineOptions,
+}
+
+fn is_key_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_'
+}
+
+fn is_key_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+impl EnvsubstEngine {
+    fn lookup(&self, mappings: &Mappings, key: &str) -> Result<String, ProgramError> {
+        let value = mappings
+            .get(key)
+            .map(JsonRender::render)
+            .ok_or_else(|| ProgramError::MissingKey(format!("Variable {:?} not found.", key)))?;
+        self.options.trace_substitution(key, Some(&value));
+        Ok(value)
+    }
+}
+
+impl Engine for EnvsubstEngine {
+    fn settings(&self) -> String {
+        format!("envsubst:{}", self.options.settings())
+    }
+
+    fn referenced_keys(&self, template: &str) -> Result<Vec<String>, ProgramError> {
+        let mut keys = Vec::new();
+        substitute(template, &mut |key| {
+            keys.push(key.to_string());
+            Ok(None)
+        })?;
+        Ok(keys)
+    }
+
+    fn render(&self, template: &str, mappings: &Mappings) -> Result<String, ProgramError> {
+        substitute(template, &mut |key| match self.lookup(mappings, key) {
+            Err(ProgramError::MissingKey(_)) if self.options.on_missing == OnMissing::Empty => {
+                Ok(Some(String::new()))
+            }
+            Err(ProgramError::MissingKey(_)) if self.options.on_missing == OnMissing::Keep => {
+                Ok(None)
+            }
+            result => result.map(Some),
+        })
+    }
+}
+
+/// Replaces every `${KEY}` and `$KEY` reference in `text` with what `lookup`
+/// returns for the key. References that `lookup` returns `None` for, and any
+/// `$` that doesn't start a reference, are kept as they are.
+pub(crate) fn substitute(
+    text: &str,
+    lookup: &mut dyn FnMut(&str) -> Result<Option<String>, ProgramError>,
+) -> Result<String, ProgramError> {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find('$') {
+        result.push_str(&rest[..start]);
+        let reference = &rest[start + 1..];
+
+        if let Some(braced) = reference.strip_prefix('{') {
+            match braced.find('}') {
+                Some(end)
+                    if braced[..end].starts_with(is_key_start)
+                        && braced[..end].chars().all(is_key_char) =>
+                {
+                    match lookup(&braced[..end])? {
+                        Some(value) => result.push_str(&value),
+                        None => result.push_str(&rest[start..start + end + 3]),
+                    }
+                    rest = &braced[end + 1..];
+                    continue;
+                }
+                _ => {}
+            }
+        } else if reference.starts_with(is_key_start) {
+            let end = reference
+                .find(|c| !is_key_char(c))
+                .unwrap_or(reference.len());
+            match lookup(&reference[..end])? {
+                Some(value) => result.push_str(&value),
+                None => result.push_str(&rest[start..start + end + 1]),
+            }
+            rest = &reference[end..];
+            continue;
+        }
+
+        result.push('$');
+        rest = reference;
+    }
+
+    result.push_str(rest);
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use handlebars::JsonValue;
+
+    fn render_keeping_missing(template: &str) -> String {
+        let engine = HandlebarsEngine::new(EngineOptions {
+            on_missing: OnMissing::Keep,
+            ..EngineOptions::default()
+        })
+        .unwrap();
+        let mut mappings = Mappings::new();
+        mappings.insert("port".to_string(), JsonValue::from("<80>"));
+        engine.render(template, &mappings).unwrap()
+    }
+
+    #[test]
+    fn keeping_missing_keys_keeps_double_stash_references() {
+        assert_eq!(
+            render_keeping_missing("{{port}} {{host}}"),
+            "&lt;80&gt; {{host}}"
+        );
+    }
+
+    #[test]
+    fn keeping_missing_keys_keeps_triple_stash_references() {
+        assert_eq!(
+            render_keeping_missing("{{{port}}} {{{host}}} {{#if port}}{{{db.host}}}{{/if}}"),
+            "<80> {{{host}}} {{{db.host}}}"
+        );
+    }
+}
@@ -0,0 +1,135 @@
+use serde_yaml::{Mapping, Value};
+use std::{path::Path, str::FromStr};
+
+/// The formats that replacements files can be written in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum ValuesFormat {
+    /// YAML, which also covers most JSON.
+    Yaml,
+    Json,
+    Toml,
+    /// `KEY=value` lines, as in a `.env` file.
+    Env,
+}
+
+impl FromStr for ValuesFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "yaml" | "yml" => Ok(ValuesFormat::Yaml),
+            "json" => Ok(ValuesFormat::Json),
+            "toml" => Ok(ValuesFormat::Toml),
+            "env" => Ok(ValuesFormat::Env),
+            _ => Err(format!(
+                "Unknown format {:?}; expected one of \"yaml\", \"json\", \"toml\", or \"env\".",
+                s
+            )),
+        }
+    }
+}
+
+impl ValuesFormat {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            ValuesFormat::Yaml => "YAML",
+            ValuesFormat::Json => "JSON",
+            ValuesFormat::Toml => "TOML",
+            ValuesFormat::Env => "an env file",
+        }
+    }
+
+    /// The format of the file at `path`, going by its extension. Files that
+    /// aren't TOML or env files are read as YAML, which JSON files are too.
+    pub(crate) fn detect(path: &Path) -> ValuesFormat {
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or("");
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("toml") => ValuesFormat::Toml,
+            Some("env") => ValuesFormat::Env,
+            _ if name == ".env" => ValuesFormat::Env,
+            _ => ValuesFormat::Yaml,
+        }
+    }
+}
+
+fn from_toml(value: toml::Value) -> Value {
+    match value {
+        toml::Value::String(string) => Value::String(string),
+        toml::Value::Integer(integer) => Value::from(integer),
+        toml::Value::Float(float) => Value::from(float),
+        toml::Value::Boolean(boolean) => Value::Bool(boolean),
+        // Dates are kept as they're written, like they are in YAML.
+        toml::Value::Datetime(datetime) => Value::String(datetime.to_string()),
+        toml::Value::Array(array) => Value::Sequence(array.into_iter().map(from_toml).collect()),
+        toml::Value::Table(table) => Value::Mapping(
+            table
+                .into_iter()
+                .map(|(key, value)| (Value::String(key), from_toml(value)))
+                .collect(),
+        ),
+    }
+}
+
+/// Parses a TOML document into the same shape a YAML file is read into.
+pub(crate) fn parse_toml(contents: &str) -> Result<Value, String> {
+    contents
+        .parse::<toml::Table>()
+        .map(|table| from_toml(toml::Value::Table(table)))
+        .map_err(|e| match e.span() {
+            Some(span) => format!(
+                "{} on line {}",
+                e.message(),
+                contents[..span.start].matches('\n').count() + 1
+            ),
+            None => e.message().to_string(),
+        })
+}
+
+/// Parses a JSON document into the same shape a YAML file is read into.
+pub(crate) fn parse_json(contents: &str) -> Result<Value, String> {
+    serde_json::from_str::<serde_json::Value>(contents)
+        .map_err(|e| e.to_string())
+        .and_then(|value| serde_yaml::to_value(value).map_err(|e| e.to_string()))
+}
+
+/// Removes the quotes around an env file value, if it has any.
+fn unquote(value: &str) -> &str {
+    for quote in ['"', '\''] {
+        if let Some(inner) = value
+            .strip_prefix(quote)
+            .and_then(|value| value.strip_suffix(quote))
+        {
+            return inner;
+        }
+    }
+    value
+}
+
+/// Parses the `KEY=value` lines of an env file into a mapping of strings.
+/// Blank lines and lines starting with `#` are skipped, an `export` before
+/// the key is allowed, and quotes around a value are removed.
+pub(crate) fn parse_env(contents: &str) -> Result<Value, String> {
+    let mut mapping = Mapping::new();
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| format!("line {} isn't a KEY=value pair", index + 1))?;
+        let key = key.trim();
+        if key.is_empty() {
+            return Err(format!("line {} has no key", index + 1));
+        }
+        mapping.insert(
+            Value::String(key.to_string()),
+            Value::String(unquote(value.trim()).to_string()),
+        );
+    }
+    Ok(Value::Mapping(mapping))
+}
@@ -0,0 +1,40 @@
+use std::path::Path;
+
+use crate::writer::ProgramError;
+
+/// The line that opens and closes a template's front matter.
+const DELIMITER: &str = "---";
+
+/// Whether `line` (with its line break) is a front matter delimiter.
+fn is_delimiter(line: &str) -> bool {
+    line.trim_end_matches(&['\r', '\n'][..]) == DELIMITER
+}
+
+/// Splits a template into its YAML front matter and its body, for
+/// `--single-file`. The front matter is everything between a `---` on the
+/// first line and the next line that is `---`; a template that doesn't start
+/// with `---` has none. Only the first two delimiters count, so the body can
+/// contain `---` lines of its own.
+pub(crate) fn split<'t>(
+    template: &'t str,
+    path: &Path,
+) -> Result<(Option<&'t str>, &'t str), ProgramError> {
+    let first_line = template.split_inclusive('\n').next().unwrap_or("");
+    if !is_delimiter(first_line) {
+        return Ok((None, template));
+    }
+
+    let front_matter = &template[first_line.len()..];
+    let mut offset = 0;
+    for line in front_matter.split_inclusive('\n') {
+        if is_delimiter(line) {
+            return Ok((
+                Some(&front_matter[..offset]),
+                &front_matter[offset + line.len()..],
+            ));
+        }
+        offset += line.len();
+    }
+
+    Err(ProgramError::UnterminatedFrontMatter(path.to_path_buf()))
+}
@@ -0,0 +1,221 @@
+use std::{ffi::OsString, path::Path, process::Command, str::FromStr};
+
+/// What git has staged for a file.
+pub(crate) enum Indexed {
+    Contents(Vec<u8>),
+    /// The file is in a repository, but not in its index.
+    Untracked,
+    /// The file isn't in a git repository, or git couldn't be run.
+    Unavailable,
+}
+
+/// Reads the version of `path` that's in the git index (which is what's
+/// committed in `HEAD`, unless changes to it have been staged). The file
+/// itself doesn't need to exist.
+pub(crate) fn indexed(path: &Path) -> Indexed {
+    let (dir, name) = match (path.parent(), path.file_name()) {
+        (Some(dir), Some(name)) => (dir, name),
+        _ => return Indexed::Unavailable,
+    };
+    let dir = if dir.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        dir
+    };
+
+    let in_repository = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(["rev-parse", "--is-inside-work-tree"])
+        .output()
+        .map(|output| output.status.success())
+        .unwrap_or(false);
+    if !in_repository {
+        return Indexed::Unavailable;
+    }
+
+    let mut spec = OsString::from(":./");
+    spec.push(name);
+    match Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .arg("show")
+        .arg(spec)
+        .output()
+    {
+        Ok(output) if output.status.success() => Indexed::Contents(output.stdout),
+        Ok(_) => Indexed::Untracked,
+        Err(_) => Indexed::Unavailable,
+    }
+}
+
+/// Who `--commit` attributes its commit to, given as `Name <email>`.
+#[derive(Debug, Clone)]
+#[cfg_attr(not(feature = "git"), allow(dead_code))]
+pub(crate) struct Author {
+    name: String,
+    email: String,
+}
+
+impl FromStr for Author {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Expected \"Name <email>\", not {:?}.", s);
+        let (name, email) = s
+            .trim()
+            .strip_suffix('>')
+            .and_then(|s| s.split_once('<'))
+            .ok_or_else(invalid)?;
+        let (name, email) = (name.trim(), email.trim());
+        if name.is_empty() || email.is_empty() {
+            return Err(invalid());
+        }
+
+        Ok(Author {
+            name: name.to_string(),
+            email: email.to_string(),
+        })
+    }
+}
+
+#[cfg(feature = "git")]
+pub(crate) use repository::Committer;
+
+#[cfg(feature = "git")]
+mod repository {
+    use git2::{ErrorCode, Repository, Signature};
+    use std::{
+        fs,
+        path::{Path, PathBuf},
+    };
+
+    use super::Author;
+    use crate::writer::ProgramError;
+
+    fn failed(e: git2::Error) -> ProgramError {
+        ProgramError::CannotCommit(e.message().to_string())
+    }
+
+    /// Commits the output files of a run, for `--commit`.
+    pub(crate) struct Committer {
+        message: String,
+        author: Option<Author>,
+    }
+
+    impl Committer {
+        pub(crate) fn new(
+            message: &str,
+            author: Option<&Author>,
+        ) -> Result<Committer, ProgramError> {
+            Ok(Committer {
+                message: message.to_string(),
+                author: author.cloned(),
+            })
+        }
+
+        /// Stages `paths` in the repository they're in and commits them. Returns
+        /// the id of the commit, or `None` if the files are the same as in the
+        /// last commit, in which case nothing is committed.
+        pub(crate) fn commit(&self, paths: &[PathBuf]) -> Result<Option<String>, ProgramError> {
+            let first = match paths.first() {
+                Some(first) => first,
+                None => return Ok(None),
+            };
+            let start = match first.parent() {
+                Some(parent) if parent != Path::new("") => parent,
+                _ => Path::new("."),
+            };
+            let repo = Repository::discover(start).map_err(failed)?;
+            let workdir = repo
+                .workdir()
+                .and_then(|workdir| fs::canonicalize(workdir).ok())
+                .ok_or_else(|| {
+                    ProgramError::CannotCommit(String::from("the repository has no working tree"))
+                })?;
+
+            let mut index = repo.index().map_err(failed)?;
+            for path in paths {
+                let relative = fs::canonicalize(path)
+                    .ok()
+                    .and_then(|path| path.strip_prefix(&workdir).ok().map(Path::to_path_buf))
+                    .ok_or_else(|| {
+                        ProgramError::CannotCommit(format!("{:?} isn't in {:?}", path, workdir))
+                    })?;
+                index.add_path(&relative).map_err(failed)?;
+            }
+            index.write().map_err(failed)?;
+            let tree = repo
+                .find_tree(index.write_tree().map_err(failed)?)
+                .map_err(failed)?;
+
+            let parent = match repo.head() {
+                Ok(head) => Some(head.peel_to_commit().map_err(failed)?),
+                Err(e)
+                    if e.code() == ErrorCode::UnbornBranch || e.code() == ErrorCode::NotFound =>
+                {
+                    None
+                }
+                Err(e) => return Err(failed(e)),
+            };
+            if parent.as_ref().map(|parent| parent.tree_id()) == Some(tree.id()) {
+                return Ok(None);
+            }
+
+            // The committer is whoever git is configured for, falling back to
+            // the author if it isn't configured.
+            let author = self
+                .author
+                .as_ref()
+                .map(|author| Signature::now(&author.name, &author.email))
+                .transpose()
+                .map_err(failed)?;
+            let committer = match (repo.signature(), &author) {
+            (Ok(committer), _) => committer,
+            (Err(_), Some(author)) => author.clone(),
+            (Err(_), None) => {
+                return Err(ProgramError::CannotCommit(String::from(
+                    "git has no user.name and user.email configured; set them or pass --commit-author",
+                )))
+            }
+        };
+            let author = author.unwrap_or_else(|| committer.clone());
+
+            let parents: Vec<_> = parent.iter().collect();
+            let id = repo
+                .commit(
+                    Some("HEAD"),
+                    &author,
+                    &committer,
+                    &self.message,
+                    &tree,
+                    &parents,
+                )
+                .map_err(failed)?;
+            Ok(Some(id.to_string()))
+        }
+    }
+}
+
+#[cfg(not(feature = "git"))]
+pub(crate) use unsupported::Committer;
+
+#[cfg(not(feature = "git"))]
+mod unsupported {
+    use std::path::PathBuf;
+
+    use super::Author;
+    use crate::writer::ProgramError;
+
+    pub(crate) struct Committer;
+
+    impl Committer {
+        pub(crate) fn new(_: &str, _: Option<&Author>) -> Result<Committer, ProgramError> {
+            Err(ProgramError::GitUnsupported)
+        }
+
+        pub(crate) fn commit(&self, _: &[PathBuf]) -> Result<Option<String>, ProgramError> {
+            Err(ProgramError::GitUnsupported)
+        }
+    }
+}
@@ -0,0 +1,1307 @@
+use handlebars::template::{HelperTemplate, Parameter, TemplateElement};
+use handlebars::{
+    BlockContext, Context, Handlebars, Helper, HelperDef, HelperResult, JsonRender, JsonValue,
+    Output, PathAndJson, RenderContext, RenderError, Renderable, ScopedJson,
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, HashMap},
+    env,
+    path::Path,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use crate::cache::hex_digest;
+use crate::engine::{EngineOptions, OnMissing};
+use crate::locale::{self, Locale};
+use crate::sandbox::Root;
+use crate::writer::ProgramError;
+
+/// Compares its two parameters and either renders its block (or `{{else}}`
+/// branch) or, when used inline or as a subexpression, returns the result as
+/// a boolean.
+struct Comparison {
+    name: &'static str,
+    accepts: fn(Ordering) -> bool,
+}
+
+/// Whether `s` is a number written the one way it would be written as a
+/// number: digits without leading zeros, and a fraction without trailing
+/// ones. Strings like `1.10`, `007`, and `nan` are text, not numbers, since
+/// version numbers, zip codes, and IDs must be compared as they're written.
+fn is_plain_number(s: &str) -> bool {
+    let unsigned = s.strip_prefix('-').unwrap_or(s);
+    let (whole, fraction) = match unsigned.split_once('.') {
+        Some((whole, fraction)) => (whole, Some(fraction)),
+        None => (unsigned, None),
+    };
+    let is_digits = |part: &str| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit());
+    is_digits(whole)
+        && (whole == "0" || !whole.starts_with('0'))
+        && fraction.is_none_or(|fraction| is_digits(fraction) && !fraction.ends_with('0'))
+}
+
+fn as_number(value: &JsonValue) -> Option<f64> {
+    match value {
+        JsonValue::Number(n) => n.as_f64(),
+        JsonValue::String(s) if is_plain_number(s) => s.parse().ok(),
+        _ => None,
+    }
+}
+
+fn compare(left: &JsonValue, right: &JsonValue) -> Option<Ordering> {
+    match (as_number(left), as_number(right)) {
+        (Some(l), Some(r)) => l.partial_cmp(&r),
+        _ => Some(left.render().cmp(&right.render())),
+    }
+}
+
+impl Comparison {
+    fn evaluate(&self, h: &Helper) -> Result<bool, RenderError> {
+        match (h.param(0), h.param(1)) {
+            (Some(left), Some(right)) => Ok(compare(left.value(), right.value())
+                .map(self.accepts)
+                .unwrap_or(false)),
+            _ => Err(RenderError::new(format!(
+                "The `{}` helper needs two values to compare.",
+                self.name
+            ))),
+        }
+    }
+}
+
+impl HelperDef for Comparison {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<Option<ScopedJson<'reg, 'rc>>, RenderError> {
+        self.evaluate(h)
+            .map(|result| Some(ScopedJson::Derived(JsonValue::Bool(result))))
+    }
+
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        r: &'reg Handlebars<'reg>,
+        ctx: &'rc Context,
+        rc: &mut RenderContext<'reg, 'rc>,
+        out: &mut dyn Output,
+    ) -> HelperResult {
+        let result = self.evaluate(h)?;
+        if !h.is_block() {
+            out.write(&result.to_string())?;
+            return Ok(());
+        }
+
+        match if result { h.template() } else { h.inverse() } {
+            Some(template) => template.render(r, ctx, rc, out),
+            None => Ok(()),
+        }
+    }
+}
+
+fn comparison(name: &'static str, accepts: fn(Ordering) -> bool) -> Box<Comparison> {
+    Box::new(Comparison { name, accepts })
+}
+
+fn register_comparisons(handlebars: &mut Registrar) {
+    handlebars.register_helper("eq", comparison("eq", |o| o == Ordering::Equal));
+    handlebars.register_helper("ne", comparison("ne", |o| o != Ordering::Equal));
+    handlebars.register_helper("gt", comparison("gt", |o| o == Ordering::Greater));
+    handlebars.register_helper("lt", comparison("lt", |o| o == Ordering::Less));
+}
+
+/// Picks the singular or plural form of a word depending on a count, as in
+/// `{{pluralize count "entry" "entries"}}`. Unless `plural_required` is set,
+/// the plural form can be left out, in which case it's the singular with an
+/// "s" appended.
+struct Plural {
+    name: &'static str,
+    plural_required: bool,
+}
+
+impl HelperDef for Plural {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<Option<ScopedJson<'reg, 'rc>>, RenderError> {
+        let usage = || {
+            RenderError::new(if self.plural_required {
+                format!(
+                    "The `{}` helper needs a count, a singular, and a plural form.",
+                    self.name
+                )
+            } else {
+                format!(
+                    "The `{}` helper needs a count and a singular form.",
+                    self.name
+                )
+            })
+        };
+
+        let count = h.param(0).ok_or_else(usage)?;
+        let count = as_number(count.value()).ok_or_else(|| {
+            RenderError::new(format!(
+                "The `{}` helper needs a number as its count, not {}.",
+                self.name,
+                count.value()
+            ))
+        })?;
+        let singular = h.param(1).ok_or_else(usage)?.value().render();
+        let plural = match h.param(2) {
+            Some(plural) => plural.value().render(),
+            None if self.plural_required => return Err(usage()),
+            None => format!("{}s", singular),
+        };
+
+        let word = if count == 1.0 { singular } else { plural };
+        Ok(Some(ScopedJson::Derived(JsonValue::String(word))))
+    }
+}
+
+fn register_plurals(handlebars: &mut Registrar) {
+    handlebars.register_helper(
+        "pluralize",
+        Box::new(Plural {
+            name: "pluralize",
+            plural_required: true,
+        }),
+    );
+    handlebars.register_helper(
+        "plural",
+        Box::new(Plural {
+            name: "plural",
+            plural_required: false,
+        }),
+    );
+}
+
+/// The prefix of the environment variables that `{{secret "name"}}` reads.
+const SECRET_PREFIX: &str = "SECRET_";
+
+/// Reads a secret from the environment, so that it never has to be in the
+/// template or the replacements file. Secrets aren't mappings, so their
+/// values are never logged, not even with `--trace-render`.
+struct Secret;
+
+impl Find for Secret {
+    fn find(&self, h: &Helper) -> Result<JsonValue, NotFound> {
+        let name = h
+            .param(0)
+            .and_then(|name| name.value().as_str())
+            .ok_or_else(|| RenderError::new("The `secret` helper needs the name of a secret."))?;
+        let variable = format!("{}{}", SECRET_PREFIX, name);
+
+        env::var(&variable).map(JsonValue::String).map_err(|_| {
+            NotFound::Missing(RenderError::new(format!(
+                "The secret {:?} isn't set; expected it in the environment variable {}",
+                name, variable
+            )))
+        })
+    }
+}
+
+/// Why a helper couldn't find what it was asked for.
+enum NotFound {
+    /// The helper was used wrong, which fails whatever the `--on-missing`
+    /// policy is.
+    Usage(RenderError),
+    /// What it was asked for doesn't exist, so the `--on-missing` policy
+    /// decides what to render.
+    Missing(RenderError),
+}
+
+impl From<RenderError> for NotFound {
+    fn from(e: RenderError) -> Self {
+        NotFound::Usage(e)
+    }
+}
+
+/// A helper that looks something up, and might not find it.
+trait Find {
+    fn find(&self, h: &Helper) -> Result<JsonValue, NotFound>;
+}
+
+/// Renders what a `Find` helper finds, or, if it's missing, what the
+/// `--on-missing` policy says to: an error, nothing, or the expression as it
+/// was written.
+struct OrMissing<F> {
+    find: F,
+    on_missing: OnMissing,
+}
+
+fn or_missing<F: Find>(find: F, on_missing: OnMissing) -> Box<OrMissing<F>> {
+    Box::new(OrMissing { find, on_missing })
+}
+
+impl<F: Find> HelperDef for OrMissing<F> {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<Option<ScopedJson<'reg, 'rc>>, RenderError> {
+        match self.find.find(h) {
+            Ok(value) => Ok(Some(ScopedJson::Derived(value))),
+            Err(NotFound::Usage(e)) => Err(e),
+            Err(NotFound::Missing(e)) => match self.on_missing {
+                OnMissing::Error => Err(e),
+                OnMissing::Empty => Ok(Some(ScopedJson::Derived(JsonValue::Null))),
+                OnMissing::Keep => Ok(None),
+            },
+        }
+    }
+
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        r: &'reg Handlebars<'reg>,
+        ctx: &'rc Context,
+        rc: &mut RenderContext<'reg, 'rc>,
+        out: &mut dyn Output,
+    ) -> HelperResult {
+        match self.call_inner(h, r, ctx, rc)? {
+            Some(value) if rc.is_disable_escape() => out.write(&value.render())?,
+            Some(value) => out.write(&r.get_escape_fn()(&value.render()))?,
+            None => out.write(&as_written(h))?,
+        }
+        Ok(())
+    }
+}
+
+/// Replaces the built-in `lookup` with one that fails on missing keys, like
+/// strict mode does for plain variables, instead of rendering nothing.
+/// Lists can only be indexed with whole numbers that aren't negative.
+struct StrictLookup;
+
+impl Find for StrictLookup {
+    fn find(&self, h: &Helper) -> Result<JsonValue, NotFound> {
+        let (collection, key) = match (h.param(0), h.param(1)) {
+            (Some(collection), Some(key)) => (collection, key.value()),
+            _ => {
+                return Err(NotFound::Usage(RenderError::new(
+                    "The `lookup` helper needs a collection and a key to look up.",
+                )))
+            }
+        };
+
+        let value = match collection.value() {
+            JsonValue::Object(object) => object.get(&key.render()),
+            JsonValue::Array(array) => {
+                let index = as_number(key)
+                    .filter(|index| *index >= 0.0 && index.fract() == 0.0)
+                    .ok_or_else(|| {
+                        RenderError::new(format!(
+                            "The `lookup` helper needs a whole number that isn't negative to \
+                             index a list with, not {}.",
+                            key
+                        ))
+                    })?;
+                array.get(index as usize)
+            }
+            _ => None,
+        };
+        value.cloned().ok_or_else(|| {
+            NotFound::Missing(RenderError::new(format!(
+                "Couldn't find {} in {}",
+                key,
+                collection
+                    .relative_path()
+                    .map(String::as_str)
+                    .unwrap_or("the value passed to `lookup`")
+            )))
+        })
+    }
+}
+
+/// Reads an environment variable, as in `{{env "DEPLOY_ENV"}}`. If it isn't
+/// set, that's handled like a missing key.
+struct Env;
+
+impl Find for Env {
+    fn find(&self, h: &Helper) -> Result<JsonValue, NotFound> {
+        let name = h
+            .param(0)
+            .and_then(|name| name.value().as_str())
+            .ok_or_else(|| {
+                RenderError::new("The `env` helper needs the name of an environment variable.")
+            })?;
+
+        env::var(name).map(JsonValue::String).map_err(|_| {
+            NotFound::Missing(RenderError::new(format!(
+                "The environment variable {} isn't set",
+                name
+            )))
+        })
+    }
+}
+
+/// The random number generator that `uuid` and `randhex` share. It's seeded
+/// from the OS unless a `--seed` is given, in which case every run produces
+/// the same values in the same order.
+type SharedRng = Arc<Mutex<StdRng>>;
+
+fn random_bytes(rng: &SharedRng, count: usize) -> Vec<u8> {
+    let mut bytes = vec![0; count];
+    rng.lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .fill_bytes(&mut bytes);
+    bytes
+}
+
+/// Generates a random (version 4) UUID, as in `{{uuid}}`.
+struct RandomUuid(SharedRng);
+
+impl HelperDef for RandomUuid {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        _: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<Option<ScopedJson<'reg, 'rc>>, RenderError> {
+        let mut bytes = [0; 16];
+        bytes.copy_from_slice(&random_bytes(&self.0, 16));
+        let uuid = uuid::Builder::from_random_bytes(bytes).into_uuid();
+        Ok(Some(ScopedJson::Derived(JsonValue::String(
+            uuid.to_string(),
+        ))))
+    }
+}
+
+/// Generates a string of random hexadecimal digits, as in `{{randhex 16}}`.
+struct RandomHex(SharedRng);
+
+impl HelperDef for RandomHex {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<Option<ScopedJson<'reg, 'rc>>, RenderError> {
+        let digits = h
+            .param(0)
+            .and_then(|digits| as_number(digits.value()))
+            .filter(|digits| *digits >= 0.0 && digits.fract() == 0.0)
+            .ok_or_else(|| {
+                RenderError::new("The `randhex` helper needs the number of digits to generate.")
+            })? as usize;
+
+        let mut hex: String = random_bytes(&self.0, digits.div_ceil(2))
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        hex.truncate(digits);
+        Ok(Some(ScopedJson::Derived(JsonValue::String(hex))))
+    }
+}
+
+fn register_random(handlebars: &mut Registrar, seed: Option<u64>) {
+    let rng: SharedRng = Arc::new(Mutex::new(match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => rand::make_rng(),
+    }));
+    handlebars.register_helper("uuid", Box::new(RandomUuid(rng.clone())));
+    handlebars.register_helper("randhex", Box::new(RandomHex(rng)));
+}
+
+/// Splits a string at a delimiter into a list, so that flat values can be
+/// iterated over, as in `{{#each (split tags ",")}}`. With `trim=true`, the
+/// whitespace around each element is removed.
+struct Split;
+
+impl HelperDef for Split {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<Option<ScopedJson<'reg, 'rc>>, RenderError> {
+        let (value, delimiter) = match (h.param(0), h.param(1)) {
+            (Some(value), Some(delimiter)) => (value.value().render(), delimiter.value().render()),
+            _ => {
+                return Err(RenderError::new(
+                    "The `split` helper needs a string and a delimiter to split it at.",
+                ))
+            }
+        };
+        if delimiter.is_empty() {
+            return Err(RenderError::new(
+                "The `split` helper needs a non-empty delimiter.",
+            ));
+        }
+        let trim = h
+            .hash_get("trim")
+            .is_some_and(|trim| trim.value().as_bool() == Some(true));
+
+        let elements = if value.is_empty() {
+            Vec::new()
+        } else {
+            value
+                .split(delimiter.as_str())
+                .map(|element| if trim { element.trim() } else { element })
+                .map(JsonValue::from)
+                .collect()
+        };
+        Ok(Some(ScopedJson::Derived(JsonValue::Array(elements))))
+    }
+}
+
+/// Whether a value counts as absent for `coalesce`: null, an empty string,
+/// or an empty list or object.
+fn is_blank(value: &JsonValue) -> bool {
+    match value {
+        JsonValue::Null => true,
+        JsonValue::String(s) => s.is_empty(),
+        JsonValue::Array(elements) => elements.is_empty(),
+        JsonValue::Object(fields) => fields.is_empty(),
+        _ => false,
+    }
+}
+
+/// Returns the first of its parameters that's set and isn't blank, as in
+/// `{{coalesce nickname name "friend"}}`. Parameters that refer to missing
+/// keys are skipped, even in strict mode. If none of them is set, it
+/// returns nothing.
+struct Coalesce;
+
+impl HelperDef for Coalesce {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<Option<ScopedJson<'reg, 'rc>>, RenderError> {
+        let value = h
+            .params()
+            .iter()
+            .filter(|param| !param.is_value_missing())
+            .map(PathAndJson::value)
+            .find(|value| !is_blank(value))
+            .cloned()
+            .unwrap_or(JsonValue::Null);
+        Ok(Some(ScopedJson::Derived(value)))
+    }
+}
+
+/// Cuts `text` down to at most `max` characters, ending it with `ellipsis`
+/// if anything was cut. Characters are Unicode scalar values, so multibyte
+/// characters are never split.
+fn truncate(text: &str, max: usize, ellipsis: &str) -> String {
+    if text.chars().count() <= max {
+        return text.to_string();
+    }
+    let kept = max.saturating_sub(ellipsis.chars().count());
+    text.chars()
+        .take(kept)
+        .chain(ellipsis.chars())
+        .take(max)
+        .collect()
+}
+
+/// Shortens a string to a maximum number of characters, as in
+/// `{{truncate description 80}}`. If it's cut, it ends with an ellipsis
+/// ("…", or the third parameter), which counts towards the maximum.
+struct Truncate;
+
+impl HelperDef for Truncate {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<Option<ScopedJson<'reg, 'rc>>, RenderError> {
+        let (text, max) = match (h.param(0), h.param(1)) {
+            (Some(text), Some(max)) => (text.value().render(), max.value()),
+            _ => {
+                return Err(RenderError::new(
+                    "The `truncate` helper needs a string and a maximum length.",
+                ))
+            }
+        };
+        let max = max
+            .as_u64()
+            .or_else(|| max.as_str().and_then(|max| max.parse().ok()))
+            .ok_or_else(|| {
+                RenderError::new("The maximum length of the `truncate` helper must be a count.")
+            })? as usize;
+        let ellipsis = h
+            .param(2)
+            .map_or_else(|| String::from("…"), |ellipsis| ellipsis.value().render());
+
+        Ok(Some(ScopedJson::Derived(JsonValue::String(truncate(
+            &text, max, &ellipsis,
+        )))))
+    }
+}
+
+/// Hashes a value with SHA-256, as in `{{sha256 url}}` or, cut down to its
+/// first eight characters, `{{shorthash url}}`. A length parameter, like in
+/// `{{sha256 url 12}}`, overrides how many characters are kept. Strings are
+/// hashed as they are, and anything else as its JSON.
+struct Hash {
+    name: &'static str,
+    length: Option<usize>,
+}
+
+impl HelperDef for Hash {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<Option<ScopedJson<'reg, 'rc>>, RenderError> {
+        let data = match h.param(0).map(|value| value.value()) {
+            Some(JsonValue::String(text)) => text.clone(),
+            Some(value) => value.to_string(),
+            None => {
+                return Err(RenderError::new(format!(
+                    "The `{}` helper needs a value to hash.",
+                    self.name
+                )))
+            }
+        };
+        let length = match h.param(1).map(|length| length.value()) {
+            Some(length) => Some(
+                length
+                    .as_u64()
+                    .or_else(|| length.as_str().and_then(|length| length.parse().ok()))
+                    .ok_or_else(|| {
+                        RenderError::new(format!(
+                            "The length of the `{}` helper must be a count.",
+                            self.name
+                        ))
+                    })? as usize,
+            ),
+            None => self.length,
+        };
+
+        let mut digest = hex_digest(data.as_bytes());
+        if let Some(length) = length {
+            digest.truncate(length);
+        }
+        Ok(Some(ScopedJson::Derived(JsonValue::String(digest))))
+    }
+}
+
+/// Formats a number for the `--locale`, with its digits grouped and the
+/// locale's decimal separator, as in `{{number total}}`. With `decimals=N`,
+/// it's rounded to exactly that many decimals.
+struct Number(Option<Locale>);
+
+impl HelperDef for Number {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<Option<ScopedJson<'reg, 'rc>>, RenderError> {
+        let value = h
+            .param(0)
+            .and_then(|value| as_number(value.value()))
+            .ok_or_else(|| RenderError::new("The `number` helper needs a number to format."))?;
+        let decimals = match h.hash_get("decimals") {
+            Some(decimals) => Some(decimals.value().as_u64().ok_or_else(|| {
+                RenderError::new("The `decimals` of the `number` helper must be a count.")
+            })? as usize),
+            None => None,
+        };
+
+        let formatted = locale::format_number(value, decimals, self.0.as_ref());
+        Ok(Some(ScopedJson::Derived(JsonValue::String(formatted))))
+    }
+}
+
+/// Formats a date for the `--locale`, as in `{{date released}}` or
+/// `{{date released format="%e %B %Y"}}`.
+struct Date(Option<Locale>);
+
+impl HelperDef for Date {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<Option<ScopedJson<'reg, 'rc>>, RenderError> {
+        let value = h
+            .param(0)
+            .ok_or_else(|| RenderError::new("The `date` helper needs a date to format."))?;
+        let format = h
+            .hash_get("format")
+            .and_then(|format| format.value().as_str());
+
+        locale::format_date(value.value(), format, self.0.as_ref())
+            .map(|formatted| Some(ScopedJson::Derived(JsonValue::String(formatted))))
+            .ok_or_else(|| {
+                RenderError::new(format!(
+                    "The `date` helper can't format {} as a date{}",
+                    value.value(),
+                    format.map_or(String::new(), |format| format!(" with {:?}", format))
+                ))
+            })
+    }
+}
+
+/// Reads a CSV file with a header row into one object per row, keyed by the
+/// column names.
+fn read_csv(path: &str) -> Result<Vec<JsonValue>, RenderError> {
+    let error = |e: csv::Error| RenderError::new(format!("Couldn't read {:?}: {}", path, e));
+    let mut reader = csv::Reader::from_path(path).map_err(error)?;
+    let headers = reader.headers().map_err(error)?.clone();
+
+    reader
+        .records()
+        .map(|record| {
+            let record = record.map_err(error)?;
+            Ok(JsonValue::Object(
+                headers
+                    .iter()
+                    .zip(record.iter())
+                    .map(|(header, field)| (header.to_string(), JsonValue::from(field)))
+                    .collect(),
+            ))
+        })
+        .collect()
+}
+
+/// Renders its block once for every row of a CSV file, as in
+/// `{{#csv "hosts.csv"}}{{name}}{{/csv}}`, with the row's columns as the
+/// context.
+struct Csv;
+
+impl HelperDef for Csv {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        r: &'reg Handlebars<'reg>,
+        ctx: &'rc Context,
+        rc: &mut RenderContext<'reg, 'rc>,
+        out: &mut dyn Output,
+    ) -> HelperResult {
+        let path = h
+            .param(0)
+            .and_then(|path| path.value().as_str())
+            .ok_or_else(|| RenderError::new("The `csv` helper needs the path of a CSV file."))?;
+        let template = h
+            .template()
+            .ok_or_else(|| RenderError::new("The `csv` helper must be used as a block."))?;
+
+        let rows = read_csv(path)?;
+        if rows.is_empty() {
+            return match h.inverse() {
+                Some(inverse) => inverse.render(r, ctx, rc, out),
+                None => Ok(()),
+            };
+        }
+
+        rc.push_block(BlockContext::new());
+        let last = rows.len() - 1;
+        for (index, row) in rows.into_iter().enumerate() {
+            if let Some(block) = rc.block_mut() {
+                block.set_local_var("@index".to_string(), JsonValue::from(index));
+                block.set_local_var("@first".to_string(), JsonValue::Bool(index == 0));
+                block.set_local_var("@last".to_string(), JsonValue::Bool(index == last));
+                block.set_base_value(row);
+            }
+            template.render(r, ctx, rc, out)?;
+        }
+        rc.pop_block();
+
+        Ok(())
+    }
+}
+
+/// Renders its block if a file exists, and the `{{else}}` branch (if any)
+/// otherwise, as in `{{#iffile "extra.conf"}}`. Paths are relative to the
+/// root given with `--file-root`, and may not point outside of it.
+struct IfFile(Root);
+
+impl HelperDef for IfFile {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        r: &'reg Handlebars<'reg>,
+        ctx: &'rc Context,
+        rc: &mut RenderContext<'reg, 'rc>,
+        out: &mut dyn Output,
+    ) -> HelperResult {
+        let path = h
+            .param(0)
+            .and_then(|path| path.value().as_str())
+            .ok_or_else(|| RenderError::new("The `iffile` helper needs the path of a file."))?;
+        let path = self.0.join(path).ok_or_else(|| {
+            RenderError::new(format!(
+                "The `iffile` helper can't look at {:?}, because it's outside of {:?}",
+                path,
+                self.0.path()
+            ))
+        })?;
+
+        match if path.exists() {
+            h.template()
+        } else {
+            h.inverse()
+        } {
+            Some(template) => template.render(r, ctx, rc, out),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Renders its block only with `--keep-comments`, as in
+/// `{{#comment}}# Set by the deploy job.{{/comment}}`, for explanations that
+/// are only wanted in some of the output.
+struct Comment(bool);
+
+impl HelperDef for Comment {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        r: &'reg Handlebars<'reg>,
+        ctx: &'rc Context,
+        rc: &mut RenderContext<'reg, 'rc>,
+        out: &mut dyn Output,
+    ) -> HelperResult {
+        match h.template() {
+            Some(template) if self.0 => template.render(r, ctx, rc, out),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Renders its block if the `--profile` is one of its parameters, and the
+/// `{{else}}` branch (if any) otherwise, as in `{{#ifprofile "prod"}}`.
+/// Without a profile, only the `{{else}}` branch is rendered. Inside either
+/// branch, `{{@profile}}` is the profile.
+struct IfProfile(Option<String>);
+
+impl HelperDef for IfProfile {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        r: &'reg Handlebars<'reg>,
+        ctx: &'rc Context,
+        rc: &mut RenderContext<'reg, 'rc>,
+        out: &mut dyn Output,
+    ) -> HelperResult {
+        if h.params().is_empty() {
+            return Err(RenderError::new(
+                "The `ifprofile` helper needs the name of at least one profile.",
+            ));
+        }
+        let mut matches = false;
+        for param in h.params() {
+            let name = param.value().as_str().ok_or_else(|| {
+                RenderError::new("The profiles given to the `ifprofile` helper must be strings.")
+            })?;
+            matches |= self.0.as_deref() == Some(name);
+        }
+
+        let template = match if matches { h.template() } else { h.inverse() } {
+            Some(template) => template,
+            None => return Ok(()),
+        };
+        let mut block = rc.block().cloned().unwrap_or_default();
+        block.set_local_var(
+            "@profile".to_string(),
+            self.0.clone().map_or(JsonValue::Null, JsonValue::String),
+        );
+        rc.push_block(block);
+        let rendered = template.render(r, ctx, rc, out);
+        rc.pop_block();
+        rendered
+    }
+}
+
+/// Stands in for a helper that has been left out of the allowlist.
+struct Disallowed;
+
+impl HelperDef for Disallowed {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+        _: &mut dyn Output,
+    ) -> HelperResult {
+        Err(RenderError::new(format!(
+            "The `{}` helper is not allowed.",
+            h.name()
+        )))
+    }
+}
+
+/// The helpers that Handlebars registers on its own.
+const BUILT_IN: &[&str] = &[
+    "if", "unless", "each", "with", "lookup", "raw", "log", "eq", "ne", "gt", "gte", "lt", "lte",
+    "and", "or", "not",
+];
+
+/// The helpers that replacer registers on top of the built-ins.
+const PROVIDED: &[&str] = &[
+    "eq",
+    "ne",
+    "gt",
+    "lt",
+    "pluralize",
+    "plural",
+    "secret",
+    "env",
+    "csv",
+    "uuid",
+    "randhex",
+    "iffile",
+    "split",
+    "number",
+    "date",
+    "coalesce",
+    "truncate",
+    "comment",
+    "sha256",
+    "shorthash",
+    "ifprofile",
+];
+
+/// The helpers that read the environment or the file system, which
+/// `--safe` disables.
+pub(crate) const UNSAFE: &[&str] = &["env", "secret", "csv", "iffile"];
+
+/// Takes the place of an `UNSAFE` helper with `--safe`.
+struct Unsafe;
+
+impl HelperDef for Unsafe {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+        _: &mut dyn Output,
+    ) -> HelperResult {
+        Err(RenderError::new(format!(
+            "The `{}` helper is disabled in safe mode.",
+            h.name()
+        )))
+    }
+}
+
+pub(crate) type BoxedHelper = Box<dyn HelperDef + Send + Sync>;
+
+/// How often each of the helpers that replacer registers has been called,
+/// for `--report-unused-helpers`. Clones share their counts.
+#[derive(Clone, Default)]
+pub(crate) struct Usage(Arc<Mutex<BTreeMap<String, usize>>>);
+
+impl Usage {
+    fn counts(&self) -> MutexGuard<'_, BTreeMap<String, usize>> {
+        self.0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn record(&self, name: &str) {
+        if let Some(count) = self.counts().get_mut(name) {
+            *count += 1;
+        }
+    }
+
+    /// Prints the helpers that were called (and how often) and the ones that
+    /// weren't to stderr, sorted by name.
+    pub(crate) fn report(&self) {
+        let counts = self.counts();
+        let (used, unused): (Vec<_>, Vec<_>) = counts.iter().partition(|(_, count)| **count > 0);
+
+        eprintln!("Used helpers:");
+        for (name, count) in used {
+            eprintln!("  {} ({})", name, count);
+        }
+        eprintln!("Unused helpers:");
+        for (name, _) in unused {
+            eprintln!("  {}", name);
+        }
+    }
+}
+
+/// Wraps a helper to count its calls.
+struct Counted {
+    name: String,
+    helper: BoxedHelper,
+    usage: Usage,
+}
+
+impl HelperDef for Counted {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        r: &'reg Handlebars<'reg>,
+        ctx: &'rc Context,
+        rc: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<Option<ScopedJson<'reg, 'rc>>, RenderError> {
+        self.usage.record(&self.name);
+        self.helper.call_inner(h, r, ctx, rc)
+    }
+
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        r: &'reg Handlebars<'reg>,
+        ctx: &'rc Context,
+        rc: &mut RenderContext<'reg, 'rc>,
+        out: &mut dyn Output,
+    ) -> HelperResult {
+        self.usage.record(&self.name);
+        self.helper.call(h, r, ctx, rc, out)
+    }
+}
+
+/// Registers helpers, wrapping them to count their calls if there's a
+/// `usage` to count them in.
+struct Registrar<'a, 'reg> {
+    handlebars: &'a mut Handlebars<'reg>,
+    usage: Option<&'a Usage>,
+}
+
+impl Registrar<'_, '_> {
+    fn register_helper(&mut self, name: &str, helper: BoxedHelper) {
+        let helper = match self.usage {
+            Some(usage) => {
+                usage.counts().insert(name.to_string(), 0);
+                Box::new(Counted {
+                    name: name.to_string(),
+                    helper,
+                    usage: usage.clone(),
+                })
+            }
+            None => helper,
+        };
+        self.handlebars.register_helper(name, helper);
+    }
+}
+
+/// Registers the helpers that replacer provides on top of the Handlebars
+/// built-ins, along with any `custom` ones. If `options` has a list of
+/// allowed helpers, every helper that isn't on it is replaced with one that
+/// refuses to render, and so is every `UNSAFE` helper in safe mode. If it
+/// has a helper usage, every helper registered here counts its calls in it.
+pub(crate) fn register(
+    handlebars: &mut Handlebars,
+    custom: Vec<(String, BoxedHelper)>,
+    options: &EngineOptions,
+) -> Result<(), ProgramError> {
+    let file_root = match &options.file_root {
+        Some(root) => Root::new(root)?,
+        None => Root::new(Path::new("."))?,
+    };
+
+    let custom: Vec<String> = {
+        let mut registrar = Registrar {
+            handlebars,
+            usage: options.helper_usage.as_ref(),
+        };
+        let names = custom.iter().map(|(name, _)| name.clone()).collect();
+        for (name, helper) in custom {
+            registrar.register_helper(&name, helper);
+        }
+        register_comparisons(&mut registrar);
+        register_plurals(&mut registrar);
+        register_random(&mut registrar, options.seed);
+        registrar.register_helper("secret", or_missing(Secret, options.on_missing));
+        registrar.register_helper("env", or_missing(Env, options.on_missing));
+        registrar.register_helper("lookup", or_missing(StrictLookup, options.on_missing));
+        registrar.register_helper("csv", Box::new(Csv));
+        registrar.register_helper("split", Box::new(Split));
+        registrar.register_helper("coalesce", Box::new(Coalesce));
+        registrar.register_helper("truncate", Box::new(Truncate));
+        registrar.register_helper(
+            "sha256",
+            Box::new(Hash {
+                name: "sha256",
+                length: None,
+            }),
+        );
+        registrar.register_helper(
+            "shorthash",
+            Box::new(Hash {
+                name: "shorthash",
+                length: Some(8),
+            }),
+        );
+        registrar.register_helper("comment", Box::new(Comment(options.keep_comments)));
+        registrar.register_helper("number", Box::new(Number(options.locale)));
+        registrar.register_helper("date", Box::new(Date(options.locale)));
+        registrar.register_helper("iffile", Box::new(IfFile(file_root)));
+        registrar.register_helper("ifprofile", Box::new(IfProfile(options.profile.clone())));
+        names
+    };
+
+    if let Some(allowed) = &options.allowed_helpers {
+        let is_known = |name: &str| {
+            BUILT_IN.contains(&name)
+                || PROVIDED.contains(&name)
+                || custom.iter().any(|custom| custom == name)
+        };
+        if let Some(unknown) = allowed.iter().find(|name| !is_known(name)) {
+            return Err(ProgramError::UnknownHelper(unknown.clone()));
+        }
+
+        let custom = custom.iter().map(String::as_str);
+        for name in BUILT_IN
+            .iter()
+            .copied()
+            .chain(PROVIDED.iter().copied())
+            .chain(custom)
+        {
+            if !allowed.contains(name) {
+                handlebars.register_helper(name, Box::new(Disallowed));
+            }
+        }
+    }
+
+    if options.safe {
+        for name in UNSAFE {
+            handlebars.register_helper(name, Box::new(Unsafe));
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes a reference to a missing key (or an unknown helper) back out as it
+/// was written, e.g. `{{name}}` or `{{format date "short"}}`.
+struct KeepMissing;
+
+/// The helper call `h` as it was written, e.g. `{{format date "short"}}`.
+fn as_written(h: &Helper) -> String {
+    let argument = |value: &PathAndJson| match value.relative_path() {
+        Some(path) => path.clone(),
+        None => value.value().to_string(),
+    };
+
+    let mut expression = vec![h.name().to_string()];
+    expression.extend(h.params().iter().map(argument));
+    expression.extend(
+        h.hash()
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, argument(value))),
+    );
+    format!("{{{{{}}}}}", expression.join(" "))
+}
+
+impl HelperDef for KeepMissing {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+        out: &mut dyn Output,
+    ) -> HelperResult {
+        out.write(&as_written(h))?;
+        Ok(())
+    }
+}
+
+/// The helper that `keep_missing_raw` rewrites triple-stash expressions to
+/// call. The space keeps templates from calling it themselves.
+const KEEP_MISSING_RAW: &str = "helperMissing raw";
+
+/// Writes the value of its argument without escaping it, like a triple-stash
+/// `{{{name}}}` does, or, if it's missing, the expression as it was written.
+struct KeepMissingRaw;
+
+impl HelperDef for KeepMissingRaw {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+        out: &mut dyn Output,
+    ) -> HelperResult {
+        let value = h
+            .param(0)
+            .ok_or_else(|| RenderError::new("A raw expression needs a value."))?;
+        match value.relative_path() {
+            Some(path) if value.is_value_missing() => {
+                out.write(&format!("{{{{{{{}}}}}}}", path))?
+            }
+            _ => out.write(&value.value().render())?,
+        }
+        Ok(())
+    }
+}
+
+/// Makes references to missing keys render as they were written instead of
+/// as nothing. Only has an effect outside of strict mode, and only on
+/// triple-stash references once `keep_missing_raw` has rewritten them.
+pub(crate) fn keep_missing(handlebars: &mut Handlebars) {
+    handlebars.register_helper("helperMissing", Box::new(KeepMissing));
+    handlebars.register_helper(KEEP_MISSING_RAW, Box::new(KeepMissingRaw));
+}
+
+/// Rewrites the triple-stash expressions in `elements` (and in the blocks
+/// among them) into calls to `KeepMissingRaw`, since Handlebars only calls
+/// `helperMissing` for double-stash ones.
+pub(crate) fn keep_missing_raw(elements: &mut [TemplateElement]) {
+    for element in elements {
+        match element {
+            TemplateElement::HTMLExpression(parameter) => {
+                let parameter = parameter.clone();
+                *element = TemplateElement::Expression(Box::new(HelperTemplate {
+                    name: Parameter::Name(KEEP_MISSING_RAW.to_string()),
+                    params: vec![parameter],
+                    hash: HashMap::new(),
+                    block_param: None,
+                    template: None,
+                    inverse: None,
+                    block: false,
+                }));
+            }
+            TemplateElement::HelperBlock(ht) => {
+                for nested in ht.template.iter_mut().chain(ht.inverse.iter_mut()) {
+                    keep_missing_raw(&mut nested.elements);
+                }
+            }
+            TemplateElement::DecoratorBlock(dt) | TemplateElement::PartialBlock(dt) => {
+                if let Some(nested) = &mut dt.template {
+                    keep_missing_raw(&mut nested.elements);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{Engine, HandlebarsEngine};
+    use crate::values::Mappings;
+
+    fn render(template: &str, on_missing: OnMissing) -> Result<String, ProgramError> {
+        let engine = HandlebarsEngine::new(EngineOptions {
+            on_missing,
+            ..EngineOptions::default()
+        })?;
+        let mut mappings = Mappings::new();
+        mappings.insert(
+            "hosts".to_string(),
+            serde_json::json!({ "prod": "db.example.com" }),
+        );
+        mappings.insert("ports".to_string(), serde_json::json!([80, 443]));
+        engine.render(template, &mappings)
+    }
+
+    fn equal(left: JsonValue, right: JsonValue) -> bool {
+        compare(&left, &right) == Some(Ordering::Equal)
+    }
+
+    #[test]
+    fn numbers_compare_by_value() {
+        assert!(equal(JsonValue::from("1.5"), JsonValue::from(1.5)));
+        assert!(equal(JsonValue::from("-2"), JsonValue::from(-2)));
+        assert_eq!(
+            compare(&JsonValue::from("10"), &JsonValue::from("9")),
+            Some(Ordering::Greater)
+        );
+    }
+
+    #[test]
+    fn versions_compare_as_text() {
+        assert!(!equal(JsonValue::from("1.10"), JsonValue::from("1.1")));
+        assert!(!equal(JsonValue::from("1.10"), JsonValue::from(1.1)));
+    }
+
+    #[test]
+    fn leading_zeros_compare_as_text() {
+        assert!(!equal(JsonValue::from("007"), JsonValue::from("7")));
+        assert!(!equal(JsonValue::from("007"), JsonValue::from(7)));
+        assert!(equal(JsonValue::from("0.5"), JsonValue::from(0.5)));
+    }
+
+    #[test]
+    fn nan_is_text() {
+        assert!(equal(JsonValue::from("nan"), JsonValue::from("nan")));
+        assert!(!equal(JsonValue::from("nan"), JsonValue::from("NaN")));
+    }
+
+    #[test]
+    fn lookups_index_lists_with_whole_numbers() {
+        assert_eq!(
+            render("{{lookup ports 1}}", OnMissing::Error).unwrap(),
+            "443"
+        );
+        for index in &["-1", "1.7", "\"one\""] {
+            let template = format!("{{{{lookup ports {}}}}}", index);
+            assert!(render(&template, OnMissing::Empty).is_err(), "{}", index);
+        }
+    }
+
+    #[test]
+    fn lookups_take_keys_from_subexpressions() {
+        env::set_var("REPLACER_LOOKUP_TEST_ENV", "prod");
+        assert_eq!(
+            render(
+                "{{lookup hosts (env \"REPLACER_LOOKUP_TEST_ENV\")}}",
+                OnMissing::Error
+            )
+            .unwrap(),
+            "db.example.com"
+        );
+    }
+
+    #[test]
+    fn missing_lookups_follow_the_on_missing_policy() {
+        assert!(render("{{lookup hosts \"dev\"}}", OnMissing::Error).is_err());
+        assert_eq!(
+            render("[{{lookup hosts \"dev\"}}]", OnMissing::Empty).unwrap(),
+            "[]"
+        );
+        assert_eq!(
+            render("[{{lookup hosts \"dev\"}}]", OnMissing::Keep).unwrap(),
+            "[{{lookup hosts \"dev\"}}]"
+        );
+    }
+
+    const UNSET: &[&str] = &[
+        "[{{env \"REPLACER_UNSET_TEST\"}}]",
+        "[{{secret \"replacer_unset_test\"}}]",
+    ];
+
+    #[test]
+    fn unset_variables_fail_with_on_missing_error() {
+        for template in UNSET {
+            assert!(render(template, OnMissing::Error).is_err(), "{}", template);
+        }
+    }
+
+    #[test]
+    fn unset_variables_render_nothing_with_on_missing_empty() {
+        for template in UNSET {
+            assert_eq!(render(template, OnMissing::Empty).unwrap(), "[]");
+        }
+    }
+
+    #[test]
+    fn unset_variables_are_kept_with_on_missing_keep() {
+        for template in UNSET {
+            assert_eq!(render(template, OnMissing::Keep).unwrap(), *template);
+        }
+    }
+}
@@ -0,0 +1,69 @@
+use log::{info, warn};
+use std::{path::Path, process::Command, str::FromStr};
+
+use crate::writer::ProgramError;
+
+/// A command to run on every output file after it's been written, such as a
+/// formatter. The command is split at whitespace rather than run through a
+/// shell, and every `{}` in it is replaced with the path of the output file.
+#[derive(Debug, Clone)]
+pub(crate) struct PostHook {
+    program: String,
+    args: Vec<String>,
+}
+
+impl FromStr for PostHook {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace().map(String::from);
+        match words.next() {
+            Some(program) => Ok(PostHook {
+                program,
+                args: words.collect(),
+            }),
+            None => Err(String::from("The post-hook command is empty.")),
+        }
+    }
+}
+
+impl PostHook {
+    fn execute(&self, output_file: &Path) -> Result<(), String> {
+        let path = output_file.to_string_lossy();
+        let args: Vec<String> = self
+            .args
+            .iter()
+            .map(|arg| arg.replace("{}", &path))
+            .collect();
+        info!(
+            "Running {:?} {:?} on {:?}.",
+            self.program, args, output_file
+        );
+
+        let status = Command::new(&self.program)
+            .args(&args)
+            .status()
+            .map_err(|e| format!("couldn't run {:?}: {}", self.program, e))?;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(format!("{:?} exited with {}", self.program, status))
+        }
+    }
+
+    /// Runs the hook on `output_file`. If it fails, that's an error when
+    /// `strict` is set and only a warning otherwise.
+    pub(crate) fn run(&self, output_file: &Path, strict: bool) -> Result<(), ProgramError> {
+        match self.execute(output_file) {
+            Ok(()) => Ok(()),
+            Err(reason) if strict => Err(ProgramError::PostHookFailed(
+                output_file.to_path_buf(),
+                reason,
+            )),
+            Err(reason) => {
+                warn!("The post-hook failed for {:?}: {}.", output_file, reason);
+                Ok(())
+            }
+        }
+    }
+}
@@ -0,0 +1,556 @@
+use glob::{glob, MatchOptions, Pattern};
+use ignore::{
+    gitignore::{Gitignore, GitignoreBuilder},
+    Match, WalkBuilder,
+};
+use log::{debug, info, warn};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    env,
+    path::{absolute, Component, Path, PathBuf},
+};
+
+use crate::packaged::Entry;
+use crate::writer::ProgramError;
+
+const IGNORE_FILE: &str = ".replacerignore";
+
+/// What's appended to the name of a template to get the name of its vars
+/// file, whose mappings only apply to that template.
+const VARS_SUFFIX: &str = ".vars.yaml";
+
+/// The vars file of the template at `path`, which may not exist.
+pub(crate) fn vars_file(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(VARS_SUFFIX);
+    path.with_file_name(name)
+}
+
+/// A template file matched by one of the input patterns, along with the
+/// directory its pattern was rooted in.
+pub(crate) struct InputFile {
+    pub(crate) path: PathBuf,
+    base: PathBuf,
+}
+
+impl InputFile {
+    /// The path of the file relative to the non-glob prefix of the pattern
+    /// that matched it, if the file lives below that prefix.
+    pub(crate) fn relative_path(&self) -> Option<&Path> {
+        self.path
+            .strip_prefix(&self.base)
+            .ok()
+            .filter(|relative| relative.file_name().is_some())
+    }
+
+    /// The directory below the base of the pattern that the file is in, at
+    /// `depth` levels down, if the file is any deeper than that.
+    fn directory_at(&self, depth: usize) -> Option<PathBuf> {
+        let relative = self.relative_path()?;
+        let mut components = relative.components();
+        components.next_back();
+        let directories: Vec<Component> = components.collect();
+        if directories.len() <= depth {
+            return None;
+        }
+        Some(
+            self.base
+                .join(directories[..=depth].iter().collect::<PathBuf>()),
+        )
+    }
+
+    /// The path of the file relative to `root`. Relative paths (on either
+    /// side) are resolved against the current directory first.
+    pub(crate) fn path_relative_to(&self, root: &Path) -> Result<PathBuf, ProgramError> {
+        let cwd = env::current_dir().unwrap_or_else(|_| PathBuf::from("."));
+        cwd.join(&self.path)
+            .strip_prefix(cwd.join(root))
+            .ok()
+            .filter(|relative| relative.file_name().is_some())
+            .map(Path::to_path_buf)
+            .ok_or_else(|| ProgramError::InputOutsideRoot(self.path.clone(), root.to_path_buf()))
+    }
+}
+
+fn is_glob(component: &Component) -> bool {
+    component
+        .as_os_str()
+        .to_string_lossy()
+        .contains(['*', '?', '['])
+}
+
+fn pattern_base(pattern: &str) -> PathBuf {
+    let path = Path::new(pattern);
+    if !path.components().any(|component| is_glob(&component)) {
+        return path.parent().map(Path::to_path_buf).unwrap_or_default();
+    }
+
+    path.components()
+        .take_while(|component| !is_glob(component))
+        .filter(|component| component != &Component::CurDir)
+        .collect()
+}
+
+fn load_ignore_file(root: &Path) -> Result<Option<Gitignore>, ProgramError> {
+    let root = if root.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        root
+    };
+    let ignore_file = root.join(IGNORE_FILE);
+    if !ignore_file.is_file() {
+        return Ok(None);
+    }
+
+    debug!("Using ignore rules from {:?}.", &ignore_file);
+
+    let root = absolute(root).map_err(|_| ProgramError::FileNotFound(root.to_path_buf()))?;
+    let mut builder = GitignoreBuilder::new(root);
+    if let Some(e) = builder.add(&ignore_file) {
+        warn!(
+            "Some rules in {:?} could not be parsed: {}",
+            &ignore_file, e
+        );
+    }
+    builder
+        .build()
+        .map(Some)
+        .map_err(|_| ProgramError::ReadFailed(ignore_file))
+}
+
+/// Whether the rules exclude the file at `path`. The rules only cover the
+/// directory they're rooted in, so paths outside of it (which the matcher
+/// can't handle) are never ignored.
+fn is_ignored(rules: &Gitignore, path: &Path) -> bool {
+    let relative = match absolute(path) {
+        Ok(absolute) => match absolute.strip_prefix(rules.path()) {
+            Ok(relative) => relative.to_path_buf(),
+            Err(_) => return false,
+        },
+        Err(_) => return false,
+    };
+    match rules.matched_path_or_any_parents(&relative, false) {
+        Match::Ignore(rule) => {
+            debug!(
+                "Skipping {:?}: matched rule {:?} in {:?}.",
+                path,
+                rule.original(),
+                rule.from().unwrap_or_else(|| Path::new(IGNORE_FILE))
+            );
+            true
+        }
+        _ => false,
+    }
+}
+
+/// The files that `pattern` matches, as `glob` finds them.
+fn glob_files(pattern: &str) -> Result<Vec<PathBuf>, ProgramError> {
+    Ok(glob(pattern)
+        .map_err(|e| ProgramError::InvalidPattern(format!("{}: {}", pattern, e.msg)))?
+        .filter_map(|entry| match entry {
+            Ok(path) => Some(path),
+            Err(e) => {
+                warn!("Unable to read {:?}; skipping it.", e.path());
+                None
+            }
+        })
+        .filter(|path| path.is_file())
+        .collect())
+}
+
+/// The files that `pattern` matches, like `glob_files`, except that the
+/// search doesn't descend into directories more than `max_depth` levels
+/// below `base`. The directories it stops at are added to `skipped`.
+fn walk_files(
+    pattern: &str,
+    base: &Path,
+    max_depth: usize,
+    skipped: &mut BTreeSet<PathBuf>,
+) -> Result<Vec<PathBuf>, ProgramError> {
+    let matcher = Pattern::new(pattern)
+        .map_err(|e| ProgramError::InvalidPattern(format!("{}: {}", pattern, e.msg)))?;
+    let options = MatchOptions {
+        require_literal_separator: true,
+        ..MatchOptions::new()
+    };
+
+    // The search starts at the part of the pattern before the first
+    // wildcard, which may already be below the base.
+    let start: PathBuf = Path::new(pattern)
+        .components()
+        .take_while(|component| !is_glob(component))
+        .collect();
+    let offset = without_cur_dir(&start)
+        .strip_prefix(without_cur_dir(base))
+        .map(|below| below.components().count())
+        .unwrap_or(0);
+    // Files can be `max_depth` directories below the base, which is one
+    // level further down than those directories are.
+    let limit = match (max_depth + 1).checked_sub(offset) {
+        Some(limit) if limit > 0 => limit,
+        _ => {
+            skipped.insert(start);
+            return Ok(Vec::new());
+        }
+    };
+
+    let is_relative = start.as_os_str().is_empty();
+    let root = if is_relative { Path::new(".") } else { &start };
+    let mut files = Vec::new();
+    let walk = WalkBuilder::new(root)
+        .standard_filters(false)
+        .follow_links(true)
+        .max_depth(Some(limit))
+        .sort_by_file_name(|a, b| a.cmp(b))
+        .build();
+    for entry in walk {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                warn!("Unable to read a file; skipping it: {}", e);
+                continue;
+            }
+        };
+        let path = match entry.path().strip_prefix(".") {
+            Ok(path) if is_relative => path,
+            _ => entry.path(),
+        };
+        // Like `glob`, leave out the `.` at the start of the pattern.
+        if entry.depth() == limit && path.is_dir() {
+            skipped.insert(without_cur_dir(path));
+        } else if path.is_file() && matcher.matches_path_with(path, options) {
+            files.push(without_cur_dir(path));
+        }
+    }
+    Ok(files)
+}
+
+/// `path` without any `.` components.
+fn without_cur_dir(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|component| component != &Component::CurDir)
+        .collect()
+}
+
+fn expand_pattern(
+    pattern: &str,
+    base: Option<&Path>,
+    max_depth: Option<usize>,
+    skipped: &mut BTreeSet<PathBuf>,
+) -> Result<Vec<InputFile>, ProgramError> {
+    // An entry in an archive isn't globbed, and isn't below any base, so its
+    // output is named after the entry alone.
+    let path = PathBuf::from(pattern);
+    if Entry::parse(&path).is_some() {
+        return Ok(vec![InputFile {
+            base: path.clone(),
+            path,
+        }]);
+    }
+
+    let base = base
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|| pattern_base(pattern));
+    let was_skipped = skipped.len();
+    let is_pattern = path.components().any(|component| is_glob(&component));
+    let paths = match max_depth {
+        Some(max_depth) if is_pattern => walk_files(pattern, &base, max_depth, skipped)?,
+        _ => glob_files(pattern)?,
+    };
+
+    // A pattern whose matches are all too deep isn't missing, it's limited.
+    if paths.is_empty() && skipped.len() == was_skipped {
+        return Err(ProgramError::FileNotFound(PathBuf::from(pattern)));
+    }
+    Ok(paths
+        .into_iter()
+        .map(|path| InputFile {
+            path,
+            base: base.clone(),
+        })
+        .collect())
+}
+
+fn with_extensions<'e>(
+    path: &'e Path,
+    extensions: &'e [&str],
+) -> impl Iterator<Item = PathBuf> + 'e {
+    extensions
+        .iter()
+        .map(move |ext| PathBuf::from(format!("{}.{}", path.display(), ext)))
+}
+
+/// Every path that `resolve_in` looks at for `name`, in the order it looks
+/// at them: the name itself, and then the name with each of `extensions`.
+pub(crate) fn candidates(name: &str, dir: &Path, extensions: &[&str]) -> Vec<PathBuf> {
+    let path = dir.join(name);
+    std::iter::once(path.clone())
+        .chain(with_extensions(&path, extensions))
+        .collect()
+}
+
+/// Resolves an input pattern against `--template-dir`. Glob patterns and
+/// names of existing files are simply looked for in `dir`. Other names are
+/// tried with each of the template `extensions` added, so that `nginx` can
+/// refer to `nginx.hbs`; it's an error if more than one of them exists.
+pub(crate) fn resolve_in(
+    pattern: &str,
+    dir: &Path,
+    extensions: &[&str],
+) -> Result<String, ProgramError> {
+    let path = dir.join(pattern);
+    if Path::new(pattern)
+        .components()
+        .any(|component| is_glob(&component))
+        || path.is_file()
+    {
+        return Ok(path.to_string_lossy().into_owned());
+    }
+
+    let mut candidates: Vec<PathBuf> = with_extensions(&path, extensions)
+        .filter(|candidate| candidate.is_file())
+        .collect();
+    match candidates.len() {
+        0 => Ok(path.to_string_lossy().into_owned()),
+        1 => Ok(candidates.remove(0).to_string_lossy().into_owned()),
+        _ => Err(ProgramError::AmbiguousTemplate(
+            pattern.to_string(),
+            candidates,
+        )),
+    }
+}
+
+/// Leaves out the files that are more than `max_depth` directories below the
+/// base of their pattern, adding the directories that skips to `skipped`.
+/// Searches don't go that deep to begin with, but a pattern without any
+/// wildcards can name such a file directly.
+fn limit_depth(
+    input_files: Vec<InputFile>,
+    max_depth: usize,
+    skipped: &mut BTreeSet<PathBuf>,
+) -> Vec<InputFile> {
+    input_files
+        .into_iter()
+        .filter(|input_file| match input_file.directory_at(max_depth) {
+            Some(dir) => {
+                debug!(
+                    "Skipping {:?}: it's too deep in {:?}.",
+                    input_file.path, dir
+                );
+                skipped.insert(dir);
+                false
+            }
+            None => true,
+        })
+        .collect()
+}
+
+/// Expands the provided input patterns into the list of template files to
+/// render, leaving out the vars files of other templates, anything excluded by
+/// a `.replacerignore` file in the template root, and anything that's deeper
+/// than `max_depth` below its pattern's base. If `base` is given, it's the
+/// base of every pattern instead. The template root is `root` if it's given,
+/// and each pattern's base if it isn't.
+pub(crate) fn expand(
+    patterns: &[String],
+    root: Option<&Path>,
+    base: Option<&Path>,
+    max_depth: Option<usize>,
+) -> Result<Vec<InputFile>, ProgramError> {
+    let mut input_files: Vec<InputFile> = Vec::new();
+    let mut skipped = BTreeSet::new();
+    for pattern in patterns {
+        for input_file in expand_pattern(pattern, base, max_depth, &mut skipped)? {
+            if !input_files
+                .iter()
+                .any(|known| known.path == input_file.path)
+            {
+                input_files.push(input_file);
+            }
+        }
+    }
+
+    // A pattern like `templates/*` matches the vars files along with their
+    // templates, but they're not templates themselves.
+    let vars_files: Vec<PathBuf> = input_files
+        .iter()
+        .map(|input_file| vars_file(&input_file.path))
+        .collect();
+    input_files.retain(|input_file| {
+        let is_vars_file = vars_files.contains(&input_file.path);
+        if is_vars_file {
+            debug!("Skipping {:?}: it's a vars file.", input_file.path);
+        }
+        !is_vars_file
+    });
+
+    let mut rules: BTreeMap<PathBuf, Option<Gitignore>> = BTreeMap::new();
+    let mut kept = Vec::new();
+    for input_file in input_files {
+        let root = root.unwrap_or(&input_file.base);
+        if !rules.contains_key(root) {
+            rules.insert(root.to_path_buf(), load_ignore_file(root)?);
+        }
+        match &rules[root] {
+            Some(rules) if is_ignored(rules, &input_file.path) => {}
+            _ => kept.push(input_file),
+        }
+    }
+    let input_files = kept;
+    let max_depth = match max_depth {
+        Some(max_depth) => max_depth,
+        None => return Ok(input_files),
+    };
+    let input_files = limit_depth(input_files, max_depth, &mut skipped);
+    if !skipped.is_empty() {
+        info!(
+            "Skipped {} director(ies) deeper than --max-depth {}.",
+            skipped.len(),
+            max_depth
+        );
+    }
+    Ok(input_files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn templates_outside_the_ignore_root_are_not_ignored() {
+        let root = tempfile::tempdir().unwrap();
+        fs::write(root.path().join(IGNORE_FILE), "*.bak\n").unwrap();
+        fs::write(root.path().join("skipped.bak"), "").unwrap();
+        let elsewhere = tempfile::tempdir().unwrap();
+        fs::write(elsewhere.path().join("outside.hbs"), "").unwrap();
+        fs::write(elsewhere.path().join("outside.bak"), "").unwrap();
+
+        let pattern = elsewhere.path().join("*").to_string_lossy().into_owned();
+        let input_files = expand(&[pattern], Some(root.path()), None, None).unwrap();
+        assert_eq!(input_files.len(), 2);
+
+        let rules = load_ignore_file(root.path()).unwrap().unwrap();
+        assert!(is_ignored(&rules, &root.path().join("skipped.bak")));
+        assert!(!is_ignored(&rules, &elsewhere.path().join("outside.bak")));
+    }
+
+    #[test]
+    fn ignore_files_are_read_from_the_pattern_base() {
+        let dir = tempfile::tempdir().unwrap();
+        let templates = dir.path().join("templates");
+        fs::create_dir(&templates).unwrap();
+        fs::write(templates.join(IGNORE_FILE), "skipped.hbs\n").unwrap();
+        fs::write(templates.join("kept.hbs"), "").unwrap();
+        fs::write(templates.join("skipped.hbs"), "").unwrap();
+
+        let pattern = templates.join("*.hbs").to_string_lossy().into_owned();
+        let input_files = expand(&[pattern], None, None, None).unwrap();
+        let paths: Vec<&Path> = input_files
+            .iter()
+            .map(|input_file| input_file.path.as_path())
+            .collect();
+        assert_eq!(paths, vec![templates.join("kept.hbs")]);
+    }
+
+    #[test]
+    fn ignore_rules_are_anchored_at_a_relative_input_base() {
+        fs::create_dir_all("target").unwrap();
+        let dir = tempfile::tempdir_in("target").unwrap();
+        let base = dir
+            .path()
+            .strip_prefix(env::current_dir().unwrap())
+            .unwrap();
+        fs::write(base.join(IGNORE_FILE), "/skipped.hbs\n").unwrap();
+        fs::create_dir(base.join("nested")).unwrap();
+        for path in ["kept.hbs", "skipped.hbs", "nested/skipped.hbs"] {
+            fs::write(base.join(path), "").unwrap();
+        }
+
+        let pattern = base.join("**/*.hbs").to_string_lossy().into_owned();
+        let mut input_files: Vec<PathBuf> = expand(&[pattern], Some(base), Some(base), None)
+            .unwrap()
+            .into_iter()
+            .map(|input_file| input_file.path)
+            .collect();
+        input_files.sort();
+        assert_eq!(
+            input_files,
+            vec![base.join("kept.hbs"), base.join("nested/skipped.hbs")]
+        );
+    }
+
+    #[test]
+    fn paths_without_a_file_name_are_not_templates() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("template.hbs"), "").unwrap();
+        let template = dir.path().join("template.hbs");
+        let trailing_slash = format!("{}/", template.display());
+
+        for pattern in [".", "..", "/", trailing_slash.as_str()] {
+            assert!(
+                matches!(
+                    expand(&[pattern.to_string()], Some(dir.path()), None, None),
+                    Err(ProgramError::FileNotFound(_))
+                ),
+                "{:?}",
+                pattern
+            );
+        }
+    }
+
+    #[test]
+    fn searches_stop_at_the_max_depth() {
+        let dir = tempfile::tempdir().unwrap();
+        let templates = dir.path().join("templates");
+        for path in ["a.hbs", "nginx/site.hbs", "nginx/sites/extra.hbs"] {
+            let path = templates.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+
+        let pattern = templates.join("**/*.hbs").to_string_lossy().into_owned();
+        let mut skipped = BTreeSet::new();
+        let files = walk_files(&pattern, &templates, 1, &mut skipped).unwrap();
+        assert_eq!(
+            files,
+            vec![templates.join("a.hbs"), templates.join("nginx/site.hbs")]
+        );
+        assert_eq!(
+            skipped.into_iter().collect::<Vec<_>>(),
+            vec![templates.join("nginx/sites")]
+        );
+
+        let input_files = expand(&[pattern], Some(dir.path()), None, Some(0)).unwrap();
+        assert_eq!(input_files.len(), 1);
+        assert_eq!(input_files[0].path, templates.join("a.hbs"));
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn pattern_bases_keep_drive_letters() {
+        assert_eq!(
+            pattern_base(r"C:\templates\**\*.hbs"),
+            Path::new(r"C:\templates")
+        );
+        assert_eq!(
+            pattern_base("C:/templates/nginx/*.hbs"),
+            Path::new(r"C:\templates\nginx")
+        );
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn relative_paths_join_with_either_separator() {
+        let input_file = InputFile {
+            path: PathBuf::from(r"C:\templates/nginx\site.hbs"),
+            base: PathBuf::from("C:/templates"),
+        };
+        let relative = input_file.relative_path().unwrap();
+        assert_eq!(relative, Path::new(r"nginx\site.hbs"));
+        assert_eq!(
+            Path::new(r"D:\out").join(relative),
+            Path::new(r"D:\out\nginx\site.hbs")
+        );
+    }
+}
@@ -0,0 +1,135 @@
+use handlebars::template::{DecoratorTemplate, HelperTemplate, Parameter, TemplateElement};
+use handlebars::Template;
+use std::collections::BTreeSet;
+
+/// Something a template refers to by name.
+enum Reference<'t> {
+    /// A call to a helper with arguments, as a block, or as a subexpression.
+    Helper(&'t str),
+    /// A bare `{{name}}` expression, which is either a variable or a call to
+    /// a helper that takes no arguments.
+    Name(&'t str),
+    /// A variable passed to a helper, as in `{{upper name}}`.
+    Argument(&'t str),
+}
+
+fn is_name_only(ht: &HelperTemplate) -> bool {
+    !ht.block && ht.params.is_empty() && ht.hash.is_empty()
+}
+
+fn walk_parameter<'t>(parameter: &'t Parameter, visit: &mut dyn FnMut(Reference<'t>)) {
+    if let Parameter::Path(_) = parameter {
+        if let Some(name) = parameter.as_name() {
+            visit(Reference::Argument(name));
+        }
+    }
+    if let Parameter::Subexpression(subexpression) = parameter {
+        if let TemplateElement::Expression(ht) = subexpression.as_element() {
+            if !is_name_only(ht) {
+                if let Some(name) = ht.name.as_name() {
+                    visit(Reference::Helper(name));
+                }
+            }
+            walk_arguments(ht.params.iter().chain(ht.hash.values()), visit);
+        }
+    }
+}
+
+fn walk_arguments<'t>(
+    arguments: impl Iterator<Item = &'t Parameter>,
+    visit: &mut dyn FnMut(Reference<'t>),
+) {
+    for argument in arguments {
+        walk_parameter(argument, visit);
+    }
+}
+
+fn walk_helper<'t>(ht: &'t HelperTemplate, visit: &mut dyn FnMut(Reference<'t>)) {
+    if let Some(name) = ht.name.as_name() {
+        visit(if is_name_only(ht) {
+            Reference::Name(name)
+        } else {
+            Reference::Helper(name)
+        });
+    }
+    walk_arguments(ht.params.iter().chain(ht.hash.values()), visit);
+    for nested in ht.template.iter().chain(ht.inverse.iter()) {
+        walk(nested, visit);
+    }
+}
+
+fn walk_decorator<'t>(dt: &'t DecoratorTemplate, visit: &mut dyn FnMut(Reference<'t>)) {
+    walk_arguments(dt.params.iter().chain(dt.hash.values()), visit);
+    if let Some(nested) = &dt.template {
+        walk(nested, visit);
+    }
+}
+
+fn walk<'t>(template: &'t Template, visit: &mut dyn FnMut(Reference<'t>)) {
+    for element in &template.elements {
+        match element {
+            TemplateElement::HTMLExpression(parameter @ Parameter::Path(_)) => {
+                if let Some(name) = parameter.as_name() {
+                    visit(Reference::Name(name));
+                }
+            }
+            TemplateElement::HTMLExpression(parameter) => walk_parameter(parameter, visit),
+            TemplateElement::Expression(ht) | TemplateElement::HelperBlock(ht) => {
+                walk_helper(ht, visit)
+            }
+            TemplateElement::DecoratorExpression(dt)
+            | TemplateElement::DecoratorBlock(dt)
+            | TemplateElement::PartialExpression(dt)
+            | TemplateElement::PartialBlock(dt) => walk_decorator(dt, visit),
+            TemplateElement::RawString(_) | TemplateElement::Comment(_) => {}
+        }
+    }
+}
+
+/// Collects the names of all the helpers a template calls. Because a bare
+/// `{{name}}` can refer to either a variable or a helper, `is_helper` decides
+/// which names count as helpers.
+pub(crate) fn helpers_used(
+    template: &Template,
+    is_helper: &dyn Fn(&str) -> bool,
+) -> BTreeSet<String> {
+    let mut helpers = BTreeSet::new();
+    walk(template, &mut |reference| match reference {
+        Reference::Helper(name) => {
+            helpers.insert(name.to_string());
+        }
+        Reference::Name(name) if is_helper(name) => {
+            helpers.insert(name.to_string());
+        }
+        Reference::Name(_) | Reference::Argument(_) => {}
+    });
+    helpers
+}
+
+/// Lists the variables that a template substitutes into its output, in the
+/// order they appear and once per appearance.
+pub(crate) fn substitutions(template: &Template, is_helper: &dyn Fn(&str) -> bool) -> Vec<String> {
+    let mut variables = Vec::new();
+    walk(template, &mut |reference| {
+        if let Reference::Name(name) = reference {
+            if !is_helper(name) {
+                variables.push(name.to_string());
+            }
+        }
+    });
+    variables
+}
+
+/// Lists every variable that a template uses, whether it's substituted
+/// directly or passed to a helper, in the order they appear. Names that
+/// `is_helper` recognizes are left out.
+pub(crate) fn variables(template: &Template, is_helper: &dyn Fn(&str) -> bool) -> Vec<String> {
+    let mut variables = Vec::new();
+    walk(template, &mut |reference| match reference {
+        Reference::Name(name) | Reference::Argument(name) if !is_helper(name) => {
+            variables.push(name.to_string());
+        }
+        _ => {}
+    });
+    variables
+}
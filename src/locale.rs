@@ -0,0 +1,104 @@
+use chrono::{DateTime, NaiveDate};
+use handlebars::JsonValue;
+use num_format::ToFormattedString;
+use std::{convert::TryFrom, fmt::Write, str::FromStr};
+
+/// The locale that the `number` and `date` helpers format values for, set
+/// with `--locale`. Without one, they use a neutral format: numbers aren't
+/// grouped and dates are written as in ISO 8601.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Locale {
+    numbers: num_format::Locale,
+    dates: chrono::Locale,
+}
+
+impl FromStr for Locale {
+    type Err = String;
+
+    /// Parses a language tag like `de-DE` (or `de_DE`). A language on its
+    /// own, like `de`, stands for the region of the same name, `de-DE`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let tag = s.replace('_', "-");
+        let (language, region) = match tag.split_once('-') {
+            Some((language, region)) => (language.to_string(), region.to_uppercase()),
+            None => (tag.clone(), tag.to_uppercase()),
+        };
+        let numbers = num_format::Locale::from_name(format!("{}-{}", language, region))
+            .or_else(|_| num_format::Locale::from_name(&language));
+        let dates = chrono::Locale::try_from(format!("{}_{}", language, region).as_str());
+
+        match (numbers, dates) {
+            (Ok(numbers), Ok(dates)) => Ok(Locale { numbers, dates }),
+            _ => Err(format!(
+                "{:?} isn't a known locale. Give a language and region, like \"de-DE\".",
+                s
+            )),
+        }
+    }
+}
+
+/// Groups the digits of `digits` with the separator and grouping of
+/// `locale`, if there is one.
+fn group(digits: &str, locale: Option<&Locale>) -> String {
+    match (locale, digits.parse::<u128>()) {
+        (Some(locale), Ok(integer)) => integer.to_formatted_string(&locale.numbers),
+        _ => digits.to_string(),
+    }
+}
+
+/// Formats `value` for `locale`, with exactly `decimals` decimals if given
+/// and with as many as it takes otherwise.
+pub(crate) fn format_number(
+    value: f64,
+    decimals: Option<usize>,
+    locale: Option<&Locale>,
+) -> String {
+    let formatted = match decimals {
+        Some(decimals) => format!("{:.*}", decimals, value.abs()),
+        None => value.abs().to_string(),
+    };
+    let (integral, fraction) = match formatted.split_once('.') {
+        Some((integral, fraction)) => (integral, Some(fraction)),
+        None => (formatted.as_str(), None),
+    };
+
+    let mut result = String::new();
+    if value.is_sign_negative() && formatted.chars().any(|c| c.is_ascii_digit() && c != '0') {
+        result.push_str(locale.map_or("-", |locale| locale.numbers.minus_sign()));
+    }
+    result.push_str(&group(integral, locale));
+    if let Some(fraction) = fraction {
+        result.push_str(locale.map_or(".", |locale| locale.numbers.decimal()));
+        result.push_str(fraction);
+    }
+    result
+}
+
+/// Formats `value`, which is either a date (`2024-03-01`), a date and time
+/// as in RFC 3339 (`2024-03-01T12:00:00Z`), or a Unix timestamp in seconds.
+/// `format` takes `strftime`-style specifiers and defaults to the locale's
+/// date format. Returns `None` if `value` isn't a date or `format` is invalid.
+pub(crate) fn format_date(
+    value: &JsonValue,
+    format: Option<&str>,
+    locale: Option<&Locale>,
+) -> Option<String> {
+    let format = format.unwrap_or(if locale.is_some() { "%x" } else { "%Y-%m-%d" });
+    let dates = locale.map_or(chrono::Locale::POSIX, |locale| locale.dates);
+    let value = match value {
+        JsonValue::String(value) => value.clone(),
+        JsonValue::Number(value) => value.to_string(),
+        _ => return None,
+    };
+
+    let mut result = String::new();
+    let written = if let Ok(date) = NaiveDate::from_str(&value) {
+        write!(result, "{}", date.format_localized(format, dates))
+    } else if let Ok(date) = DateTime::parse_from_rfc3339(&value) {
+        write!(result, "{}", date.format_localized(format, dates))
+    } else {
+        let date = DateTime::from_timestamp(value.parse().ok()?, 0)?;
+        write!(result, "{}", date.format_localized(format, dates))
+    };
+    written.ok().map(|()| result)
+}
@@ -1,94 +1,2291 @@
+mod archive;
+mod cache;
+mod commands;
+mod comments;
+mod computed;
+mod doctor;
+mod encoding;
+mod engine;
+mod formats;
+mod front_matter;
+mod git;
+mod helpers;
+mod hook;
+mod inputs;
+mod inspect;
+mod locale;
+mod man;
+mod naming;
+mod ownership;
+mod packaged;
+mod progress;
+mod redact;
+mod remote;
+mod s3;
+mod sandbox;
+mod snippet;
+mod sqlite;
+mod summary;
+mod transaction;
+mod values;
+mod wasm;
+mod wrap;
 mod writer;
 
-use env_logger::Env;
-use log::{error, info, warn};
+use archive::Archive;
+use base64::Engine as _;
+use cache::Cache;
+use commands::ValueCommand;
+use encoding::OutputEncoding;
+use engine::{Engine, EngineKind, EngineOptions, OnMissing};
+use formats::ValuesFormat;
+use git::{Author, Committer};
+use handlebars::JsonValue;
+use helpers::Usage;
+use hook::PostHook;
+use indicatif::ProgressBar;
+use inputs::InputFile;
+use locale::Locale;
+use log::{debug, error, info, warn};
+use naming::FilenameTransform;
+use ownership::Owner;
+use redact::Redaction;
+use remote::Header;
+use s3::{S3Target, Uploader};
+use sandbox::Root;
 use serde::de::DeserializeOwned;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
-    ffi::OsStr,
-    fs::File,
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    env,
+    ffi::{OsStr, OsString},
+    fs::{self, File},
+    io::{self, Read},
     path::{Path, PathBuf},
+    process::ExitCode,
+    time::Duration,
 };
 use structopt::StructOpt;
-use writer::{render, Configuration, ProgramError};
+use summary::Summary;
+use transaction::Transaction;
+use values::{Mappings, MergeStrategy, Namespace, TypedKey};
+use writer::{
+    archive, diff, is_special_file, preview, referenced_keys, render, stage, upload, verify,
+    Configuration, DiffBase, Outcome, ProgramError, Status, WriteOptions,
+};
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "replacer")]
 struct Opts {
-    // A file containing a templated text using the Handlebars format
-    #[structopt(short = "f", long = "file", parse(from_os_str))]
-    input_file: PathBuf,
+    /// A file containing a templated text using the Handlebars format. Accepts glob patterns
+    /// and may be given multiple times to render several templates in one run.
+    #[structopt(
+        short = "f",
+        long = "file",
+        required_unless = "print-template-path",
+        number_of_values = 1
+    )]
+    input_files: Vec<String>,
+
+    /// A YAML file containing of key value pairs to be replaced. Can be given more than once,
+    /// in which case values from later files take precedence.
+    #[structopt(
+        short = "i",
+        parse(from_os_str),
+        number_of_values = 1,
+        required_unless_one = &[
+            "stdin-json-values",
+            "single-file",
+            "values-sqlite",
+            "values-url",
+            "values-dir",
+            "values-b64",
+            "dump-ast",
+            "print-template-path"
+        ],
+        conflicts_with = "stdin-json-values"
+    )]
+    replacements_files: Vec<PathBuf>,
+
+    /// The format of the replacements files: yaml, json, toml, or env (KEY=value lines). By
+    /// default, files ending in .toml are read as TOML, files ending in .env as env files, and
+    /// anything else as YAML (which covers JSON).
+    #[structopt(long = "values-format")]
+    values_format: Option<ValuesFormat>,
+
+    /// How the mappings of several replacements files are combined: "shallow" lets later files
+    /// replace whole top-level keys, "deep" merges nested mappings key by key, and "strict"
+    /// merges like "deep" but fails if two files give a key different values.
+    #[structopt(long = "values-merge-strategy", default_value = "shallow")]
+    merge_strategy: MergeStrategy,
+
+    /// A YAML file whose mappings are merged deeply into those from the replacements files:
+    /// nested mappings are merged key by key instead of being replaced as a whole. Can be given
+    /// more than once; later files take precedence.
+    #[structopt(
+        long = "values-override-file",
+        parse(from_os_str),
+        number_of_values = 1
+    )]
+    values_override_files: Vec<PathBuf>,
+
+    /// Fill in "{profile}" in the paths given with -i with this, so that "-i vars/{profile}.yaml
+    /// --profile prod" reads vars/prod.yaml. Templates can check it with `ifprofile` blocks.
+    #[structopt(long = "profile")]
+    profile: Option<String>,
+
+    /// Set a mapping, given as key=value. Takes precedence over every other source of mappings.
+    /// Can be given more than once.
+    #[structopt(
+        short = "D",
+        long = "define",
+        parse(try_from_str = parse_definition),
+        number_of_values = 1
+    )]
+    definitions: Vec<(String, String)>,
+
+    /// Use the environment variables whose names start with this prefix as mappings, with the
+    /// prefix removed, so that "--from-env APP_" turns APP_PORT into "port". They take
+    /// precedence over the replacements files.
+    #[structopt(long = "from-env")]
+    from_env: Option<String>,
+
+    /// Set a mapping to the output of a command, given as key="command args", e.g.
+    /// commit="git rev-parse HEAD". The output is trimmed, and the command is split at
+    /// whitespace and run without a shell. Takes precedence over everything but -D. Can be
+    /// given more than once.
+    #[structopt(long = "values-from-command", number_of_values = 1)]
+    values_from_command: Vec<ValueCommand>,
+
+    /// Print the merged mappings that each template is rendered with to stderr as YAML before
+    /// rendering. Values of keys given with --redact (and, with --redact-log, of keys that look
+    /// sensitive) are hidden. Combined with --dry-run, exit after printing them.
+    #[structopt(long = "echo-values")]
+    echo_values: bool,
+
+    /// Read the mappings from a JSON object on stdin instead of from a file given with -i.
+    #[structopt(long = "stdin-json-values", conflicts_with = "each-dir")]
+    stdin_json_values: bool,
+
+    /// Read the mappings from a SQLite database instead of from a file given with -i, with the
+    /// query given with --values-query. Requires the `sqlite` feature.
+    #[structopt(
+        long = "values-sqlite",
+        parse(from_os_str),
+        requires = "values-query",
+        conflicts_with_all = &["replacements-files", "stdin-json-values", "each-dir"]
+    )]
+    values_sqlite: Option<PathBuf>,
+
+    /// The query that reads the mappings from the --values-sqlite database, such as "SELECT
+    /// key, value FROM config". Its first column is the key and its second column the value.
+    #[structopt(long = "values-query", requires = "values-sqlite")]
+    values_query: Option<String>,
+
+    /// Fetch the mappings from this URL instead of from a file given with -i. They're parsed
+    /// as --values-format, or else as the URL's extension suggests. Requires the `remote`
+    /// feature.
+    #[structopt(
+        long = "values-url",
+        conflicts_with_all = &["replacements-files", "stdin-json-values", "values-sqlite", "each-dir"]
+    )]
+    values_url: Option<String>,
+
+    /// Read the mappings from this base64-encoded document instead of from a file given with
+    /// -i. It's parsed as YAML (which covers JSON) unless --values-format says otherwise.
+    #[structopt(
+        long = "values-b64",
+        conflicts_with_all = &[
+            "replacements-files",
+            "stdin-json-values",
+            "values-sqlite",
+            "values-url",
+            "values-dir",
+            "each-dir"
+        ]
+    )]
+    values_b64: Option<String>,
+
+    /// Render the template once per YAML file in this directory, each file being a set of
+    /// mappings of its own, and name each output file after the stem of its values file.
+    #[structopt(
+        long = "values-dir",
+        parse(from_os_str),
+        conflicts_with_all = &[
+            "replacements-files",
+            "stdin-json-values",
+            "values-sqlite",
+            "values-url",
+            "each-dir",
+            "multi"
+        ]
+    )]
+    values_dir: Option<PathBuf>,
+
+    /// A header to send with the --values-url request, given as "Name: value", such as
+    /// "Authorization: Bearer <token>". Can be given more than once.
+    #[structopt(long = "values-header", requires = "values-url", number_of_values = 1)]
+    values_headers: Vec<Header>,
+
+    /// A YAML file containing program configuration. Can be given more than once, in which case
+    /// the first one that exists is used.
+    #[structopt(
+        short = "c",
+        long = "config-file",
+        parse(from_os_str),
+        number_of_values = 1,
+        required_unless_one = &["each-dir", "dump-ast", "print-template-path"]
+    )]
+    config_files: Vec<PathBuf>,
+
+    /// Render the templates without writing anything to disk.
+    #[structopt(long = "dry-run")]
+    dry_run: bool,
+
+    /// In dry-run mode, print the rendered output to stderr.
+    #[structopt(long = "show-output", requires = "dry-run")]
+    show_output: bool,
+
+    /// Keep the directory structure below each input pattern's base directory in the output
+    /// directory instead of writing all results next to each other.
+    #[structopt(long = "preserve-tree")]
+    preserve_tree: bool,
+
+    /// Name each output file after its input's path relative to this directory, keeping the
+    /// directory structure below it. Every input file must be inside the directory.
+    #[structopt(
+        long = "relative-to",
+        parse(from_os_str),
+        conflicts_with = "preserve-tree"
+    )]
+    relative_to: Option<PathBuf>,
+
+    /// Text to insert before the extension of output file names, e.g. ".generated" to write
+    /// "config.yaml" as "config.generated.yaml". Names given with -o are used as they are.
+    #[structopt(long = "output-suffix")]
+    output_suffix: Option<String>,
+
+    /// A directory to look for templates in. Names given with -f are relative to it, and can
+    /// leave out the template extension, so "-f nginx" finds "nginx.hbs".
+    #[structopt(long = "template-dir", parse(from_os_str))]
+    template_dir: Option<PathBuf>,
+
+    /// Print the paths that a template name given with -f is looked for at, and the file it
+    /// resolves to, then exit. For debugging --template-dir lookups.
+    #[structopt(long = "print-template-path")]
+    print_template_path: Option<String>,
+
+    /// A directory to resolve the -f patterns in instead of the current directory. With
+    /// --preserve-tree, output paths are relative to it rather than to each pattern's base.
+    #[structopt(
+        long = "input-base",
+        parse(from_os_str),
+        conflicts_with = "template-dir"
+    )]
+    input_base: Option<PathBuf>,
+
+    /// An extra template file extension (e.g. ".tpl") to strip from output file names, on top
+    /// of ".hbs", ".tmpl", and ".j2". Can be given more than once.
+    #[structopt(long = "template-ext", number_of_values = 1)]
+    template_exts: Vec<String>,
+
+    /// Print a diff between the existing output files and the rendered results instead of
+    /// writing anything.
+    #[structopt(long = "diff", conflicts_with = "dry-run")]
+    diff: bool,
+
+    /// With --diff, what to compare the rendered results against: "worktree" (the output files
+    /// on disk, the default) or "git" (the output files as they are in the git index).
+    #[structopt(long = "diff-against", default_value = "worktree")]
+    diff_against: DiffBase,
+
+    /// With --diff, exit with status 1 if any output file would change.
+    #[structopt(long = "exit-code", requires = "diff")]
+    exit_code: bool,
+
+    /// Check that every output file exists and matches what rendering would produce, without
+    /// writing anything. Lists the missing and stale files and exits with status 1 if there
+    /// are any.
+    #[structopt(long = "verify", conflicts_with_all = &["dry-run", "diff"])]
+    verify: bool,
+
+    /// The template engine to render with: "handlebars" or "envsubst" (plain ${KEY} and $KEY
+    /// substitution).
+    #[structopt(long = "engine", default_value = "handlebars")]
+    engine: EngineKind,
+
+    /// The permissions to give output files, in octal (e.g. 600). Only supported on Unix. By
+    /// default, the process umask decides.
+    #[structopt(long = "chmod", parse(try_from_str = parse_mode))]
+    chmod: Option<u32>,
+
+    /// A comma-separated list of the only helpers templates may use. By default, all helpers
+    /// are available.
+    #[structopt(long = "allow-helpers", use_delimiter = true)]
+    allow_helpers: Option<Vec<String>>,
+
+    /// A directory of WASM modules to load as extra helpers, each named after its file. Requires
+    /// a build with the `wasm-helpers` feature.
+    #[structopt(long = "helpers-dir", parse(from_os_str))]
+    helpers_dir: Option<PathBuf>,
+
+    /// After rendering, list the helpers that replacer registers (its own and those from
+    /// --helpers-dir) on stderr, along with how often each was called, and the ones that
+    /// weren't called at all.
+    #[structopt(long = "report-unused-helpers")]
+    report_unused_helpers: bool,
+
+    /// Seed the random number generator behind the uuid and randhex helpers, so that they
+    /// produce the same values on every run.
+    #[structopt(long = "seed")]
+    seed: Option<u64>,
+
+    /// The directory that paths given to the iffile helper are relative to. They can't point
+    /// outside of it. Defaults to the current directory.
+    #[structopt(long = "file-root", parse(from_os_str))]
+    file_root: Option<PathBuf>,
+
+    /// Show a progress bar while rendering. Only shown when stderr is a terminal.
+    #[structopt(long = "progress")]
+    progress: bool,
+
+    /// Print the fully resolved configuration (the config file with environment variables
+    /// expanded, and where each template will be written) to stderr as YAML before rendering.
+    /// Combined with --dry-run, exit after printing it.
+    #[structopt(long = "echo-config")]
+    echo_config: bool,
+
+    /// Only log errors.
+    #[structopt(short = "q", long = "quiet")]
+    quiet: bool,
+
+    /// The name of the output file, relative to the output directory. By default, the name of
+    /// the input file is used. The name is rendered with the mappings, like a template, so it
+    /// can use them, e.g. "{{service}}-config.yaml".
+    #[structopt(short = "o", long = "output")]
+    output: Option<String>,
+
+    /// Treat the replacements file as a list of mappings and render each template once per
+    /// entry. Requires an --output name that uses the mappings to give each result its own
+    /// name, e.g. "{{name}}.conf".
+    #[structopt(long = "multi", requires = "output")]
+    multi: bool,
+
+    /// Log every variable that gets substituted, along with its value, at trace level.
+    #[structopt(long = "trace-render")]
+    trace_render: bool,
+
+    /// A comma-separated list of keys whose values should never be logged.
+    #[structopt(long = "redact", use_delimiter = true)]
+    redact: Vec<String>,
+
+    /// Also hide the values of keys that look sensitive in the logs, such as ones containing
+    /// "password", "secret", or "token", logging them as ****. Which keys look sensitive can be
+    /// changed with --redact-pattern.
+    #[structopt(long = "redact-log")]
+    redact_log: bool,
+
+    /// A glob pattern for keys whose values --redact-log hides, like "*password*", instead of
+    /// the built-in ones. Matching ignores case. Can be given more than once.
+    #[structopt(long = "redact-pattern", number_of_values = 1, requires = "redact-log")]
+    redact_patterns: Vec<glob::Pattern>,
 
-    // A YAML file containing of key value pairs to be replaced.
-    #[structopt(short = "i", parse(from_os_str))]
-    replacements_file: PathBuf,
+    /// Hand output files over to this user and group (user:group, user, or :group) after
+    /// writing them. Requires sufficient privileges and a Unix build with the `chown` feature.
+    #[structopt(long = "owner")]
+    owner: Option<String>,
 
-    // A YAML file containing program configuration.
-    #[structopt(short = "c", long = "config-file", parse(from_os_str))]
-    config_file: PathBuf,
+    /// Render the templates once for every subdirectory of this directory that contains a
+    /// replacements file (named like the one given with -i), writing the results into that
+    /// subdirectory. The output directory from the configuration file is ignored.
+    #[structopt(long = "each-dir", parse(from_os_str), requires = "replacements-files")]
+    each_dir: Option<PathBuf>,
+
+    /// A comma-separated list of key:type pairs, e.g. "port:int,enabled:bool". The values of
+    /// these keys are converted to the given type (string, int, float, or bool) before
+    /// rendering.
+    #[structopt(long = "types", use_delimiter = true)]
+    types: Vec<TypedKey>,
+
+    /// Make the values in a file available under a top-level key, given as name=path, so that
+    /// "--namespace db=db.yaml" lets templates use "{{db.host}}". Can be given more than once.
+    #[structopt(long = "namespace", number_of_values = 1)]
+    namespaces: Vec<Namespace>,
+
+    /// Read the mappings from under this dotted path (e.g. "tools.replacer") or JSON Pointer
+    /// (e.g. "/tools/replacer") in the replacements file instead of from its top level.
+    #[structopt(long = "values-key")]
+    values_key: Option<String>,
+
+    /// Treat environment variables that the config file refers to but that aren't set as
+    /// empty, instead of failing.
+    #[structopt(long = "allow-unset-env")]
+    allow_unset_env: bool,
+
+    /// Lock each output file while writing it, so that several runs writing to the same
+    /// output directory at once don't corrupt each other's results.
+    #[structopt(long = "parallel-safe")]
+    parallel_safe: bool,
+
+    /// With --parallel-safe, how many seconds to wait for another run to release an output
+    /// file before giving up.
+    #[structopt(long = "lock-timeout", default_value = "30")]
+    lock_timeout: u64,
+
+    /// Flatten nested mappings into top-level keys before rendering, joining the keys with
+    /// --flatten-separator, so that a "host" under "db" becomes "db_host".
+    #[structopt(long = "values-flatten")]
+    values_flatten: bool,
+
+    /// What to join keys with for --values-flatten. Defaults to "_".
+    #[structopt(long = "flatten-separator", requires = "values-flatten")]
+    flatten_separator: Option<String>,
+
+    /// Leave out every mapping whose value is exactly this string (e.g. "__NULL__"), as if the
+    /// key weren't in the replacements file at all.
+    #[structopt(long = "null-value")]
+    null_value: Option<String>,
+
+    /// How many times to retry writing an output file after a transient failure, such as
+    /// those seen on some network filesystems. Other failures aren't retried.
+    #[structopt(long = "write-retries", default_value = "3")]
+    write_retries: u32,
+
+    /// How many milliseconds to wait before retrying a failed write. The delay doubles with
+    /// every further retry.
+    #[structopt(long = "retry-delay", default_value = "100")]
+    retry_delay: u64,
+
+    /// Keep rendering the remaining templates after one fails, instead of stopping. The run
+    /// still exits with status 1 if anything failed.
+    #[structopt(long = "keep-going")]
+    keep_going: bool,
+
+    /// With --keep-going, stop the run anyway once this many templates have failed. By default,
+    /// there's no limit.
+    #[structopt(long = "fail-fast-threshold", requires = "keep-going")]
+    fail_fast_threshold: Option<usize>,
+
+    /// Write a JSON summary of the run (counts of rendered, skipped, and failed templates,
+    /// bytes written, elapsed time, and errors) to this file, or to stdout if it's "-".
+    #[structopt(long = "summary-json")]
+    summary_json: Option<String>,
+
+    /// The encoding to write output files in: "utf-8" (the default), "latin1", or "utf-16le".
+    #[structopt(long = "output-encoding", default_value = "utf-8")]
+    output_encoding: OutputEncoding,
+
+    /// Replace characters that the output encoding can't represent with '?' instead of
+    /// failing.
+    #[structopt(long = "lossy")]
+    lossy: bool,
+
+    /// Refuse to write any output file that would end up outside this directory once symlinks
+    /// and ".." components are resolved. Nothing is written if any output file would.
+    #[structopt(long = "root", parse(from_os_str))]
+    root: Option<PathBuf>,
+
+    /// Keep track of what each output file was rendered from in this directory, and skip
+    /// outputs whose template and mappings haven't changed since they were last written.
+    #[structopt(long = "cache-dir", parse(from_os_str))]
+    cache_dir: Option<PathBuf>,
+
+    /// What to do with references to keys that aren't in the mappings: "error" (the
+    /// default), "empty" to render nothing in their place, or "keep" to leave them in the
+    /// output as written.
+    #[structopt(long = "on-missing", default_value = "error")]
+    on_missing: OnMissing,
+
+    /// Remove comments that are alone on their lines ({{! ... }} or {{!-- ... --}}, with
+    /// nothing but whitespace around them) from Handlebars templates, lines and all, so that
+    /// they don't leave blank lines in the output.
+    #[structopt(long = "strip-comments")]
+    strip_comments: bool,
+
+    /// Keep the contents of {{#comment}} blocks in the output. By default, they're left out, so
+    /// that one template can give both annotated and minimal output.
+    #[structopt(long = "keep-comments")]
+    keep_comments: bool,
+
+    /// Hard-wrap the rendered output so that no line is longer than this many characters,
+    /// breaking lines at whitespace where possible. Existing line breaks are kept.
+    #[structopt(long = "wrap", parse(try_from_str = parse_width))]
+    wrap: Option<usize>,
+
+    /// Don't write output files that already have exactly the rendered contents, so that their
+    /// modification times only change when their contents do.
+    #[structopt(long = "only-if-changed")]
+    only_if_changed: bool,
+
+    /// Read mappings from YAML front matter at the top of each template, between two "---"
+    /// lines, and render the rest of the file. The front matter takes precedence over the
+    /// replacements files, but not over --from-env and -D. With this, -i is optional.
+    #[structopt(long = "single-file", conflicts_with = "multi")]
+    single_file: bool,
+
+    /// Instead of rendering anything, print how many templates there are, how many distinct
+    /// keys they substitute, and which of those keys are missing from the mappings to stdout.
+    #[structopt(long = "count-only", conflicts_with_all = &["diff", "verify"])]
+    count_only: bool,
+
+    /// A command to run on every output file after it's been written, such as a formatter,
+    /// where "{}" stands for the path of the file, e.g. "yamlfmt {}". The command is split at
+    /// whitespace and run without a shell. If it fails, a warning is logged.
+    #[structopt(long = "post-hook")]
+    post_hook: Option<PostHook>,
+
+    /// Treat a failing --post-hook (one that exits with a non-zero status or can't be run) as
+    /// a failure of the output it ran on.
+    #[structopt(long = "post-hook-strict", requires = "post-hook")]
+    post_hook_strict: bool,
+
+    /// Instead of writing output files, put them in a ZIP archive at this path, at their paths
+    /// relative to the output directory. The archive is only written if every template
+    /// renders, and it replaces any existing file at once, so it's never seen half-written.
+    #[structopt(
+        long = "zip",
+        parse(from_os_str),
+        conflicts_with_all = &["diff", "verify", "count-only"]
+    )]
+    zip: Option<PathBuf>,
+
+    /// Instead of writing output files, upload them to an S3 bucket, as in
+    /// "s3://bucket/prefix", at their paths relative to the output directory under the prefix.
+    /// Credentials are read from the environment or ~/.aws/credentials. Requires the `s3`
+    /// feature.
+    #[structopt(
+        long = "output-s3",
+        conflicts_with_all = &["diff", "verify", "count-only", "zip"]
+    )]
+    output_s3: Option<S3Target>,
+
+    /// The locale that the number and date helpers format values for, as a language and
+    /// region, e.g. "de-DE". Without it, numbers aren't grouped and dates are written as in
+    /// ISO 8601.
+    #[structopt(long = "locale")]
+    locale: Option<Locale>,
+
+    /// Disable the helpers that read the environment or the file system (env, secret, csv,
+    /// and iffile), for rendering templates you don't trust. Templates that use them are
+    /// rejected before anything is rendered.
+    #[structopt(long = "safe")]
+    safe: bool,
+
+    /// Leave out templates that are more than this many directories below the part of their
+    /// -f pattern before the first wildcard, so that 0 only takes the files directly in it. By
+    /// default, there's no limit.
+    #[structopt(long = "max-depth")]
+    max_depth: Option<usize>,
+
+    /// After writing the output files, stage them in the git repository they're in and commit
+    /// them with this message. Nothing is committed if they haven't changed, or if any
+    /// template fails. Requires the `git` feature.
+    #[structopt(
+        long = "commit",
+        conflicts_with_all = &["dry-run", "diff", "verify", "count-only", "zip", "output-s3"]
+    )]
+    commit: Option<String>,
+
+    /// The author of the commit made with --commit, as "Name <email>". Defaults to the
+    /// user.name and user.email that git is configured with.
+    #[structopt(long = "commit-author", requires = "commit")]
+    commit_author: Option<Author>,
+
+    /// A YAML file with a list of keys that the mappings must have, dotted for nested keys.
+    /// If any of them are missing from any set of mappings, nothing is rendered, and the error
+    /// names all of them.
+    #[structopt(long = "required", parse(from_os_str))]
+    required: Option<PathBuf>,
+
+    /// Write either every output file or none of them: each result is written to a temporary
+    /// file next to its output file first, and only once every template has rendered are they
+    /// all moved into place. If any template fails, the temporary files are removed.
+    #[structopt(
+        long = "transactional",
+        conflicts_with_all = &["dry-run", "diff", "verify", "count-only", "zip", "output-s3"]
+    )]
+    transactional: bool,
+
+    /// Print each template to stderr as the engine sees it, before it's rendered: without its
+    /// front matter, and with the comments and annotations that are removed before rendering
+    /// taken out. For debugging templates.
+    #[structopt(long = "dump-preprocessed")]
+    dump_preprocessed: bool,
+
+    /// Print the tree of elements that each template is parsed into to stderr and exit without
+    /// rendering anything, so no mappings or config file are needed. For debugging templates.
+    #[structopt(long = "dump-ast")]
+    dump_ast: bool,
+
+    /// Render the templates that have the same output file (usually all of them, with -o)
+    /// into that one file, one after another in the order they're given, instead of each one
+    /// overwriting the last.
+    #[structopt(long = "concat")]
+    concat: bool,
+
+    /// What to put between the results of the templates combined with --concat. Nothing by
+    /// default.
+    #[structopt(long = "separator", requires = "concat", allow_hyphen_values = true)]
+    separator: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+fn parse_width(width: &str) -> Result<usize, String> {
+    width
+        .parse()
+        .ok()
+        .filter(|width| *width > 0)
+        .ok_or_else(|| format!("{:?} is not a valid line width.", width))
+}
+
+fn parse_mode(mode: &str) -> Result<u32, String> {
+    u32::from_str_radix(mode.trim_start_matches("0o"), 8)
+        .ok()
+        .filter(|mode| *mode <= 0o7777)
+        .ok_or_else(|| format!("{:?} is not a valid octal file mode.", mode))
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 struct Config {
     output_dir: PathBuf,
+    /// The naming convention to convert output file names to, unless they're
+    /// given with -o.
+    filename_transform: Option<FilenameTransform>,
+    /// Mappings to use when no other source has them.
+    #[serde(default, skip_serializing_if = "serde_yaml::Value::is_null")]
+    defaults: serde_yaml::Value,
+    /// Templates for mappings that are derived from the others, by key.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    computed: BTreeMap<String, String>,
 }
 
 fn open_file(path: &PathBuf) -> Result<File, ProgramError> {
     File::open(path).map_err(|_| ProgramError::FileNotFound(path.clone()))
 }
 
+fn read_template(path: &PathBuf) -> Result<String, ProgramError> {
+    if let Some(entry) = packaged::Entry::parse(path) {
+        return entry.read();
+    }
+    let mut template = String::new();
+    open_file(path)?
+        .read_to_string(&mut template)
+        .map_err(|_| {
+            ProgramError::RenderError(String::from("I/O Error when rendering template."), None)
+        })?;
+    Ok(template)
+}
+
+/// Reads a YAML (or JSON) file. A file without any content (nothing but
+/// whitespace and comments) is read as null.
+fn read_yaml(path: &PathBuf) -> Result<serde_yaml::Value, ProgramError> {
+    let contents =
+        fs::read_to_string(path).map_err(|_| ProgramError::FileNotFound(path.clone()))?;
+    parse_yaml(&contents, path)
+}
+
+/// Parses YAML (or JSON) read from `path`, like `read_yaml`.
+fn parse_yaml(contents: &str, path: &Path) -> Result<serde_yaml::Value, ProgramError> {
+    let is_blank = contents.lines().all(|line| {
+        let line = line.trim();
+        line.is_empty() || line.starts_with('#')
+    });
+    if is_blank {
+        return Ok(serde_yaml::Value::Null);
+    }
+
+    serde_yaml::from_str(contents)
+        .map(values::resolve_merge_keys)
+        .map_err(|_| ProgramError::ReadFailed(path.to_path_buf()))
+}
+
+/// Reads a replacements file in the format given with --values-format, or
+/// else the one its extension suggests.
+fn read_values_file(path: &PathBuf, opts: &Opts) -> Result<serde_yaml::Value, ProgramError> {
+    let contents =
+        fs::read_to_string(path).map_err(|_| ProgramError::FileNotFound(path.clone()))?;
+    parse_values(&contents, path, opts)
+}
+
+/// Parses replacements read from `source`, like `read_values_file`.
+fn parse_values(
+    contents: &str,
+    source: &Path,
+    opts: &Opts,
+) -> Result<serde_yaml::Value, ProgramError> {
+    let format = opts
+        .values_format
+        .unwrap_or_else(|| ValuesFormat::detect(source));
+    let parse = match format {
+        ValuesFormat::Yaml => return parse_yaml(contents, source),
+        ValuesFormat::Json => formats::parse_json,
+        ValuesFormat::Toml => formats::parse_toml,
+        ValuesFormat::Env => formats::parse_env,
+    };
+
+    parse(contents).map_err(|reason| {
+        ProgramError::CannotParseValues(source.to_path_buf(), format.name(), reason)
+    })
+}
+
+/// Fetches the replacements from --values-url, in the format given with
+/// --values-format, or else the one the URL's path suggests.
+fn fetch_values(url: &str, opts: &Opts) -> Result<Vec<Mappings>, ProgramError> {
+    let contents = remote::fetch(url, &opts.values_headers)?;
+    // The query and fragment aren't part of the path the format goes by.
+    let source = PathBuf::from(url.split(['?', '#']).next().unwrap_or(url));
+    file_entries(parse_values(&contents, &source, opts)?, &source, opts)
+}
+
 fn deserialize<T>(path: &PathBuf) -> Result<T, ProgramError>
 where
     T: DeserializeOwned,
 {
-    open_file(path).and_then(|f| {
-        serde_yaml::from_reader::<_, T>(f).map_err(|_| ProgramError::ReadFailed(path.clone()))
+    read_yaml(path).and_then(|value| {
+        serde_yaml::from_value(value).map_err(|_| ProgramError::ReadFailed(path.clone()))
     })
 }
 
-fn parse_input_files(opts: &Opts) -> Result<Configuration, ProgramError> {
-    let template = open_file(&opts.input_file)?;
-    let mappings: HashMap<String, String> = deserialize(&opts.replacements_file)?;
-    let config: Config = deserialize(&opts.config_file)?;
-    let filename = opts.input_file.as_path().file_name().unwrap_or_else(|| {
-        let default_name = "output";
-        warn!(
-            "Unable to generate an output filename based on the the input file; using {} instead.",
-            &default_name
-        );
-        OsStr::new(default_name)
+/// Expands `${VAR}` and `$VAR` references to environment variables in the
+/// config.
+fn expand_env(config: Config, opts: &Opts) -> Result<Config, ProgramError> {
+    let output_dir = match config.output_dir.to_str() {
+        Some(output_dir) => output_dir,
+        None => return Ok(config),
+    };
+
+    let expanded = engine::substitute(output_dir, &mut |name| match env::var(name) {
+        Ok(value) => Ok(Some(value)),
+        Err(_) if opts.allow_unset_env => Ok(Some(String::new())),
+        Err(_) => Err(ProgramError::UnsetEnvironmentVariable(name.to_string())),
+    })?;
+
+    Ok(Config {
+        output_dir: PathBuf::from(expanded),
+        ..config
+    })
+}
+
+/// Extensions that mark a file as a template. They're stripped from output
+/// file names, so that `nginx.conf.hbs` renders to `nginx.conf`.
+const TEMPLATE_EXTENSIONS: [&str; 3] = [".hbs", ".tmpl", ".j2"];
+
+/// The known template extensions and the ones added with --template-ext,
+/// without their leading dots.
+fn template_extensions(opts: &Opts) -> Vec<&str> {
+    TEMPLATE_EXTENSIONS
+        .iter()
+        .copied()
+        .chain(opts.template_exts.iter().map(String::as_str))
+        .map(|ext| ext.trim_start_matches('.'))
+        .filter(|ext| !ext.is_empty())
+        .collect()
+}
+
+/// Removes a template extension from the end of `path`'s file name, if it has
+/// one. Names that consist of nothing but the extension are left alone.
+fn strip_template_extension(path: PathBuf, opts: &Opts) -> PathBuf {
+    let name = match path.file_name().and_then(OsStr::to_str) {
+        Some(name) => name,
+        None => return path,
+    };
+
+    let stripped = template_extensions(opts).into_iter().find_map(|ext| {
+        name.strip_suffix(ext)
+            .and_then(|rest| rest.strip_suffix('.'))
+            .filter(|rest| !rest.is_empty())
     });
 
-    let output_file = Path::new(&config.output_dir.join(&filename)).to_path_buf();
+    match stripped {
+        Some(stripped) => path.with_file_name(stripped),
+        None => path,
+    }
+}
+
+/// Inserts `suffix` before the extension of `path`'s file name, so that
+/// `config.yaml` becomes `config.generated.yaml` with `.generated`. Names
+/// without an extension get the suffix at the end.
+fn add_output_suffix(path: PathBuf, suffix: &str) -> PathBuf {
+    let mut name = match path.file_stem() {
+        Some(stem) => stem.to_os_string(),
+        None => return path,
+    };
+    name.push(suffix);
+    if let Some(extension) = path.extension() {
+        name.push(".");
+        name.push(extension);
+    }
+    path.with_file_name(name)
+}
+
+fn default_output_path(input: &InputFile, opts: &Opts) -> Result<PathBuf, ProgramError> {
+    if let Some(root) = &opts.relative_to {
+        return input
+            .path_relative_to(root)
+            .map(|path| strip_template_extension(path, opts));
+    }
+
+    // Input expansion only matches files, so paths without a file name, like
+    // `.`, `..`, and `/`, never get this far. A template in an archive is
+    // named after its entry.
+    let entry = packaged::Entry::parse(&input.path);
+    let filename = match (&entry, input.path.file_name()) {
+        (Some(entry), _) => OsString::from(entry.file_name()),
+        (None, Some(filename)) => filename.to_os_string(),
+        (None, None) => return Err(ProgramError::NoOutputName(input.path.clone())),
+    };
+
+    let path = match input.relative_path() {
+        Some(relative_path) if opts.preserve_tree => relative_path.to_path_buf(),
+        _ => PathBuf::from(filename),
+    };
+    Ok(strip_template_extension(path, opts))
+}
+
+/// Gives an output file the `stem` of its --values-dir file as its name,
+/// keeping its extension, so that `nginx.conf` becomes `acme.conf`.
+fn rename_after_values_file(path: PathBuf, stem: &OsStr) -> PathBuf {
+    let mut name = stem.to_os_string();
+    if let Some(extension) = path.extension() {
+        name.push(".");
+        name.push(extension);
+    }
+    path.with_file_name(name)
+}
+
+fn configure(
+    input: &InputFile,
+    template: &str,
+    mappings: &Mappings,
+    target: &Target,
+    opts: &Opts,
+    engine: &dyn Engine,
+) -> Result<Configuration, ProgramError> {
+    let config = &target.config;
+    let input_file = &input.path;
+
+    let declared = match opts.engine {
+        EngineKind::Handlebars => comments::output_path(template),
+        EngineKind::Envsubst => None,
+    };
+    let output_file = match opts.output.as_deref().or(declared) {
+        Some(name) => config.output_dir.join(engine.render(name, mappings)?),
+        None => {
+            let path = default_output_path(input, opts)?;
+            let path = match &target.output_stem {
+                Some(stem) => rename_after_values_file(path, stem),
+                None => path,
+            };
+            let path = match config.filename_transform {
+                Some(transform) => transform.apply(path),
+                None => path,
+            };
+            config.output_dir.join(match &opts.output_suffix {
+                Some(suffix) => add_output_suffix(path, suffix),
+                None => path,
+            })
+        }
+    };
 
     info!(
-        "Creating file {:?} using {:?} as a template and {:?} as a replacements file.",
-        &output_file, &opts.input_file, &opts.replacements_file,
+        "Creating file {:?} using {:?} as a template and {}.",
+        &output_file,
+        input_file,
+        target.describe_sources(),
     );
 
     Ok(Configuration {
-        template,
-        mappings,
+        template: template.to_string(),
+        template_path: input_file.clone(),
+        mappings: mappings.clone(),
         output_file,
+        output_dir: config.output_dir.clone(),
+        appended: Vec::new(),
+        separator: String::new(),
+        wrap: opts.wrap,
     })
 }
 
-fn main() -> Result<(), ()> {
-    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+/// Combines the configurations that have the same output file into one, in
+/// the order they first appear, so that their results are concatenated.
+fn concatenate(configurations: Vec<Configuration>, separator: &str) -> Vec<Configuration> {
+    let mut combined: Vec<Configuration> = Vec::new();
+    for configuration in configurations {
+        match combined
+            .iter_mut()
+            .find(|first| first.output_file == configuration.output_file)
+        {
+            Some(first) => first.appended.push(configuration),
+            None => combined.push(Configuration {
+                separator: separator.to_string(),
+                ..configuration
+            }),
+        }
+    }
+    combined
+}
 
-    let opts = Opts::from_args();
+fn check_unique_outputs(configurations: &[Configuration]) -> Result<(), ProgramError> {
+    let mut output_files = HashSet::new();
+    match configurations
+        .iter()
+        .find(|configuration| !output_files.insert(&configuration.output_file))
+    {
+        Some(duplicate) => Err(ProgramError::DuplicateOutputFile(
+            duplicate.output_file.clone(),
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Makes sure that no two templates are rendered to the same output file,
+/// which happens when templates in different directories share a name. Pipes
+/// and devices can take any number of results, so they're left out.
+fn check_output_collisions(configurations: &[Configuration]) -> Result<(), ProgramError> {
+    let mut inputs: HashMap<&Path, Vec<&Path>> = HashMap::new();
+    for configuration in configurations {
+        let templates = inputs.entry(&configuration.output_file).or_default();
+        if !templates.contains(&configuration.template_path.as_path()) {
+            templates.push(&configuration.template_path);
+        }
+    }
+
+    match configurations.iter().find(|configuration| {
+        inputs[configuration.output_file.as_path()].len() > 1
+            && !is_special_file(&configuration.output_file)
+    }) {
+        Some(collision) => Err(ProgramError::OutputCollision(
+            collision.output_file.clone(),
+            inputs[collision.output_file.as_path()]
+                .iter()
+                .map(|path| path.to_path_buf())
+                .collect(),
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Adds the config file's `computed` values to each of the mapping sets.
+fn compute_values(
+    config: &Config,
+    mut mapping_sets: Vec<Mappings>,
+    opts: &Opts,
+    engine: &dyn Engine,
+) -> Result<Vec<Mappings>, ProgramError> {
+    let redaction = redaction(opts);
+    for mappings in &mut mapping_sets {
+        computed::apply(&config.computed, mappings, &redaction, engine)?;
+    }
+    Ok(mapping_sets)
+}
+
+/// A set of mappings to render the templates with, along with where they
+/// came from and where the results should go.
+struct Target {
+    replacements_files: Vec<PathBuf>,
+    /// The config file's `defaults`.
+    defaults: Mappings,
+    /// The entries of each replacements file, before they're merged.
+    entries: Vec<Vec<Mappings>>,
+    /// The mappings that are the same for every target.
+    shared: SharedMappings,
+    mapping_sets: Vec<Mappings>,
+    config: Config,
+    /// With --values-dir, what the output files are named after: the stem of
+    /// the values file.
+    output_stem: Option<OsString>,
+}
+
+impl Target {
+    fn new(
+        replacements_files: Vec<PathBuf>,
+        defaults: Mappings,
+        entries: Vec<Vec<Mappings>>,
+        shared: &SharedMappings,
+        config: Config,
+        opts: &Opts,
+        engine: &dyn Engine,
+    ) -> Result<Target, ProgramError> {
+        let mapping_sets = merge_mapping_sets(
+            &defaults,
+            &replacements_files,
+            entries.clone(),
+            &[],
+            shared,
+            opts,
+        )?;
+        Ok(Target {
+            mapping_sets: compute_values(&config, mapping_sets, opts, engine)?,
+            replacements_files,
+            defaults,
+            entries,
+            shared: shared.clone(),
+            config,
+            output_stem: None,
+        })
+    }
+
+    /// The mapping sets for a template with its own mappings (its vars file
+    /// and front matter), which are merged in on top of the replacements
+    /// files.
+    fn for_template(
+        &self,
+        template_layers: &[Mappings],
+        opts: &Opts,
+        engine: &dyn Engine,
+    ) -> Result<Vec<Mappings>, ProgramError> {
+        let mapping_sets = merge_mapping_sets(
+            &self.defaults,
+            &self.replacements_files,
+            self.entries.clone(),
+            template_layers,
+            &self.shared,
+            opts,
+        )?;
+        compute_values(&self.config, mapping_sets, opts, engine)
+    }
+
+    /// Describes where the mappings came from, for logs.
+    fn describe_sources(&self) -> String {
+        let files: Vec<String> = self
+            .replacements_files
+            .iter()
+            .map(|file| format!("{:?}", file))
+            .collect();
+        match files.len() {
+            1 => format!("{} as a replacements file", files[0]),
+            _ => format!("{} as replacements files", files.join(", ")),
+        }
+    }
+}
+
+/// What the mappings are called in logs and errors when they're read from
+/// stdin.
+const STDIN: &str = "<stdin>";
+
+/// What the mappings are called in logs and errors when they're given with
+/// --values-b64.
+const VALUES_B64: &str = "<--values-b64>";
 
-    let result = parse_input_files(&opts).and_then(render);
+/// Decodes and parses the mappings given with --values-b64. Whitespace is
+/// ignored, since tools like `base64` break their output into lines.
+fn decode_values(encoded: &str, opts: &Opts) -> Result<Vec<Mappings>, ProgramError> {
+    let encoded: String = encoded.split_whitespace().collect();
+    let decoded = base64::engine::general_purpose::STANDARD
+        .decode(encoded)
+        .map_err(|e| {
+            ProgramError::InvalidBase64(e.to_string().trim_end_matches('.').to_string())
+        })?;
+    let contents = String::from_utf8(decoded).map_err(|_| {
+        ProgramError::InvalidBase64(String::from("it doesn't decode to UTF-8 text"))
+    })?;
 
-    match result {
-        Ok(path) => {
-            info!("Successfully wrote file {:?}", path);
-            Ok(())
+    let source = PathBuf::from(VALUES_B64);
+    file_entries(parse_values(&contents, &source, opts)?, &source, opts)
+}
+
+/// Reads the mappings for `--stdin-json-values`. Templates can't be read from
+/// stdin at the same time.
+fn read_stdin_values(opts: &Opts) -> Result<Vec<Mappings>, ProgramError> {
+    if let Some(input) = opts
+        .input_files
+        .iter()
+        .find(|input| *input == "-" || *input == "/dev/stdin")
+    {
+        return Err(ProgramError::StdinUsedTwice(input.clone()));
+    }
+
+    let source = PathBuf::from(STDIN);
+    let value = serde_json::from_reader::<_, serde_json::Value>(io::stdin().lock())
+        .ok()
+        .filter(serde_json::Value::is_object)
+        .and_then(|value| serde_yaml::to_value(value).ok())
+        .ok_or_else(|| ProgramError::ReadFailed(source.clone()))?;
+    file_entries(value, &source, opts)
+}
+
+/// Reads the files given with --values-override-file, in order.
+fn load_overrides(opts: &Opts) -> Result<Vec<Mappings>, ProgramError> {
+    opts.values_override_files
+        .iter()
+        .map(|path| {
+            let overrides = values::into_mappings(read_yaml(path)?, path)?;
+            debug!(
+                "Loaded the keys {:?} from the override file {:?}.",
+                overrides.keys().collect::<Vec<_>>(),
+                path
+            );
+            Ok(overrides)
+        })
+        .collect()
+}
+
+/// Reads the list of keys in the file given with --required.
+fn load_required_keys(opts: &Opts) -> Result<Vec<String>, ProgramError> {
+    let path = match &opts.required {
+        Some(path) => path,
+        None => return Ok(Vec::new()),
+    };
+    match read_yaml(path)? {
+        serde_yaml::Value::Null => Ok(Vec::new()),
+        value => serde_yaml::from_value(value).map_err(|_| ProgramError::ReadFailed(path.clone())),
+    }
+}
+
+/// Fails if `mappings` is missing any of the keys from --required, naming
+/// all of them.
+fn check_required_keys(
+    required: &[String],
+    mappings: &Mappings,
+    opts: &Opts,
+) -> Result<(), ProgramError> {
+    let missing: Vec<&str> = required
+        .iter()
+        .filter(|key| !comments::has_key(mappings, key))
+        .map(String::as_str)
+        .collect();
+    match (&opts.required, missing.is_empty()) {
+        (Some(path), false) => Err(ProgramError::MissingKey(format!(
+            "The mappings are missing keys that {:?} requires: {}.",
+            path,
+            missing.join(", ")
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Reads the files given with --namespace, keyed by their namespaces.
+fn load_namespaces(opts: &Opts) -> Result<Vec<(String, JsonValue)>, ProgramError> {
+    let mut namespaces: Vec<(String, JsonValue)> = Vec::new();
+    for Namespace { name, path } in &opts.namespaces {
+        if namespaces.iter().any(|(known, _)| known == name) {
+            return Err(ProgramError::DuplicateNamespace(name.clone()));
         }
+        let values = values::into_mappings(read_yaml(path)?, path)?;
+        namespaces.push((
+            name.clone(),
+            JsonValue::Object(values.into_iter().collect()),
+        ));
+    }
+
+    Ok(namespaces)
+}
+
+/// Turns the contents of a replacements file into its entries: one set of
+/// mappings, or one per list item with --multi.
+fn file_entries(
+    mut value: serde_yaml::Value,
+    replacements_file: &PathBuf,
+    opts: &Opts,
+) -> Result<Vec<Mappings>, ProgramError> {
+    if let Some(key) = &opts.values_key {
+        value = values::select(value, key, replacements_file)?;
+    }
+    let entries = if opts.multi {
+        match value {
+            serde_yaml::Value::Sequence(entries) => entries,
+            serde_yaml::Value::Null => Vec::new(),
+            _ => return Err(ProgramError::ReadFailed(replacements_file.clone())),
+        }
+    } else {
+        vec![value]
+    };
+
+    entries
+        .into_iter()
+        .map(|entry| {
+            let mappings = values::into_mappings(entry, replacements_file)?;
+            debug!(
+                "Loaded the keys {:?} from {:?}.",
+                mappings.keys().collect::<Vec<_>>(),
+                replacements_file
+            );
+            Ok(mappings)
+        })
+        .collect()
+}
+
+/// The placeholder in the paths given with -i that --profile fills in.
+const PROFILE_PLACEHOLDER: &str = "{profile}";
+
+/// The paths given with -i, with the profile filled in.
+fn resolve_replacements_files(opts: &Opts) -> Result<Vec<PathBuf>, ProgramError> {
+    opts.replacements_files
+        .iter()
+        .map(|file| {
+            let path = file.to_string_lossy();
+            if !path.contains(PROFILE_PLACEHOLDER) {
+                return Ok(file.clone());
+            }
+
+            let profile = opts
+                .profile
+                .as_ref()
+                .ok_or_else(|| ProgramError::MissingProfile(file.clone()))?;
+            let resolved = PathBuf::from(path.replace(PROFILE_PLACEHOLDER, profile));
+            // With --each-dir, the path is looked for in every subdirectory.
+            if opts.each_dir.is_none() && !resolved.is_file() {
+                return Err(ProgramError::ProfileFileNotFound(resolved, profile.clone()));
+            }
+            debug!("Using {:?} for the profile {:?}.", resolved, profile);
+            Ok(resolved)
+        })
+        .collect()
+}
+
+/// Parses a `-D key=value` definition.
+fn parse_definition(definition: &str) -> Result<(String, String), String> {
+    match definition.find('=') {
+        Some(i) if i > 0 => Ok((definition[..i].to_string(), definition[i + 1..].to_string())),
+        _ => Err(format!("Expected key=value, not {:?}.", definition)),
+    }
+}
+
+/// The mappings given with --from-env, --values-from-command, and -D, in that
+/// order. The commands are run here, so this should only be called once.
+fn command_line_layers(opts: &Opts) -> Result<Vec<Mappings>, ProgramError> {
+    let environment: Mappings = match &opts.from_env {
+        Some(prefix) => env::vars()
+            .filter_map(|(name, value)| {
+                name.strip_prefix(prefix.as_str())
+                    .filter(|key| !key.is_empty())
+                    .map(|key| (key.to_lowercase(), JsonValue::String(value)))
+            })
+            .collect(),
+        None => Mappings::new(),
+    };
+    if opts.from_env.is_some() {
+        debug!(
+            "Loaded the keys {:?} from the environment.",
+            environment.keys().collect::<Vec<_>>()
+        );
+    }
+
+    let outputs: Mappings = opts
+        .values_from_command
+        .iter()
+        .map(ValueCommand::run)
+        .collect::<Result<_, _>>()?;
+
+    let definitions: Mappings = opts
+        .definitions
+        .iter()
+        .map(|(key, value)| (key.clone(), JsonValue::String(value.clone())))
+        .collect();
+    if !definitions.is_empty() {
+        debug!(
+            "Loaded the keys {:?} from -D.",
+            definitions.keys().collect::<Vec<_>>()
+        );
+    }
+
+    Ok(vec![environment, outputs, definitions])
+}
+
+/// The mappings that apply to every target on top of its own: the override
+/// files, the mappings given on the command line, and the namespaces. They're
+/// read once, before any target is loaded.
+#[derive(Clone)]
+struct SharedMappings {
+    overrides: Vec<Mappings>,
+    layers: Vec<Mappings>,
+    namespaces: Vec<(String, JsonValue)>,
+}
+
+impl SharedMappings {
+    fn load(opts: &Opts) -> Result<SharedMappings, ProgramError> {
+        Ok(SharedMappings {
+            overrides: load_overrides(opts)?,
+            layers: command_line_layers(opts)?,
+            namespaces: load_namespaces(opts)?,
+        })
+    }
+}
+
+/// Merges the mappings from every source into the sets to render with. From
+/// the lowest precedence to the highest, the sources are: the config file's
+/// `defaults`, the replacements files in the order they were given, the
+/// template's own mappings, the override files, and the command line
+/// layers. The replacements files are combined with --values-merge-strategy:
+/// later files replace whole top-level keys, merge nested mappings key by
+/// key, or, with "strict", must agree on every value they share. Override
+/// files are always merged in deeply; every other source replaces whole
+/// top-level keys.
+///
+/// Without --multi, every file has a single entry, and they're merged. With
+/// it, the entries of all files are rendered one after the other, without
+/// being merged with each other, and the other sources apply to each of
+/// them.
+fn merge_mapping_sets(
+    defaults: &Mappings,
+    sources: &[PathBuf],
+    files: Vec<Vec<Mappings>>,
+    template_layers: &[Mappings],
+    shared: &SharedMappings,
+    opts: &Opts,
+) -> Result<Vec<Mappings>, ProgramError> {
+    let entries: Vec<Mappings> = if opts.multi {
+        files.into_iter().flatten().collect()
+    } else {
+        let sources = sources
+            .iter()
+            .map(PathBuf::as_path)
+            .zip(files)
+            .flat_map(|(source, entries)| entries.into_iter().map(move |entry| (source, entry)))
+            .collect();
+        vec![values::merge_sources(sources, opts.merge_strategy)?]
+    };
+
+    entries
+        .into_iter()
+        .map(|entry| {
+            let mut mappings = defaults.clone();
+            mappings.extend(entry);
+            for layer in template_layers {
+                mappings.extend(layer.clone());
+            }
+            for overrides in &shared.overrides {
+                values::deep_merge(&mut mappings, overrides.clone());
+            }
+            // The command line only sets flat keys, which may override
+            // flattened ones.
+            if opts.values_flatten {
+                let separator = opts.flatten_separator.as_deref().unwrap_or("_");
+                mappings = values::flatten(mappings, separator)?;
+            }
+            for layer in &shared.layers {
+                mappings.extend(layer.clone());
+            }
+
+            if let Some(null_value) = &opts.null_value {
+                mappings.retain(|_, value| value.as_str() != Some(null_value));
+            }
+            values::coerce(&mut mappings, &opts.types)?;
+            values::add_namespaces(&mut mappings, &shared.namespaces)?;
+            Ok(mappings)
+        })
+        .collect()
+}
+
+fn load_entries(
+    replacements_files: &[PathBuf],
+    opts: &Opts,
+) -> Result<Vec<Vec<Mappings>>, ProgramError> {
+    replacements_files
+        .iter()
+        .map(|file| file_entries(read_values_file(file, opts)?, file, opts))
+        .collect()
+}
+
+/// Splits a template into its front matter, as mappings, and its body when
+/// --single-file is given.
+fn split_front_matter<'t>(
+    template: &'t str,
+    path: &Path,
+    opts: &Opts,
+) -> Result<(Option<Mappings>, &'t str), ProgramError> {
+    if !opts.single_file {
+        return Ok((None, template));
+    }
+
+    let (front_matter, body) = front_matter::split(template, path)?;
+    let front_matter = match front_matter {
+        Some(yaml) => {
+            let mappings = values::into_mappings(parse_yaml(yaml, path)?, path)?;
+            debug!(
+                "Loaded the keys {:?} from the front matter of {:?}.",
+                mappings.keys().collect::<Vec<_>>(),
+                path
+            );
+            Some(mappings)
+        }
+        None => None,
+    };
+    Ok((front_matter, body))
+}
+
+/// Reads the vars file next to the template at `path`, if there is one.
+fn load_vars_file(path: &Path) -> Result<Option<Mappings>, ProgramError> {
+    let vars_file = inputs::vars_file(path);
+    if !vars_file.is_file() {
+        return Ok(None);
+    }
+    let vars = values::into_mappings(read_yaml(&vars_file)?, &vars_file)?;
+    debug!(
+        "Loaded the keys {:?} from the vars file {:?}.",
+        vars.keys().collect::<Vec<_>>(),
+        vars_file
+    );
+    Ok(Some(vars))
+}
+
+/// Logs which of the shared mappings of `target` the vars file of the
+/// template at `path` overrides.
+fn log_vars_overrides(vars: &Mappings, path: &Path, target: &Target) {
+    let overridden: BTreeSet<&String> = target
+        .mapping_sets
+        .iter()
+        .flat_map(|mappings| {
+            vars.iter()
+                .filter(move |(key, value)| mappings.get(*key).is_some_and(|old| old != *value))
+                .map(|(key, _)| key)
+        })
+        .collect();
+    if !overridden.is_empty() {
+        info!(
+            "{:?} overrides {:?} from the shared mappings.",
+            inputs::vars_file(path),
+            overridden
+        );
+    }
+}
+
+/// The config file's `defaults`, as mappings.
+fn load_defaults(config: &Config, config_file: &Path) -> Result<Mappings, ProgramError> {
+    let defaults = values::into_mappings(config.defaults.clone(), config_file)?;
+    if !defaults.is_empty() {
+        debug!(
+            "Loaded the keys {:?} from the defaults in {:?}.",
+            defaults.keys().collect::<Vec<_>>(),
+            config_file
+        );
+    }
+    Ok(defaults)
+}
+
+/// The YAML files in --values-dir, sorted by name. It's an error if there
+/// aren't any.
+fn values_dir_files(dir: &Path) -> Result<Vec<PathBuf>, ProgramError> {
+    let mut files = fs::read_dir(dir)
+        .map_err(|_| ProgramError::FileNotFound(dir.to_path_buf()))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.is_file()
+                && matches!(
+                    path.extension().and_then(OsStr::to_str),
+                    Some("yaml") | Some("yml")
+                )
+        })
+        .collect::<Vec<_>>();
+    if files.is_empty() {
+        return Err(ProgramError::NoValuesFiles(dir.to_path_buf()));
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// A target per YAML file in --values-dir, in the order of their names, each
+/// with the config file's output directory and defaults.
+fn values_dir_targets(
+    config: &Config,
+    defaults: &Mappings,
+    shared: &SharedMappings,
+    opts: &Opts,
+    engine: &dyn Engine,
+) -> Result<Vec<Target>, ProgramError> {
+    let files = match &opts.values_dir {
+        Some(dir) => values_dir_files(dir)?,
+        None => return Ok(Vec::new()),
+    };
+
+    let mut targets = Vec::new();
+    for file in files {
+        let replacements_files = vec![file];
+        let entries = load_entries(&replacements_files, opts)?;
+        let output_stem = replacements_files[0].file_stem().map(OsStr::to_os_string);
+        let mut target = Target::new(
+            replacements_files,
+            defaults.clone(),
+            entries,
+            shared,
+            config.clone(),
+            opts,
+            engine,
+        )?;
+        target.output_stem = output_stem;
+        targets.push(target);
+    }
+    Ok(targets)
+}
+
+fn each_dir_targets(
+    parent: &Path,
+    file_names: &[PathBuf],
+    shared: &SharedMappings,
+    opts: &Opts,
+    engine: &dyn Engine,
+) -> Result<Vec<Target>, ProgramError> {
+    let mut dirs = fs::read_dir(parent)
+        .map_err(|_| ProgramError::FileNotFound(parent.to_path_buf()))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_dir())
+        .collect::<Vec<_>>();
+    dirs.sort();
+
+    let mut targets = Vec::new();
+    for dir in dirs {
+        let replacements_files: Vec<PathBuf> =
+            file_names.iter().map(|name| dir.join(name)).collect();
+        if let Some(missing) = file_names
+            .iter()
+            .zip(&replacements_files)
+            .find(|(_, file)| !file.is_file())
+            .map(|(name, _)| name)
+        {
+            warn!(
+                "Skipping {:?} because it doesn't contain {:?}.",
+                &dir, missing
+            );
+            continue;
+        }
+
+        let entries = load_entries(&replacements_files, opts)?;
+        let config = Config {
+            output_dir: dir,
+            filename_transform: None,
+            defaults: serde_yaml::Value::Null,
+            computed: BTreeMap::new(),
+        };
+        targets.push(Target::new(
+            replacements_files,
+            Mappings::new(),
+            entries,
+            shared,
+            config,
+            opts,
+            engine,
+        )?);
+    }
+
+    Ok(targets)
+}
+
+/// The fully resolved configuration of a run, for `--echo-config`.
+#[derive(Serialize)]
+struct EchoedConfig<'a> {
+    working_directory: &'a Path,
+    targets: Vec<EchoedTarget<'a>>,
+    outputs: Vec<EchoedOutput>,
+}
+
+#[derive(Serialize)]
+struct EchoedTarget<'a> {
+    replacements_files: &'a [PathBuf],
+    #[serde(flatten)]
+    config: &'a Config,
+}
+
+#[derive(Serialize)]
+struct EchoedOutput {
+    template: PathBuf,
+    output_file: PathBuf,
+}
+
+fn echo_config(root: &Path, targets: &[Target], outputs: Vec<EchoedOutput>) {
+    let echoed = EchoedConfig {
+        working_directory: root,
+        targets: targets
+            .iter()
+            .map(|target| EchoedTarget {
+                replacements_files: &target.replacements_files,
+                config: &target.config,
+            })
+            .collect(),
+        outputs,
+    };
+    match serde_yaml::to_string(&echoed) {
+        Ok(yaml) => eprintln!("{}", yaml),
+        Err(e) => warn!("Couldn't print the configuration: {}", e),
+    }
+}
+
+/// Prints every merged mapping set, with the values of redacted keys hidden.
+fn echo_values(targets: &[Target], redaction: &Redaction) {
+    for target in targets {
+        for mappings in &target.mapping_sets {
+            let mut shown = mappings.clone();
+            for (key, value) in shown.iter_mut() {
+                if let Some(mask) = redaction.mask(key) {
+                    *value = JsonValue::String(mask.to_string());
+                }
+            }
+            match serde_yaml::to_string(&shown) {
+                Ok(yaml) => eprintln!("{}", yaml),
+                Err(e) => warn!("Couldn't print the mappings: {}", e),
+            }
+        }
+    }
+}
+
+/// Prints the number of templates, the keys they substitute, and the keys
+/// that are missing from the mappings of at least one of them.
+fn count(configurations: &[Configuration], engine: &dyn Engine) -> Result<(), ProgramError> {
+    let mut templates = BTreeSet::new();
+    let mut referenced = BTreeSet::new();
+    let mut missing = BTreeSet::new();
+    for configuration in configurations.iter().flat_map(Configuration::parts) {
+        templates.insert(&configuration.template_path);
+        for key in referenced_keys(configuration, engine)? {
+            if !comments::has_key(&configuration.mappings, &key) {
+                missing.insert(key.clone());
+            }
+            referenced.insert(key);
+        }
+    }
+
+    println!("templates: {}", templates.len());
+    println!("referenced keys: {}", referenced.len());
+    match missing.len() {
+        0 => println!("missing keys: 0"),
+        n => println!(
+            "missing keys: {} ({})",
+            n,
+            missing.into_iter().collect::<Vec<_>>().join(", ")
+        ),
+    }
+    Ok(())
+}
+
+/// Picks the first of the config files given with -c that exists.
+fn find_config_file(candidates: &[PathBuf]) -> Result<&PathBuf, ProgramError> {
+    match candidates.iter().find(|candidate| candidate.is_file()) {
+        Some(config_file) => {
+            if candidates.len() > 1 {
+                info!("Using the config file {:?}.", config_file);
+            }
+            Ok(config_file)
+        }
+        None if candidates.len() == 1 => Err(ProgramError::FileNotFound(candidates[0].clone())),
+        None => Err(ProgramError::NoConfigFile(candidates.to_vec())),
+    }
+}
+
+/// The templates given with -f, looked up in --template-dir if it's given.
+/// With --input-base, the patterns are resolved in that directory. The
+/// ignore file is read from the input base or the template dir, or, if
+/// neither is given, from the base of each pattern.
+fn expand_input_files(opts: &Opts) -> Result<Vec<InputFile>, ProgramError> {
+    if let Some(base) = &opts.input_base {
+        if !base.is_dir() {
+            return Err(ProgramError::FileNotFound(base.clone()));
+        }
+        let patterns: Vec<String> = opts
+            .input_files
+            .iter()
+            .map(|pattern| base.join(pattern).to_string_lossy().into_owned())
+            .collect();
+        return inputs::expand(&patterns, Some(base), Some(base), opts.max_depth);
+    }
+
+    let patterns = match &opts.template_dir {
+        Some(dir) => {
+            let extensions = template_extensions(opts);
+            opts.input_files
+                .iter()
+                .map(|pattern| inputs::resolve_in(pattern, dir, &extensions))
+                .collect::<Result<_, _>>()?
+        }
+        None => opts.input_files.clone(),
+    };
+    inputs::expand(
+        &patterns,
+        opts.template_dir.as_deref(),
+        None,
+        opts.max_depth,
+    )
+}
+
+fn parse_input_files(opts: &Opts, engine: &dyn Engine) -> Result<Vec<Configuration>, ProgramError> {
+    let shared = SharedMappings::load(opts)?;
+    let required = load_required_keys(opts)?;
+    let targets = match (&opts.each_dir, &opts.config_files[..]) {
+        (Some(parent), _) => each_dir_targets(
+            parent,
+            &resolve_replacements_files(opts)?,
+            &shared,
+            opts,
+            engine,
+        )?,
+        (None, [_, ..]) if opts.values_dir.is_some() => {
+            let config_file = find_config_file(&opts.config_files)?;
+            let config = expand_env(deserialize(config_file)?, opts)?;
+            let defaults = load_defaults(&config, config_file)?;
+            values_dir_targets(&config, &defaults, &shared, opts, engine)?
+        }
+        (None, [_, ..]) => {
+            let config_file = find_config_file(&opts.config_files)?;
+            let config = expand_env(deserialize(config_file)?, opts)?;
+            let defaults = load_defaults(&config, config_file)?;
+            let (replacements_files, entries) = if opts.stdin_json_values {
+                (vec![PathBuf::from(STDIN)], vec![read_stdin_values(opts)?])
+            } else if let (Some(database), Some(query)) = (&opts.values_sqlite, &opts.values_query)
+            {
+                let values = sqlite::read_values(database, query)?;
+                (vec![database.clone()], vec![vec![values]])
+            } else if let Some(url) = &opts.values_url {
+                (vec![PathBuf::from(url)], vec![fetch_values(url, opts)?])
+            } else if let Some(encoded) = &opts.values_b64 {
+                (
+                    vec![PathBuf::from(VALUES_B64)],
+                    vec![decode_values(encoded, opts)?],
+                )
+            } else {
+                let files = resolve_replacements_files(opts)?;
+                let entries = load_entries(&files, opts)?;
+                (files, entries)
+            };
+            vec![Target::new(
+                replacements_files,
+                defaults,
+                entries,
+                &shared,
+                config,
+                opts,
+                engine,
+            )?]
+        }
+        _ => unreachable!(
+            "The config file is required unless --each-dir is given, and -i is required unless \
+             --stdin-json-values, --values-sqlite, --values-url, or --values-b64 (which \
+             conflict with --each-dir) is given."
+        ),
+    };
+    if opts.echo_values {
+        echo_values(&targets, &redaction(opts));
+    }
+
+    let mut configurations = Vec::new();
+    let mut outputs = Vec::new();
+    for input in expand_input_files(opts)? {
+        let contents = read_template(&input.path)?;
+        let (front_matter, template) = split_front_matter(&contents, &input.path, opts)?;
+        let vars = load_vars_file(&input.path)?;
+        let template_layers: Vec<Mappings> = vars.iter().chain(&front_matter).cloned().collect();
+        for target in &targets {
+            if let Some(vars) = &vars {
+                log_vars_overrides(vars, &input.path, target);
+            }
+            let mapping_sets = if template_layers.is_empty() {
+                Cow::Borrowed(&target.mapping_sets)
+            } else {
+                Cow::Owned(target.for_template(&template_layers, opts, engine)?)
+            };
+            for mappings in mapping_sets.iter() {
+                check_required_keys(&required, mappings, opts)?;
+                let configuration = configure(&input, template, mappings, target, opts, engine)?;
+                outputs.push(EchoedOutput {
+                    template: input.path.clone(),
+                    output_file: configuration.output_file.clone(),
+                });
+                configurations.push(configuration);
+            }
+        }
+    }
+
+    if opts.echo_config {
+        let root = env::current_dir().unwrap_or_else(|_| PathBuf::from("."));
+        echo_config(&root, &targets, outputs);
+    }
+
+    let configurations = if opts.concat {
+        concatenate(configurations, opts.separator.as_deref().unwrap_or(""))
+    } else {
+        configurations
+    };
+    check_output_collisions(&configurations)?;
+    if opts.multi {
+        check_unique_outputs(&configurations)?;
+    }
+
+    if let Some(root) = &opts.root {
+        let root = Root::new(root)?;
+        for configuration in &configurations {
+            root.check(&configuration.output_file)?;
+        }
+    }
+
+    Ok(configurations)
+}
+
+/// The keys whose values are hidden in the logs, going by --redact and
+/// --redact-log.
+fn redaction(opts: &Opts) -> Redaction {
+    Redaction::new(&opts.redact, opts.redact_log, &opts.redact_patterns)
+}
+
+fn engine_options(opts: &Opts, helper_usage: Option<Usage>) -> EngineOptions {
+    EngineOptions {
+        allowed_helpers: opts
+            .allow_helpers
+            .as_ref()
+            .map(|names| names.iter().cloned().collect()),
+        trace_render: opts.trace_render,
+        redact: redaction(opts),
+        on_missing: opts.on_missing,
+        helpers_dir: opts.helpers_dir.clone(),
+        helper_usage,
+        seed: opts.seed,
+        file_root: opts.file_root.clone(),
+        strip_comments: opts.strip_comments,
+        keep_comments: opts.keep_comments,
+        profile: opts.profile.clone(),
+        locale: opts.locale,
+        safe: opts.safe,
+    }
+}
+
+/// Lists the paths that `name` is looked for at for --print-template-path,
+/// on stderr, and prints the one it resolves to on stdout.
+fn print_template_path(name: &str, opts: &Opts) -> Result<(), ProgramError> {
+    let extensions = template_extensions(opts);
+    let (dir, mut candidates) = match &opts.template_dir {
+        Some(dir) => (dir.as_path(), inputs::candidates(name, dir, &extensions)),
+        None => {
+            let dir = opts.input_base.as_deref().unwrap_or_else(|| Path::new("."));
+            (dir, vec![dir.join(name)])
+        }
+    };
+
+    eprintln!("Looked for {:?} in {:?}:", name, dir);
+    for candidate in &candidates {
+        let found = if candidate.is_file() {
+            "found"
+        } else {
+            "not found"
+        };
+        eprintln!("  {} ({})", candidate.display(), found);
+    }
+
+    let resolved = match &opts.template_dir {
+        Some(dir) => PathBuf::from(inputs::resolve_in(name, dir, &extensions)?),
+        None => candidates.remove(0),
+    };
+    if !resolved.is_file() {
+        return Err(ProgramError::FileNotFound(resolved));
+    }
+    println!("{}", resolved.display());
+    Ok(())
+}
+
+/// Prints the parsed form of every template for --dump-ast. Front matter is
+/// left out, like it is when rendering, but no mappings are read.
+fn dump_ast(opts: &Opts, engine: &dyn Engine) -> Result<(), ProgramError> {
+    for input in expand_input_files(opts)? {
+        let contents = read_template(&input.path)?;
+        let (_, template) = split_front_matter(&contents, &input.path, opts)?;
+        let tree = engine.syntax_tree(template)?;
+        eprintln!("==> {} (AST) <==", input.path.display());
+        eprintln!("{}", tree);
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    if env::args().nth(1).as_deref() == Some("man") {
+        return match man::print(Opts::clap()) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(_) => ExitCode::FAILURE,
+        };
+    }
+
+    if env::args().nth(1).as_deref() == Some("doctor") {
+        let args = env::args().enumerate().filter(|(i, _)| *i != 1);
+        let opts = Opts::from_iter(args.map(|(_, arg)| arg));
+        progress::init_logging("error", false, &ProgressBar::hidden());
+        return match doctor::run(&opts) {
+            true => ExitCode::SUCCESS,
+            false => ExitCode::FAILURE,
+        };
+    }
+
+    let opts = Opts::from_args();
+    let progress = ProgressBar::hidden();
+    progress::init_logging(
+        if opts.quiet { "error" } else { "info" },
+        opts.trace_render,
+        &progress,
+    );
+
+    let helper_usage = if opts.report_unused_helpers {
+        Some(Usage::default())
+    } else {
+        None
+    };
+    let engine_options = engine_options(&opts, helper_usage.clone());
+    let owner = match opts.owner.as_deref().map(Owner::parse).transpose() {
+        Ok(owner) => owner,
         Err(e) => {
             error!("Encountered an error during execution: {}", e);
-            Err(())
+            return ExitCode::FAILURE;
+        }
+    };
+    let write_options = WriteOptions {
+        mode: opts.chmod,
+        owner,
+        lock_timeout: if opts.parallel_safe {
+            Some(Duration::from_secs(opts.lock_timeout))
+        } else {
+            None
+        },
+        write_retries: opts.write_retries,
+        retry_delay: Duration::from_millis(opts.retry_delay),
+        encoding: opts.output_encoding,
+        lossy: opts.lossy,
+        cache: opts.cache_dir.clone().map(Cache::new),
+        only_if_changed: opts.only_if_changed,
+        post_hook: opts.post_hook.clone(),
+        post_hook_strict: opts.post_hook_strict,
+    };
+
+    #[cfg(not(unix))]
+    {
+        if opts.chmod.is_some() {
+            warn!("--chmod is only supported on Unix; ignoring it.");
+        }
+    }
+
+    let mut summary = Summary::start(opts.fail_fast_threshold);
+    let mut total = 0;
+    let mut changed_files = 0;
+    let mut failed_files = 0;
+    let result = opts.engine.build(engine_options).and_then(|engine| {
+        if let Some(name) = &opts.print_template_path {
+            return print_template_path(name, &opts);
+        }
+        if opts.dump_ast {
+            return dump_ast(&opts, engine.as_ref());
+        }
+        let configurations = parse_input_files(&opts, engine.as_ref())?;
+        if opts.count_only {
+            return count(&configurations, engine.as_ref());
+        }
+        if (opts.echo_config || opts.echo_values) && opts.dry_run {
+            return Ok(());
+        }
+        total = configurations.len();
+        if opts.progress && !opts.quiet {
+            progress::show(&progress, total);
+        }
+
+        let mut zip = opts.zip.as_deref().map(Archive::new);
+        let uploader = opts.output_s3.as_ref().map(Uploader::new).transpose()?;
+        let committer = opts
+            .commit
+            .as_deref()
+            .map(|message| Committer::new(message, opts.commit_author.as_ref()))
+            .transpose()?;
+        let mut written = Vec::new();
+        let mut dumped = HashSet::new();
+        let mut transaction = if opts.transactional {
+            Some(Transaction::default())
+        } else {
+            None
+        };
+        for (index, config) in configurations.into_iter().enumerate() {
+            // A template can be rendered more than once, but it's the same
+            // every time.
+            if opts.dump_preprocessed {
+                for part in config.parts() {
+                    if dumped.insert(part.template_path.clone()) {
+                        eprintln!("==> {} (preprocessed) <==", part.template_path.display());
+                        eprintln!("{}", engine.preprocess(&part.template));
+                    }
+                }
+            }
+            let outcome = if opts.diff {
+                diff(config, engine.as_ref(), opts.diff_against, &write_options)
+            } else if opts.verify {
+                verify(config, engine.as_ref(), &write_options)
+            } else if opts.dry_run {
+                preview(config, engine.as_ref(), opts.show_output)
+            } else if let Some(zip) = &mut zip {
+                archive(config, engine.as_ref(), &write_options, zip)
+            } else if let Some(uploader) = &uploader {
+                upload(config, engine.as_ref(), &write_options, uploader)
+            } else if let Some(transaction) = &mut transaction {
+                stage(config, engine.as_ref(), &write_options, transaction)
+            } else {
+                render(config, engine.as_ref(), &write_options)
+            };
+            progress.inc(1);
+
+            let outcome = match outcome {
+                Ok(outcome) => outcome,
+                Err(e) if opts.keep_going => {
+                    error!("Encountered an error during execution: {}", e);
+                    summary.record_error(&e, true);
+                    if summary.reached_threshold() {
+                        let skipped = total - index - 1;
+                        error!(
+                            "Stopping because {} templates have failed; skipping the remaining {}",
+                            summary.failed(),
+                            skipped
+                        );
+                        summary.skip(skipped);
+                        break;
+                    }
+                    continue;
+                }
+                Err(e) => {
+                    summary.record_error(&e, true);
+                    summary.skip(total - index - 1);
+                    return Err(e);
+                }
+            };
+
+            summary.record(&outcome);
+            match outcome {
+                Outcome::Written { output_file, .. } => {
+                    info!("Successfully wrote file {:?}", output_file);
+                    written.push(output_file);
+                }
+                Outcome::Previewed(path) => info!("Would write file {:?}", path),
+                Outcome::Archived { name, .. } => info!("Added {:?} to the archive", name),
+                Outcome::Uploaded { key, .. } => info!("Uploaded {:?}", key),
+                Outcome::Staged { output_file, .. } => debug!("Staged {:?}", output_file),
+                Outcome::Unchanged(path) => {
+                    debug!("{:?} hasn't changed; skipping it", path);
+                    // It may still differ from what's committed.
+                    written.push(path);
+                }
+                Outcome::Compared {
+                    output_file,
+                    changed,
+                } => {
+                    if changed {
+                        changed_files += 1;
+                    } else {
+                        info!("{:?} is up to date", output_file);
+                    }
+                }
+                Outcome::Verified {
+                    output_file,
+                    status,
+                } => match status {
+                    Status::UpToDate => info!("{:?} is up to date", output_file),
+                    Status::Stale => {
+                        failed_files += 1;
+                        println!("stale: {}", output_file.display());
+                    }
+                    Status::Missing => {
+                        failed_files += 1;
+                        println!("missing: {}", output_file.display());
+                    }
+                },
+            }
         }
+
+        match zip {
+            Some(zip) if summary.has_failures() => {
+                warn!(
+                    "Not writing {:?}, because not every template rendered",
+                    zip.path()
+                );
+            }
+            Some(zip) => {
+                let path = zip.path().to_path_buf();
+                let entries = zip.finish()?;
+                info!("Wrote {} file(s) to the archive {:?}", entries, path);
+            }
+            None => {}
+        }
+
+        match transaction {
+            Some(_) if summary.has_failures() => {
+                warn!("Not writing any output files, because not every template rendered")
+            }
+            Some(transaction) => {
+                for output_file in transaction.commit(&write_options)? {
+                    info!("Successfully wrote file {:?}", output_file);
+                    written.push(output_file);
+                }
+            }
+            None => {}
+        }
+
+        match committer {
+            Some(_) if summary.has_failures() => {
+                warn!("Not committing the output files, because not every template rendered")
+            }
+            Some(committer) => match committer.commit(&written)? {
+                Some(id) => info!("Committed {} output file(s) as {}", written.len(), id),
+                None => info!("The output files haven't changed; not committing them"),
+            },
+            None => {}
+        }
+
+        Ok(())
+    });
+    progress.finish_and_clear();
+
+    if let Some(usage) = &helper_usage {
+        usage.report();
+    }
+
+    if summary.unchanged() > 0 {
+        info!(
+            "Skipped {} output file(s) that were already up to date",
+            summary.unchanged()
+        );
+    }
+
+    let mut exit_code = ExitCode::SUCCESS;
+    if let Err(e) = result {
+        error!("Encountered an error during execution: {}", e);
+        if !summary.has_failures() {
+            summary.record_error(&e, false);
+        }
+        exit_code = ExitCode::FAILURE;
+    } else if summary.has_failures() {
+        exit_code = ExitCode::FAILURE;
+    } else if opts.verify {
+        if failed_files > 0 {
+            println!(
+                "{} of {} output file(s) are missing or stale",
+                failed_files, total
+            );
+            exit_code = ExitCode::FAILURE;
+        } else {
+            info!("All {} output file(s) are up to date", total);
+        }
+    } else if opts.exit_code && changed_files > 0 {
+        info!("{} output file(s) would change", changed_files);
+        exit_code = ExitCode::FAILURE;
+    }
+
+    if let Some(destination) = &opts.summary_json {
+        if let Err(e) = summary.write(destination) {
+            error!("Couldn't write the run summary: {}", e);
+            exit_code = ExitCode::FAILURE;
+        }
+    }
+
+    exit_code
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mappings(pairs: &[(&str, &str)]) -> Mappings {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), JsonValue::String(value.to_string())))
+            .collect()
+    }
+
+    fn opts(args: &[&str]) -> Opts {
+        let required = ["replacer", "-c", "config.yml", "-f", "template.hbs"];
+        Opts::from_iter(required.iter().chain(args))
+    }
+
+    fn output_name(name: &str, args: &[&str]) -> PathBuf {
+        let mut args = args.to_vec();
+        args.extend(["-i", "values.yml"]);
+        strip_template_extension(PathBuf::from(name), &opts(&args))
+    }
+
+    #[test]
+    fn template_extensions_are_stripped_from_output_names() {
+        assert_eq!(output_name("nginx.conf.hbs", &[]), Path::new("nginx.conf"));
+        assert_eq!(
+            output_name("site/app.ini.j2", &[]),
+            Path::new("site/app.ini")
+        );
+        assert_eq!(
+            output_name("motd.tpl", &["--template-ext", ".tpl"]),
+            Path::new("motd")
+        );
+        assert_eq!(output_name(".hbs", &[]), Path::new(".hbs"));
+    }
+
+    #[test]
+    fn names_without_a_template_extension_are_kept_whole() {
+        for name in &["nginx.conf", "notes.hbs.txt", "page.xhbs", "Makefile"] {
+            assert_eq!(output_name(name, &[]), Path::new(name));
+        }
+        assert_eq!(
+            output_name("motd.tpl", &["--template-ext", ".tmp"]),
+            Path::new("motd.tpl")
+        );
+    }
+
+    #[test]
+    fn empty_values_files_have_no_mappings() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let path = file.path().to_path_buf();
+        let empty = read_yaml(&path).unwrap();
+        assert_eq!(
+            values::into_mappings(empty, &path).unwrap(),
+            Mappings::new()
+        );
+
+        for contents in ["{}", "---", "---\n", "# nothing here\n"] {
+            let value = parse_yaml(contents, &path).unwrap();
+            assert_eq!(
+                values::into_mappings(value, &path).unwrap(),
+                Mappings::new(),
+                "{:?}",
+                contents
+            );
+        }
+    }
+
+    #[test]
+    fn mapping_sources_take_precedence_in_order() {
+        env::set_var("REPLACER_PRECEDENCE_TEST_D", "environment");
+        env::set_var("REPLACER_PRECEDENCE_TEST_E", "environment");
+        let opts = opts(&[
+            "-i",
+            "first.yml",
+            "-i",
+            "second.yml",
+            "--from-env",
+            "REPLACER_PRECEDENCE_TEST_",
+            "-D",
+            "e=definition",
+        ]);
+        let defaults = mappings(&[
+            ("a", "defaults"),
+            ("b", "defaults"),
+            ("c", "defaults"),
+            ("d", "defaults"),
+            ("e", "defaults"),
+        ]);
+        let files = vec![
+            vec![mappings(&[
+                ("b", "first"),
+                ("c", "first"),
+                ("d", "first"),
+                ("e", "first"),
+            ])],
+            vec![mappings(&[
+                ("c", "second"),
+                ("d", "second"),
+                ("e", "second"),
+            ])],
+        ];
+
+        let shared = SharedMappings::load(&opts).unwrap();
+        let merged = merge_mapping_sets(
+            &defaults,
+            &opts.replacements_files,
+            files,
+            &[],
+            &shared,
+            &opts,
+        )
+        .unwrap();
+
+        assert_eq!(
+            merged,
+            vec![mappings(&[
+                ("a", "defaults"),
+                ("b", "first"),
+                ("c", "second"),
+                ("d", "environment"),
+                ("e", "definition"),
+            ])]
+        );
     }
 }
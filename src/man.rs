@@ -0,0 +1,81 @@
+use std::io::{self, Write};
+use structopt::clap::App;
+
+/// Escapes text for use in roff, so that backslashes, hyphens, and lines
+/// that start with a control character come out as written.
+fn escape(text: &str) -> String {
+    let escaped = text.replace('\\', "\\e").replace('-', "\\-");
+    if escaped.starts_with('.') || escaped.starts_with('\'') {
+        format!("\\&{}", escaped)
+    } else {
+        escaped
+    }
+}
+
+/// Formats an option heading such as `-f, --file <input-files>...` with the
+/// flags in bold and the value names in italics.
+fn heading(line: &str) -> String {
+    line.split_whitespace()
+        .map(|word| {
+            let (word, separator) = match word.strip_suffix(',') {
+                Some(word) => (word, ","),
+                None => (word, ""),
+            };
+            let font = if word.starts_with('<') { "I" } else { "B" };
+            format!("\\f{}{}\\fR{}", font, escape(word), separator)
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Turns the long help of `app` into a roff man page. The help text is
+/// where clap keeps everything about the options, so building on it keeps
+/// the page in line with the options without listing them again.
+fn render(app: App) -> io::Result<String> {
+    let name = app.get_name().to_string();
+    let mut help = Vec::new();
+    app.set_term_width(0)
+        .write_long_help(&mut help)
+        .map_err(|e| io::Error::other(e.to_string()))?;
+    let help = String::from_utf8_lossy(&help);
+
+    let mut page = format!(
+        ".TH {} 1\n.SH NAME\n{} \\- render Handlebars templates from a YAML mapping file\n",
+        name.to_uppercase(),
+        name
+    );
+    let mut section = None;
+    for line in help.lines() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            continue;
+        }
+
+        if !line.starts_with(' ') && trimmed.ends_with(':') {
+            // Flags and options are listed together.
+            let heading = match trimmed.trim_end_matches(':') {
+                "USAGE" => "SYNOPSIS",
+                "FLAGS" | "OPTIONS" => "OPTIONS",
+                other => other,
+            };
+            if section != Some(heading) {
+                page.push_str(&format!(".SH {}\n", heading));
+                section = Some(heading);
+            }
+        } else if section.is_none() {
+            // The name and version line, which the NAME section covers.
+        } else if trimmed.starts_with('-') && line.len() - line.trim_start().len() < 12 {
+            page.push_str(&format!(".TP\n{}\n", heading(trimmed)));
+        } else {
+            page.push_str(&format!("{}\n", escape(trimmed)));
+        }
+    }
+
+    Ok(page)
+}
+
+/// Writes a man page for `app` to stdout.
+pub(crate) fn print(app: App) -> io::Result<()> {
+    let page = render(app)?;
+    io::stdout().write_all(page.as_bytes())
+}
@@ -0,0 +1,80 @@
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+/// A naming convention that output file names are converted to, set with
+/// `filename_transform` in the config file.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum FilenameTransform {
+    /// `MyService.Conf` becomes `myservice.conf`.
+    Lowercase,
+    /// `MyService.Conf` becomes `my-service.conf`.
+    Kebab,
+    /// `MyService.Conf` becomes `my_service.conf`.
+    Snake,
+}
+
+/// Splits `part` into lowercase words at separators (anything that isn't a
+/// letter or digit) and at changes of case, so that `myHTTPServer` becomes
+/// `my`, `http`, and `server`.
+fn words(part: &str) -> Vec<String> {
+    let chars: Vec<char> = part.chars().collect();
+    let mut words = Vec::new();
+    let mut word = String::new();
+
+    for (i, &c) in chars.iter().enumerate() {
+        if !c.is_alphanumeric() {
+            if !word.is_empty() {
+                words.push(std::mem::take(&mut word));
+            }
+            continue;
+        }
+
+        let previous = i.checked_sub(1).map(|i| chars[i]);
+        let next = chars.get(i + 1);
+        let starts_word = c.is_uppercase()
+            && match previous {
+                Some(previous) if previous.is_lowercase() || previous.is_numeric() => true,
+                Some(previous) if previous.is_uppercase() => next.is_some_and(|n| n.is_lowercase()),
+                _ => false,
+            };
+        if starts_word && !word.is_empty() {
+            words.push(std::mem::take(&mut word));
+        }
+        word.extend(c.to_lowercase());
+    }
+
+    if !word.is_empty() {
+        words.push(word);
+    }
+    words
+}
+
+impl FilenameTransform {
+    fn apply_to_part(self, part: &str) -> String {
+        match self {
+            FilenameTransform::Lowercase => part.to_lowercase(),
+            FilenameTransform::Kebab => words(part).join("-"),
+            FilenameTransform::Snake => words(part).join("_"),
+        }
+    }
+
+    /// Converts the file name of `path`, leaving its directories alone. Each
+    /// part between dots is converted on its own, so extensions are kept.
+    pub(crate) fn apply(self, path: PathBuf) -> PathBuf {
+        let name = match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) => name,
+            None => return path,
+        };
+
+        let converted = name
+            .split('.')
+            .map(|part| self.apply_to_part(part))
+            .collect::<Vec<_>>()
+            .join(".");
+        if converted.trim_matches('.').is_empty() {
+            return path;
+        }
+        path.with_file_name(converted)
+    }
+}
@@ -0,0 +1,91 @@
+use std::path::Path;
+
+use crate::writer::ProgramError;
+
+/// The user and group that output files are handed over to once they've
+/// been written. Either part can be left out to keep it unchanged.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(not(all(unix, feature = "chown")), allow(dead_code))]
+pub(crate) struct Owner {
+    uid: Option<u32>,
+    gid: Option<u32>,
+}
+
+#[cfg(all(unix, feature = "chown"))]
+impl Owner {
+    /// Parses an owner given as `user:group`, `user`, or `:group`, where
+    /// users and groups are either names or numeric ids.
+    pub(crate) fn parse(spec: &str) -> Result<Owner, ProgramError> {
+        use nix::unistd::{Group, User};
+
+        let unknown = || ProgramError::UnknownOwner(spec.to_string());
+        let (user, group) = match spec.find(':') {
+            Some(i) => (&spec[..i], &spec[i + 1..]),
+            None => (spec, ""),
+        };
+
+        let uid = match user {
+            "" => None,
+            user => Some(match user.parse() {
+                Ok(uid) => uid,
+                Err(_) => User::from_name(user)
+                    .ok()
+                    .flatten()
+                    .ok_or_else(unknown)?
+                    .uid
+                    .as_raw(),
+            }),
+        };
+        let gid = match group {
+            "" => None,
+            group => Some(match group.parse() {
+                Ok(gid) => gid,
+                Err(_) => Group::from_name(group)
+                    .ok()
+                    .flatten()
+                    .ok_or_else(unknown)?
+                    .gid
+                    .as_raw(),
+            }),
+        };
+
+        if uid.is_none() && gid.is_none() {
+            return Err(unknown());
+        }
+
+        Ok(Owner { uid, gid })
+    }
+
+    pub(crate) fn apply(&self, path: &Path) -> Result<(), ProgramError> {
+        use nix::{
+            errno::Errno,
+            unistd::{chown, Gid, Uid},
+        };
+
+        chown(
+            path,
+            self.uid.map(Uid::from_raw),
+            self.gid.map(Gid::from_raw),
+        )
+        .map_err(|e| {
+            let reason = match e {
+                Errno::EPERM => String::from(
+                    "permission denied; changing ownership usually requires running as root",
+                ),
+                e => e.desc().to_string(),
+            };
+            ProgramError::CannotChangeOwner(path.to_path_buf(), reason)
+        })
+    }
+}
+
+#[cfg(not(all(unix, feature = "chown")))]
+impl Owner {
+    pub(crate) fn parse(_: &str) -> Result<Owner, ProgramError> {
+        Err(ProgramError::OwnershipUnsupported)
+    }
+
+    pub(crate) fn apply(&self, _: &Path) -> Result<(), ProgramError> {
+        Ok(())
+    }
+}
@@ -0,0 +1,127 @@
+use log::debug;
+use std::{
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use crate::writer::ProgramError;
+
+/// The kinds of archive that templates can be read from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Zip,
+    Tar,
+}
+
+/// A template inside an archive, given as `archive.zip:path/in/archive.hbs`
+/// (or `.tar:`) instead of the path of a file.
+#[derive(Debug)]
+pub(crate) struct Entry {
+    archive: PathBuf,
+    kind: Kind,
+    name: String,
+}
+
+impl Entry {
+    /// The entry that `path` points at, if it points into an archive.
+    pub(crate) fn parse(path: &Path) -> Option<Entry> {
+        let path = path.to_str()?;
+        [(".zip:", Kind::Zip), (".tar:", Kind::Tar)]
+            .iter()
+            .filter_map(|(separator, kind)| {
+                let at = path.find(separator)?;
+                Some((at + separator.len() - 1, *kind))
+            })
+            .min_by_key(|(at, _)| *at)
+            .map(|(at, kind)| Entry {
+                archive: PathBuf::from(&path[..at]),
+                kind,
+                name: path[at + 1..].trim_start_matches('/').to_string(),
+            })
+            .filter(|entry| !entry.name.is_empty())
+    }
+
+    /// The file name of the entry, without the directories it's in.
+    pub(crate) fn file_name(&self) -> &str {
+        self.name.rsplit('/').next().unwrap_or(&self.name)
+    }
+
+    /// Reads the entry out of its archive.
+    pub(crate) fn read(&self) -> Result<String, ProgramError> {
+        let file = File::open(&self.archive)
+            .map_err(|_| ProgramError::FileNotFound(self.archive.clone()))?;
+        let contents = match self.kind {
+            Kind::Zip => read_zip(self, file),
+            Kind::Tar => read_tar(self, file),
+        }?;
+        debug!("Read {:?} from the archive {:?}.", self.name, self.archive);
+        Ok(contents)
+    }
+
+    fn not_found(&self) -> ProgramError {
+        ProgramError::ArchiveEntryNotFound(self.archive.clone(), self.name.clone())
+    }
+
+    fn cannot_read(&self, reason: String) -> ProgramError {
+        ProgramError::CannotReadArchive(self.archive.clone(), reason)
+    }
+}
+
+fn read_zip(entry: &Entry, file: File) -> Result<String, ProgramError> {
+    let failed = |e: zip::result::ZipError| entry.cannot_read(e.to_string());
+    let mut archive = zip::ZipArchive::new(file).map_err(failed)?;
+    let mut found = match archive.by_name(&entry.name) {
+        Ok(found) if found.is_file() => found,
+        Ok(_) | Err(zip::result::ZipError::FileNotFound) => return Err(entry.not_found()),
+        Err(e) => return Err(failed(e)),
+    };
+    let mut contents = String::new();
+    found
+        .read_to_string(&mut contents)
+        .map_err(|e| entry.cannot_read(e.to_string()))?;
+    Ok(contents)
+}
+
+#[cfg(feature = "tar")]
+use tarball::read_tar;
+
+#[cfg(feature = "tar")]
+mod tarball {
+    use std::{fs::File, io::Read, path::Path};
+
+    use super::Entry;
+    use crate::writer::ProgramError;
+
+    pub(super) fn read_tar(entry: &Entry, file: File) -> Result<String, ProgramError> {
+        let failed = |e: std::io::Error| entry.cannot_read(e.to_string());
+        let mut archive = tar::Archive::new(file);
+        for found in archive.entries().map_err(failed)? {
+            let mut found = found.map_err(failed)?;
+            if !found.header().entry_type().is_file()
+                || found.path().map_err(failed)? != Path::new(&entry.name)
+            {
+                continue;
+            }
+            let mut contents = String::new();
+            found.read_to_string(&mut contents).map_err(failed)?;
+            return Ok(contents);
+        }
+        Err(entry.not_found())
+    }
+}
+
+#[cfg(not(feature = "tar"))]
+use unsupported::read_tar;
+
+#[cfg(not(feature = "tar"))]
+mod unsupported {
+    use std::fs::File;
+
+    use super::Entry;
+    use crate::writer::ProgramError;
+
+    pub(super) fn read_tar(_: &Entry, _: File) -> Result<String, ProgramError> {
+        Err(ProgramError::TarUnsupported)
+    }
+}
@@ -0,0 +1,59 @@
+use env_logger::{Env, Logger};
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use log::{LevelFilter, Log, Metadata, Record};
+use std::io::{stderr, IsTerminal};
+
+/// Passes log records on to env_logger, hiding the progress bar while each
+/// record is written so that the two don't end up on the same line.
+struct ProgressLogger {
+    inner: Logger,
+    progress: ProgressBar,
+}
+
+impl Log for ProgressLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.inner.matches(record) {
+            self.progress.suspend(|| self.inner.log(record));
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+/// Sets up logging, starting at `default_level` unless `RUST_LOG` says
+/// otherwise. With `trace_render`, the engine's trace records are always
+/// shown.
+pub(crate) fn init_logging(default_level: &str, trace_render: bool, progress: &ProgressBar) {
+    let mut builder =
+        env_logger::Builder::from_env(Env::default().default_filter_or(default_level));
+    if trace_render {
+        builder.filter_module("replacer::engine", LevelFilter::Trace);
+    }
+    let inner = builder.build();
+    log::set_max_level(inner.filter());
+    log::set_boxed_logger(Box::new(ProgressLogger {
+        inner,
+        progress: progress.clone(),
+    }))
+    .expect("The logger should only be set up once.");
+}
+
+/// Shows the progress bar for a run over `total` files, unless stderr isn't
+/// a terminal.
+pub(crate) fn show(progress: &ProgressBar, total: usize) {
+    if !stderr().is_terminal() {
+        return;
+    }
+
+    progress.set_length(total as u64);
+    if let Ok(style) = ProgressStyle::with_template("{bar:40} {pos}/{len} files {msg}") {
+        progress.set_style(style);
+    }
+    progress.set_draw_target(ProgressDrawTarget::stderr());
+}
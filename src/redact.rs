@@ -0,0 +1,65 @@
+use glob::{MatchOptions, Pattern};
+use std::collections::BTreeSet;
+
+/// The patterns that `--redact-log` masks the values of when no
+/// `--redact-pattern` is given.
+const DEFAULT_PATTERNS: &[&str] = &[
+    "*password*",
+    "*passwd*",
+    "*secret*",
+    "*token*",
+    "*api_key*",
+    "*apikey*",
+    "*private_key*",
+    "*credential*",
+];
+
+/// What the values of keys that match a `--redact-pattern` are logged as.
+const MASK: &str = "****";
+
+/// The keys whose values must never show up in the logs: the ones given
+/// with `--redact`, and, with `--redact-log`, any key that matches one of
+/// the redaction patterns. Patterns ignore case.
+#[derive(Debug, Default)]
+pub(crate) struct Redaction {
+    keys: BTreeSet<String>,
+    patterns: Vec<Pattern>,
+}
+
+impl Redaction {
+    /// Hides `keys`, and if `log` is set, the keys that match `patterns`,
+    /// or the built-in patterns if there are none.
+    pub(crate) fn new(keys: &[String], log: bool, patterns: &[Pattern]) -> Redaction {
+        let patterns = match (log, patterns) {
+            (false, _) => Vec::new(),
+            (true, []) => DEFAULT_PATTERNS
+                .iter()
+                .map(|pattern| Pattern::new(pattern).expect("The built-in patterns are valid."))
+                .collect(),
+            (true, patterns) => patterns.to_vec(),
+        };
+        Redaction {
+            keys: keys.iter().cloned().collect(),
+            patterns,
+        }
+    }
+
+    /// What to log instead of the value of `key`, if it mustn't be logged.
+    pub(crate) fn mask(&self, key: &str) -> Option<&'static str> {
+        let options = MatchOptions {
+            case_sensitive: false,
+            ..MatchOptions::new()
+        };
+        if self.keys.contains(key) {
+            Some("<redacted>")
+        } else if self
+            .patterns
+            .iter()
+            .any(|pattern| pattern.matches_with(key, options))
+        {
+            Some(MASK)
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,67 @@
+use std::str::FromStr;
+
+/// A header to send when fetching `--values-url`, given as `Name: value`.
+#[derive(Debug, Clone)]
+#[cfg_attr(not(feature = "remote"), allow(dead_code))]
+pub(crate) struct Header {
+    name: String,
+    value: String,
+}
+
+impl FromStr for Header {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, value) = s
+            .split_once(':')
+            .ok_or_else(|| format!("Expected \"Name: value\", not {:?}.", s))?;
+        let name = name.trim();
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return Err(format!("{:?} isn't a valid header name.", name));
+        }
+
+        Ok(Header {
+            name: name.to_string(),
+            value: value.trim().to_string(),
+        })
+    }
+}
+
+#[cfg(feature = "remote")]
+pub(crate) use http::fetch;
+
+#[cfg(feature = "remote")]
+mod http {
+    use log::debug;
+
+    use super::Header;
+    use crate::writer::ProgramError;
+
+    /// Fetches the document at `url` with a GET request that carries
+    /// `headers`. Responses that aren't successful are errors.
+    pub(crate) fn fetch(url: &str, headers: &[Header]) -> Result<String, ProgramError> {
+        let failed = |e: ureq::Error| ProgramError::CannotFetch(url.to_string(), e.to_string());
+        let mut request = ureq::Agent::new_with_defaults().get(url);
+        for header in headers {
+            request = request.header(header.name.as_str(), header.value.as_str());
+        }
+
+        let mut response = request.call().map_err(failed)?;
+        let contents = response.body_mut().read_to_string().map_err(failed)?;
+        debug!("Fetched {} byte(s) from {:?}.", contents.len(), url);
+        Ok(contents)
+    }
+}
+
+#[cfg(not(feature = "remote"))]
+pub(crate) use unsupported::fetch;
+
+#[cfg(not(feature = "remote"))]
+mod unsupported {
+    use super::Header;
+    use crate::writer::ProgramError;
+
+    pub(crate) fn fetch(_: &str, _: &[Header]) -> Result<String, ProgramError> {
+        Err(ProgramError::RemoteUnsupported)
+    }
+}
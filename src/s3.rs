@@ -0,0 +1,177 @@
+use std::str::FromStr;
+
+/// Where `--output-s3` uploads rendered results: a bucket, and a prefix
+/// that their names are appended to.
+#[derive(Debug, Clone)]
+#[cfg_attr(not(feature = "s3"), allow(dead_code))]
+pub(crate) struct S3Target {
+    bucket: String,
+    prefix: String,
+}
+
+impl FromStr for S3Target {
+    type Err = String;
+
+    /// Parses a location like `s3://bucket/prefix`, where the prefix is
+    /// optional.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let location = s
+            .strip_prefix("s3://")
+            .ok_or_else(|| format!("{:?} doesn't start with \"s3://\".", s))?;
+        let (bucket, prefix) = location.split_once('/').unwrap_or((location, ""));
+        if bucket.is_empty() {
+            return Err(format!("{:?} doesn't name a bucket.", s));
+        }
+
+        Ok(S3Target {
+            bucket: bucket.to_string(),
+            prefix: prefix.trim_matches('/').to_string(),
+        })
+    }
+}
+
+impl S3Target {
+    /// The key to upload the output named `name` to.
+    #[cfg_attr(not(feature = "s3"), allow(dead_code))]
+    pub(crate) fn key(&self, name: &str) -> String {
+        if self.prefix.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}/{}", self.prefix, name)
+        }
+    }
+}
+
+#[cfg(feature = "s3")]
+pub(crate) use upload::Uploader;
+
+#[cfg(feature = "s3")]
+mod upload {
+    use rusty_s3::{Bucket, Credentials, S3Action, UrlStyle};
+    use std::{env, fs, path::PathBuf, time::Duration};
+
+    use super::S3Target;
+    use crate::writer::ProgramError;
+
+    /// How long a signed upload request stays valid.
+    const SIGNATURE_LIFETIME: Duration = Duration::from_secs(15 * 60);
+
+    /// Reads credentials for `profile` from an AWS shared credentials file,
+    /// which is an INI file with a section per profile.
+    fn read_profile(path: PathBuf, profile: &str) -> Option<Credentials> {
+        let contents = fs::read_to_string(path).ok()?;
+        let (mut key, mut secret, mut token) = (None, None, None);
+        let mut in_profile = false;
+
+        for line in contents.lines().map(str::trim) {
+            if let Some(section) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                in_profile = section.trim() == profile;
+            } else if let (true, Some((name, value))) = (in_profile, line.split_once('=')) {
+                let value = Some(value.trim().to_string());
+                match name.trim() {
+                    "aws_access_key_id" => key = value,
+                    "aws_secret_access_key" => secret = value,
+                    "aws_session_token" => token = value,
+                    _ => {}
+                }
+            }
+        }
+
+        Some(match token {
+            Some(token) => Credentials::new_with_token(key?, secret?, token),
+            None => Credentials::new(key?, secret?),
+        })
+    }
+
+    /// Finds credentials the way the AWS tools do: in the environment first,
+    /// and then in the shared credentials file, for the profile named by
+    /// `AWS_PROFILE` (or `default`).
+    fn credentials() -> Option<Credentials> {
+        Credentials::from_env().or_else(|| {
+            let path = match env::var_os("AWS_SHARED_CREDENTIALS_FILE") {
+                Some(path) => PathBuf::from(path),
+                None => PathBuf::from(env::var_os("HOME")?).join(".aws/credentials"),
+            };
+            let profile = env::var("AWS_PROFILE").unwrap_or_else(|_| String::from("default"));
+            read_profile(path, &profile)
+        })
+    }
+
+    /// Uploads rendered results to an S3 bucket, or to a bucket in an
+    /// S3-compatible store if `AWS_ENDPOINT_URL` is set.
+    pub(crate) struct Uploader {
+        target: S3Target,
+        bucket: Bucket,
+        credentials: Credentials,
+        agent: ureq::Agent,
+    }
+
+    impl Uploader {
+        pub(crate) fn new(target: &S3Target) -> Result<Uploader, ProgramError> {
+            let region = env::var("AWS_REGION")
+                .or_else(|_| env::var("AWS_DEFAULT_REGION"))
+                .unwrap_or_else(|_| String::from("us-east-1"));
+            // Custom endpoints don't necessarily have a host name per
+            // bucket, so they're addressed with the bucket in the path.
+            let (endpoint, style) =
+                match env::var("AWS_ENDPOINT_URL_S3").or_else(|_| env::var("AWS_ENDPOINT_URL")) {
+                    Ok(endpoint) => (endpoint, UrlStyle::Path),
+                    Err(_) => (
+                        format!("https://s3.{}.amazonaws.com", region),
+                        UrlStyle::VirtualHost,
+                    ),
+                };
+            let invalid =
+                |reason: String| ProgramError::InvalidS3Endpoint(endpoint.clone(), reason);
+            let url = endpoint.parse().map_err(|e| invalid(format!("{}", e)))?;
+            let bucket = Bucket::new(url, style, target.bucket.clone(), region)
+                .map_err(|e| invalid(e.to_string()))?;
+
+            Ok(Uploader {
+                target: target.clone(),
+                bucket,
+                credentials: credentials().ok_or(ProgramError::NoS3Credentials)?,
+                agent: ureq::Agent::new_with_defaults(),
+            })
+        }
+
+        /// Uploads `contents` as the output named `name`, returning the key
+        /// it was uploaded to.
+        pub(crate) fn upload(&self, name: &str, contents: &[u8]) -> Result<String, ProgramError> {
+            let key = self.target.key(name);
+            let url = self
+                .bucket
+                .put_object(Some(&self.credentials), &key)
+                .sign(SIGNATURE_LIFETIME);
+
+            self.agent
+                .put(url.as_str())
+                .send(contents)
+                .map(|_| key.clone())
+                .map_err(|e| {
+                    ProgramError::CannotUpload(self.target.bucket.clone(), key, e.to_string())
+                })
+        }
+    }
+}
+
+#[cfg(not(feature = "s3"))]
+pub(crate) use unsupported::Uploader;
+
+#[cfg(not(feature = "s3"))]
+mod unsupported {
+    use super::S3Target;
+    use crate::writer::ProgramError;
+
+    pub(crate) struct Uploader;
+
+    impl Uploader {
+        pub(crate) fn new(_: &S3Target) -> Result<Uploader, ProgramError> {
+            Err(ProgramError::S3Unsupported)
+        }
+
+        pub(crate) fn upload(&self, _: &str, _: &[u8]) -> Result<String, ProgramError> {
+            Err(ProgramError::S3Unsupported)
+        }
+    }
+}
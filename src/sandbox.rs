@@ -0,0 +1,76 @@
+use std::{
+    env,
+    path::{Component, Path, PathBuf},
+};
+
+use crate::writer::ProgramError;
+
+/// Resolves `path` to an absolute path without symlinks or `..` components.
+/// The path doesn't need to exist: its deepest existing ancestor is
+/// canonicalized, and the rest is appended to that.
+fn resolve(path: &Path) -> PathBuf {
+    let absolute = env::current_dir()
+        .unwrap_or_else(|_| PathBuf::from("."))
+        .join(path);
+
+    let mut existing = absolute.as_path();
+    let mut missing = Vec::new();
+    let mut resolved = loop {
+        if let Ok(canonical) = existing.canonicalize() {
+            break canonical;
+        }
+        match (existing.parent(), existing.components().next_back()) {
+            (Some(parent), Some(component)) => {
+                missing.push(component);
+                existing = parent;
+            }
+            _ => break PathBuf::new(),
+        }
+    };
+
+    for component in missing.into_iter().rev() {
+        match component {
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            Component::CurDir => {}
+            component => resolved.push(component),
+        }
+    }
+
+    resolved
+}
+
+/// The directory that output files must stay inside of.
+pub(crate) struct Root(PathBuf);
+
+impl Root {
+    pub(crate) fn new(path: &Path) -> Result<Root, ProgramError> {
+        path.canonicalize()
+            .map(Root)
+            .map_err(|_| ProgramError::FileNotFound(path.to_path_buf()))
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.0
+    }
+
+    /// Resolves `path` relative to the root, or returns `None` if it ends up
+    /// outside of it once symlinks and `..` components are resolved.
+    pub(crate) fn join(&self, path: &str) -> Option<PathBuf> {
+        Some(resolve(&self.0.join(path))).filter(|resolved| resolved.starts_with(&self.0))
+    }
+
+    /// Fails if `output_file` would end up outside the root once symlinks
+    /// and `..` components are resolved.
+    pub(crate) fn check(&self, output_file: &Path) -> Result<(), ProgramError> {
+        if resolve(output_file).starts_with(&self.0) {
+            Ok(())
+        } else {
+            Err(ProgramError::PathEscapesRoot(
+                output_file.to_path_buf(),
+                self.0.clone(),
+            ))
+        }
+    }
+}
@@ -0,0 +1,89 @@
+use std::fmt;
+
+/// How many lines to show before and after the line an error is on.
+const CONTEXT_LINES: usize = 2;
+
+/// The part of a template around the location of an error, shown with the
+/// error in the style of the Rust compiler.
+#[derive(Debug)]
+pub(crate) struct Snippet {
+    line: usize,
+    column: usize,
+    /// The lines to show, with their (1-based) numbers.
+    lines: Vec<(usize, String)>,
+}
+
+impl Snippet {
+    /// The snippet of `template` around `line` and `column` (both 1-based),
+    /// or `None` if the template has no such line.
+    pub(crate) fn new(template: &str, line: usize, column: usize) -> Option<Snippet> {
+        let first = line.checked_sub(CONTEXT_LINES).unwrap_or(1).max(1);
+        // Splitting at line breaks (rather than using `lines`) keeps the
+        // empty line after a final line break, where errors about unclosed
+        // blocks are reported.
+        let end = template.split('\n').count();
+        let lines: Vec<(usize, String)> = template
+            .split('\n')
+            .enumerate()
+            .map(|(index, text)| (index + 1, text.trim_end_matches('\r').to_string()))
+            .skip(first - 1)
+            .take(line + CONTEXT_LINES + 1 - first)
+            .filter(|(number, text)| *number == line || *number < end || !text.is_empty())
+            .collect();
+
+        if lines.iter().any(|(number, _)| *number == line) {
+            Some(Snippet {
+                line,
+                column,
+                lines,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// The snippet at the location handlebars reported for an error, if it
+    /// reported one.
+    pub(crate) fn at(
+        template: &str,
+        line: Option<usize>,
+        column: Option<usize>,
+    ) -> Option<Snippet> {
+        Snippet::new(template, line?, column.unwrap_or(1))
+    }
+}
+
+impl fmt::Display for Snippet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let last = self.lines.last().map_or(self.line, |(number, _)| *number);
+        let width = last.to_string().len();
+
+        writeln!(
+            f,
+            "{:width$}--> line {}, column {}",
+            "",
+            self.line,
+            self.column,
+            width = width
+        )?;
+        writeln!(f, "{:width$} |", "", width = width)?;
+        for (number, text) in &self.lines {
+            if text.is_empty() {
+                writeln!(f, "{:>width$} |", number, width = width)?;
+            } else {
+                writeln!(f, "{:>width$} | {}", number, text, width = width)?;
+            }
+            if *number == self.line {
+                // Tabs are kept, so that the caret lines up however wide
+                // they're shown.
+                let indent: String = text
+                    .chars()
+                    .take(self.column.saturating_sub(1))
+                    .map(|c| if c == '\t' { '\t' } else { ' ' })
+                    .collect();
+                writeln!(f, "{:width$} | {}^", "", indent, width = width)?;
+            }
+        }
+        write!(f, "{:width$} |", "", width = width)
+    }
+}
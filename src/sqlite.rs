@@ -0,0 +1,86 @@
+#[cfg(feature = "sqlite")]
+pub(crate) use database::read_values;
+
+#[cfg(feature = "sqlite")]
+mod database {
+    use handlebars::JsonValue;
+    use log::debug;
+    use rusqlite::{types::ValueRef, Connection, OpenFlags};
+    use std::path::Path;
+
+    use crate::values::Mappings;
+    use crate::writer::ProgramError;
+
+    /// Turns a value from the database into a mapping value, or `None` for
+    /// a blob, which has no equivalent.
+    fn to_json(value: ValueRef) -> Option<JsonValue> {
+        match value {
+            ValueRef::Null => Some(JsonValue::Null),
+            ValueRef::Integer(integer) => Some(JsonValue::from(integer)),
+            ValueRef::Real(real) => Some(JsonValue::from(real)),
+            ValueRef::Text(text) => Some(JsonValue::from(String::from_utf8_lossy(text))),
+            ValueRef::Blob(_) => None,
+        }
+    }
+
+    /// Runs `query` against the SQLite database at `path`, which is opened
+    /// read-only, and reads its result as mappings: the first column is the
+    /// key and the second one is the value. Keys that come up more than once
+    /// get the last of their values, and null values are left out.
+    pub(crate) fn read_values(path: &Path, query: &str) -> Result<Mappings, ProgramError> {
+        let failed = |reason: String| ProgramError::QueryFailed(path.to_path_buf(), reason);
+        let connection = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .map_err(|e| ProgramError::CannotOpenDatabase(path.to_path_buf(), e.to_string()))?;
+        let mut statement = connection
+            .prepare(query)
+            .map_err(|e| failed(e.to_string()))?;
+        if statement.column_count() != 2 {
+            return Err(failed(format!(
+                "it returns {} column(s) rather than a key and a value",
+                statement.column_count()
+            )));
+        }
+
+        let mut mappings = Mappings::new();
+        let mut rows = statement.query([]).map_err(|e| failed(e.to_string()))?;
+        while let Some(row) = rows.next().map_err(|e| failed(e.to_string()))? {
+            let key = match row.get_ref(0).map_err(|e| failed(e.to_string()))? {
+                ValueRef::Text(key) => String::from_utf8_lossy(key).into_owned(),
+                ValueRef::Integer(key) => key.to_string(),
+                _ => return Err(failed(String::from("a key isn't text or an integer"))),
+            };
+            let value = row.get_ref(1).map_err(|e| failed(e.to_string()))?;
+            match to_json(value) {
+                Some(JsonValue::Null) => {
+                    debug!("Ignoring {:?} in {:?} because it has no value.", key, path)
+                }
+                Some(value) => {
+                    mappings.insert(key, value);
+                }
+                None => return Err(failed(format!("the value of {:?} is a blob", key))),
+            }
+        }
+
+        debug!(
+            "Loaded the keys {:?} from the database {:?}.",
+            mappings.keys().collect::<Vec<_>>(),
+            path
+        );
+        Ok(mappings)
+    }
+}
+
+#[cfg(not(feature = "sqlite"))]
+pub(crate) use unsupported::read_values;
+
+#[cfg(not(feature = "sqlite"))]
+mod unsupported {
+    use std::path::Path;
+
+    use crate::values::Mappings;
+    use crate::writer::ProgramError;
+
+    pub(crate) fn read_values(_: &Path, _: &str) -> Result<Mappings, ProgramError> {
+        Err(ProgramError::SqliteUnsupported)
+    }
+}
@@ -0,0 +1,109 @@
+use serde::Serialize;
+use std::{
+    fs::File,
+    io::{self, Write},
+    path::PathBuf,
+    time::Instant,
+};
+
+use crate::writer::{Outcome, ProgramError};
+
+/// An overview of a whole run, for `--summary-json`.
+#[derive(Serialize)]
+pub(crate) struct Summary {
+    /// Templates that were rendered successfully.
+    rendered: usize,
+    /// Templates that weren't rendered because an earlier one failed.
+    skipped: usize,
+    /// Templates that weren't rendered because their output was up to date
+    /// according to the cache.
+    unchanged: usize,
+    /// Templates that failed to render or write.
+    failed: usize,
+    /// The number of bytes written to output files (or to the archive, or
+    /// uploaded).
+    total_bytes: usize,
+    /// The number of failed templates that stops a `--keep-going` run, if
+    /// there is one.
+    fail_fast_threshold: Option<usize>,
+    elapsed_seconds: f64,
+    errors: Vec<String>,
+    #[serde(skip)]
+    started: Instant,
+}
+
+impl Summary {
+    pub(crate) fn start(fail_fast_threshold: Option<usize>) -> Summary {
+        Summary {
+            rendered: 0,
+            skipped: 0,
+            unchanged: 0,
+            failed: 0,
+            total_bytes: 0,
+            fail_fast_threshold,
+            elapsed_seconds: 0.0,
+            errors: Vec::new(),
+            started: Instant::now(),
+        }
+    }
+
+    pub(crate) fn record(&mut self, outcome: &Outcome) {
+        match outcome {
+            Outcome::Unchanged(_) => self.unchanged += 1,
+            Outcome::Written { bytes, .. }
+            | Outcome::Archived { bytes, .. }
+            | Outcome::Uploaded { bytes, .. }
+            | Outcome::Staged { bytes, .. } => {
+                self.rendered += 1;
+                self.total_bytes += bytes;
+            }
+            _ => self.rendered += 1,
+        }
+    }
+
+    pub(crate) fn unchanged(&self) -> usize {
+        self.unchanged
+    }
+
+    /// Records an error. Errors that happen before any template is rendered
+    /// (such as an unreadable replacements file) don't count as failed
+    /// templates, but are listed all the same.
+    pub(crate) fn record_error(&mut self, error: &ProgramError, template_failed: bool) {
+        if template_failed {
+            self.failed += 1;
+        }
+        self.errors.push(error.to_string());
+    }
+
+    pub(crate) fn skip(&mut self, count: usize) {
+        self.skipped += count;
+    }
+
+    pub(crate) fn failed(&self) -> usize {
+        self.failed
+    }
+
+    pub(crate) fn has_failures(&self) -> bool {
+        self.failed > 0
+    }
+
+    /// Whether enough templates have failed to reach the fail-fast threshold.
+    pub(crate) fn reached_threshold(&self) -> bool {
+        self.fail_fast_threshold
+            .is_some_and(|threshold| self.failed >= threshold)
+    }
+
+    /// Writes the summary as JSON to `destination`, or to stdout if it's `-`.
+    pub(crate) fn write(mut self, destination: &str) -> Result<(), ProgramError> {
+        self.elapsed_seconds = self.started.elapsed().as_secs_f64();
+        let json = serde_json::to_string_pretty(&self)
+            .map_err(|e| ProgramError::RenderError(e.to_string(), None))?;
+
+        let written = if destination == "-" {
+            writeln!(io::stdout(), "{}", json)
+        } else {
+            File::create(destination).and_then(|mut file| writeln!(file, "{}", json))
+        };
+        written.map_err(|_| ProgramError::CannotOpenFileForWriting(PathBuf::from(destination)))
+    }
+}
@@ -0,0 +1,114 @@
+use log::warn;
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    mem,
+    path::{Path, PathBuf},
+    process,
+};
+
+use crate::writer::{ProgramError, WriteOptions};
+
+/// A rendered result that's waiting to be moved into place.
+enum Pending {
+    /// Written to a temporary file next to the output file.
+    File(PathBuf),
+    /// For a pipe or device, which can't be replaced, so its contents are
+    /// kept until they're written to it.
+    Special(Vec<u8>),
+}
+
+struct Staged {
+    output_file: PathBuf,
+    pending: Pending,
+    /// The cache key to store for the output file once it's in place.
+    cache_key: Option<String>,
+}
+
+/// Collects the results of a `--transactional` run, so that they're either
+/// all moved into place once every template has rendered, or all thrown away.
+/// Anything that's still staged when the transaction is dropped is removed.
+#[derive(Default)]
+pub(crate) struct Transaction {
+    staged: Vec<Staged>,
+}
+
+impl Transaction {
+    /// Where the result for `output_file` is staged: next to it, so that
+    /// it's on the same file system and can be renamed into place.
+    pub(crate) fn temporary_path(output_file: &Path) -> PathBuf {
+        let mut name = output_file.file_name().unwrap_or_default().to_os_string();
+        name.push(format!(".{}.tmp", process::id()));
+        output_file.with_file_name(name)
+    }
+
+    /// Adds a result that's been written to `temporary`.
+    pub(crate) fn add(
+        &mut self,
+        output_file: PathBuf,
+        temporary: PathBuf,
+        cache_key: Option<String>,
+    ) {
+        self.staged.push(Staged {
+            output_file,
+            pending: Pending::File(temporary),
+            cache_key,
+        });
+    }
+
+    /// Adds a result for a pipe or device, which is written when the
+    /// transaction is committed.
+    pub(crate) fn add_special(&mut self, output_file: PathBuf, contents: Vec<u8>) {
+        self.staged.push(Staged {
+            output_file,
+            pending: Pending::Special(contents),
+            cache_key: None,
+        });
+    }
+
+    /// Moves every staged result into place, and then runs what comes after
+    /// writing a file (storing its cache key and running the post-hook).
+    /// Returns the output files. If a file can't be moved, the ones after
+    /// it are thrown away, but the ones before it stay in place.
+    pub(crate) fn commit(mut self, options: &WriteOptions) -> Result<Vec<PathBuf>, ProgramError> {
+        let staged = mem::take(&mut self.staged);
+        for (index, file) in staged.iter().enumerate() {
+            let moved = match &file.pending {
+                Pending::File(temporary) => fs::rename(temporary, &file.output_file),
+                Pending::Special(contents) => OpenOptions::new()
+                    .write(true)
+                    .open(&file.output_file)
+                    .and_then(|mut output| output.write_all(contents)),
+            };
+            if let Err(e) = moved {
+                self.staged = staged.into_iter().skip(index).collect();
+                return Err(ProgramError::CannotMoveIntoPlace(
+                    self.staged[0].output_file.clone(),
+                    e.to_string(),
+                ));
+            }
+        }
+
+        for file in &staged {
+            if let (Some(cache), Some(key)) = (&options.cache, &file.cache_key) {
+                cache.store(&file.output_file, key);
+            }
+            if let Some(hook) = &options.post_hook {
+                hook.run(&file.output_file, options.post_hook_strict)?;
+            }
+        }
+        Ok(staged.into_iter().map(|file| file.output_file).collect())
+    }
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        for file in &self.staged {
+            if let Pending::File(temporary) = &file.pending {
+                if fs::remove_file(temporary).is_err() {
+                    warn!("Couldn't remove the staged file {:?}.", temporary);
+                }
+            }
+        }
+    }
+}
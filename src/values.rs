@@ -0,0 +1,569 @@
+use handlebars::JsonValue;
+use log::debug;
+use serde_yaml::{Mapping, Value};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use crate::writer::ProgramError;
+
+/// The values that templates are rendered with, by key. Kept sorted so that
+/// anything that goes through them (logs, cache keys) comes out the same on
+/// every run.
+pub(crate) type Mappings = BTreeMap<String, JsonValue>;
+
+const MERGE_KEY: &str = "<<";
+
+fn merge_into(mapping: &mut Mapping, merged: Value) {
+    match merged {
+        Value::Mapping(merged) => {
+            for (key, value) in merged {
+                if !mapping.contains_key(&key) {
+                    mapping.insert(key, value);
+                }
+            }
+        }
+        // Earlier entries take precedence over later ones.
+        Value::Sequence(sequence) => {
+            for merged in sequence {
+                merge_into(mapping, merged);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Applies YAML merge keys (`<<: *anchor`) throughout a value: the keys of
+/// the merged mappings are copied into the mapping that contains the merge
+/// key, unless that mapping already sets them itself.
+pub(crate) fn resolve_merge_keys(value: Value) -> Value {
+    match value {
+        Value::Mapping(mapping) => {
+            let merge_key = Value::String(MERGE_KEY.to_string());
+            let mut resolved = Mapping::new();
+            let mut merged = None;
+            for (key, value) in mapping {
+                let value = resolve_merge_keys(value);
+                if key == merge_key {
+                    merged = Some(value);
+                } else {
+                    resolved.insert(key, value);
+                }
+            }
+            if let Some(merged) = merged {
+                merge_into(&mut resolved, merged);
+            }
+            Value::Mapping(resolved)
+        }
+        Value::Sequence(sequence) => {
+            Value::Sequence(sequence.into_iter().map(resolve_merge_keys).collect())
+        }
+        other => other,
+    }
+}
+
+/// Splits a path into the keys it's made of. A path that starts with `/` is a
+/// JSON Pointer (RFC 6901), such as `/tools/replacer`, in which `~1` stands
+/// for `/` and `~0` for `~`, so that keys can contain any character.
+/// Anything else is a dotted path, such as `tools.replacer`.
+fn path_keys(path: &str) -> Vec<String> {
+    match path.strip_prefix('/') {
+        Some(pointer) => pointer
+            .split('/')
+            .map(|key| key.replace("~1", "/").replace("~0", "~"))
+            .collect(),
+        None => path.split('.').map(String::from).collect(),
+    }
+}
+
+/// Picks out the value at a dotted path or JSON Pointer (see `path_keys`),
+/// where each key is a key in a (nested) mapping. With a JSON Pointer, keys
+/// can also be indices into lists.
+pub(crate) fn select(value: Value, path: &str, source: &Path) -> Result<Value, ProgramError> {
+    let is_pointer = path.starts_with('/');
+    let mut selected = value;
+    for key in path_keys(path) {
+        selected = match selected {
+            Value::Mapping(mut mapping) => mapping.remove(&Value::String(key)),
+            Value::Sequence(mut sequence) if is_pointer => key
+                .parse::<usize>()
+                .ok()
+                .filter(|index| *index < sequence.len())
+                .map(|index| sequence.swap_remove(index)),
+            _ => None,
+        }
+        .ok_or_else(|| ProgramError::MissingValuesKey(source.to_path_buf(), path.to_string()))?;
+    }
+
+    Ok(selected)
+}
+
+fn as_plain_value(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+/// Converts a YAML value to JSON for rendering. Plain values become strings,
+/// like top-level mappings do, so that nested values behave the same way.
+fn to_json(value: Value, source: &Path) -> Result<JsonValue, ProgramError> {
+    Ok(match value {
+        Value::Null => JsonValue::Null,
+        Value::Sequence(sequence) => JsonValue::Array(
+            sequence
+                .into_iter()
+                .map(|value| to_json(value, source))
+                .collect::<Result<_, _>>()?,
+        ),
+        Value::Mapping(mapping) => {
+            let mut object = serde_json::Map::new();
+            for (key, value) in mapping {
+                let key = as_plain_value(&key)
+                    .ok_or_else(|| ProgramError::ReadFailed(source.to_path_buf()))?;
+                object.insert(key, to_json(value, source)?);
+            }
+            JsonValue::Object(object)
+        }
+        plain => JsonValue::String(as_plain_value(&plain).unwrap_or_default()),
+    })
+}
+
+/// Turns a (merge-resolved) YAML mapping into the mappings used for
+/// rendering. Nested mappings and lists are kept as they are, for use with
+/// helpers like `lookup` and `each`, but top-level nulls are skipped so that
+/// using them is a missing key error. An empty file gives empty mappings.
+pub(crate) fn into_mappings(value: Value, source: &Path) -> Result<Mappings, ProgramError> {
+    let mapping = match value {
+        Value::Mapping(mapping) => mapping,
+        Value::Null => Mapping::new(),
+        _ => return Err(ProgramError::ReadFailed(source.to_path_buf())),
+    };
+
+    let mut mappings = BTreeMap::new();
+    for (key, value) in mapping {
+        let key =
+            as_plain_value(&key).ok_or_else(|| ProgramError::ReadFailed(source.to_path_buf()))?;
+        if value.is_null() {
+            debug!(
+                "Ignoring {:?} in {:?} because it has no value.",
+                key, source
+            );
+            continue;
+        }
+        mappings.insert(key, to_json(value, source)?);
+    }
+
+    Ok(mappings)
+}
+
+/// The types that mapping values can be coerced to with `--types`.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum ValueType {
+    String,
+    Int,
+    Float,
+    Bool,
+}
+
+impl ValueType {
+    fn name(self) -> &'static str {
+        match self {
+            ValueType::String => "string",
+            ValueType::Int => "int",
+            ValueType::Float => "float",
+            ValueType::Bool => "bool",
+        }
+    }
+
+    fn coerce(self, value: &str) -> Option<JsonValue> {
+        match self {
+            ValueType::String => Some(JsonValue::String(value.to_string())),
+            ValueType::Int => value.trim().parse::<i64>().ok().map(JsonValue::from),
+            ValueType::Float => value
+                .trim()
+                .parse::<f64>()
+                .ok()
+                .filter(|f| f.is_finite())
+                .map(JsonValue::from),
+            ValueType::Bool => value.trim().parse::<bool>().ok().map(JsonValue::Bool),
+        }
+    }
+}
+
+/// A key and the type its value should be coerced to, given as `key:type`.
+#[derive(Debug)]
+pub(crate) struct TypedKey {
+    key: String,
+    value_type: ValueType,
+}
+
+impl FromStr for TypedKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (key, value_type) = match s.rfind(':') {
+            Some(i) if i > 0 => (&s[..i], &s[i + 1..]),
+            _ => return Err(format!("Expected key:type, not {:?}.", s)),
+        };
+        let value_type = match value_type {
+            "string" => ValueType::String,
+            "int" => ValueType::Int,
+            "float" => ValueType::Float,
+            "bool" => ValueType::Bool,
+            other => {
+                return Err(format!(
+                "Unknown type {:?}; expected one of \"string\", \"int\", \"float\", or \"bool\".",
+                other
+            ))
+            }
+        };
+
+        Ok(TypedKey {
+            key: key.to_string(),
+            value_type,
+        })
+    }
+}
+
+/// Merges `value` into `slot`. Where both are mappings, they're merged key
+/// by key, recursively; otherwise, including for lists, `value` replaces
+/// what's in `slot`.
+fn merge_deeply(slot: &mut JsonValue, value: JsonValue) {
+    match (slot, value) {
+        (JsonValue::Object(base), JsonValue::Object(overrides)) => {
+            for (key, value) in overrides {
+                match base.get_mut(&key) {
+                    Some(slot) => merge_deeply(slot, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (slot, value) => *slot = value,
+    }
+}
+
+/// Merges `overrides` into `base` like `merge_deeply` does, so that nested
+/// mappings are combined instead of replaced.
+pub(crate) fn deep_merge(base: &mut Mappings, overrides: Mappings) {
+    for (key, value) in overrides {
+        match base.get_mut(&key) {
+            Some(slot) => merge_deeply(slot, value),
+            None => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Converts the values of the given keys to their types. Keys that aren't in
+/// the mappings are left alone.
+pub(crate) fn coerce(mappings: &mut Mappings, types: &[TypedKey]) -> Result<(), ProgramError> {
+    for typed in types {
+        if let Some(value) = mappings.get_mut(&typed.key) {
+            let raw = match value {
+                JsonValue::String(s) => s.clone(),
+                _ => continue,
+            };
+            *value = typed.value_type.coerce(&raw).ok_or_else(|| {
+                ProgramError::CannotCoerceValue(typed.key.clone(), raw, typed.value_type.name())
+            })?;
+        }
+    }
+
+    Ok(())
+}
+
+/// A values file to make available under a top-level key, given as
+/// `name=path`.
+#[derive(Debug)]
+pub(crate) struct Namespace {
+    pub(crate) name: String,
+    pub(crate) path: PathBuf,
+}
+
+impl FromStr for Namespace {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.find('=') {
+            Some(i) if i > 0 && i + 1 < s.len() => Ok(Namespace {
+                name: s[..i].to_string(),
+                path: PathBuf::from(&s[i + 1..]),
+            }),
+            _ => Err(format!("Expected name=path, not {:?}.", s)),
+        }
+    }
+}
+
+/// Adds each namespace's values to `mappings` under its name. It's an error
+/// if the mappings already have a key by that name.
+pub(crate) fn add_namespaces(
+    mappings: &mut Mappings,
+    namespaces: &[(String, JsonValue)],
+) -> Result<(), ProgramError> {
+    for (name, values) in namespaces {
+        if mappings.contains_key(name) {
+            return Err(ProgramError::NamespaceShadowsKey(name.clone()));
+        }
+        mappings.insert(name.clone(), values.clone());
+    }
+
+    Ok(())
+}
+
+/// How the mappings of several replacements files are combined, for
+/// `--values-merge-strategy`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum MergeStrategy {
+    /// Later files replace whole top-level keys.
+    Shallow,
+    /// Nested mappings are merged key by key, like override files are.
+    Deep,
+    /// Like `Deep`, but it's an error for files to disagree about a value.
+    Strict,
+}
+
+impl FromStr for MergeStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "shallow" => Ok(MergeStrategy::Shallow),
+            "deep" => Ok(MergeStrategy::Deep),
+            "strict" => Ok(MergeStrategy::Strict),
+            _ => Err(format!(
+                "Unknown merge strategy {:?}; expected one of \"shallow\", \"deep\", or \"strict\".",
+                s
+            )),
+        }
+    }
+}
+
+/// Merges `value` into `slot` like `merge_deeply`, except that it's an error
+/// if they have different values for the same key. `key` is the dotted path
+/// to `slot`, and `source` is where `value` came from.
+fn merge_strictly(
+    slot: &mut JsonValue,
+    value: JsonValue,
+    key: &str,
+    source: &Path,
+) -> Result<(), ProgramError> {
+    match (slot, value) {
+        (JsonValue::Object(base), JsonValue::Object(overrides)) => {
+            for (name, value) in overrides {
+                let nested = format!("{}.{}", key, name);
+                match base.get_mut(&name) {
+                    Some(slot) => merge_strictly(slot, value, &nested, source)?,
+                    None => {
+                        base.insert(name, value);
+                    }
+                }
+            }
+            Ok(())
+        }
+        (slot, value) if *slot == value => Ok(()),
+        (slot, value) => Err(ProgramError::ConflictingValue(
+            key.to_string(),
+            source.to_path_buf(),
+            value.to_string(),
+            slot.to_string(),
+        )),
+    }
+}
+
+/// Merges the mappings from each of `sources` into one, in order, so that
+/// later sources take precedence unless the strategy is `Strict`.
+pub(crate) fn merge_sources(
+    sources: Vec<(&Path, Mappings)>,
+    strategy: MergeStrategy,
+) -> Result<Mappings, ProgramError> {
+    let mut merged = Mappings::new();
+    for (source, mappings) in sources {
+        match strategy {
+            MergeStrategy::Shallow => merged.extend(mappings),
+            MergeStrategy::Deep => deep_merge(&mut merged, mappings),
+            MergeStrategy::Strict => {
+                for (key, value) in mappings {
+                    match merged.get_mut(&key) {
+                        Some(slot) => merge_strictly(slot, value, &key, source)?,
+                        None => {
+                            merged.insert(key, value);
+                        }
+                    }
+                }
+            }
+        }
+    }
+    Ok(merged)
+}
+
+/// Adds `value` to `flat` under `key`, or, if it's a non-empty mapping, each
+/// of its values under `key`, `separator`, and their own key, all the way
+/// down.
+fn flatten_into(
+    flat: &mut Mappings,
+    key: String,
+    value: JsonValue,
+    separator: &str,
+) -> Result<(), ProgramError> {
+    match value {
+        JsonValue::Object(object) if !object.is_empty() => {
+            for (name, value) in object {
+                flatten_into(
+                    flat,
+                    format!("{}{}{}", key, separator, name),
+                    value,
+                    separator,
+                )?;
+            }
+            Ok(())
+        }
+        value => match flat.insert(key.clone(), value) {
+            Some(_) => Err(ProgramError::FlattenedKeyCollision(key)),
+            None => Ok(()),
+        },
+    }
+}
+
+/// Turns nested mappings into top-level keys for `--values-flatten`, so that
+/// `db: {host: x}` becomes `db_host: x` with `_` as the separator. Lists are
+/// left as they are. It's an error if two keys flatten to the same one.
+pub(crate) fn flatten(mappings: Mappings, separator: &str) -> Result<Mappings, ProgramError> {
+    let mut flat = Mappings::new();
+    for (key, value) in mappings {
+        flatten_into(&mut flat, key, value, separator)?;
+    }
+    Ok(flat)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use handlebars::JsonValue;
+
+    fn read(yaml: &str) -> Mappings {
+        let value = resolve_merge_keys(serde_yaml::from_str(yaml).unwrap());
+        into_mappings(value, Path::new("test.yml")).unwrap()
+    }
+
+    #[test]
+    fn anchors_can_be_reused() {
+        let mappings = read("host: &host db.local\nprimary: *host\nreplica: *host\n");
+        assert_eq!(mappings["primary"], JsonValue::from("db.local"));
+        assert_eq!(mappings["replica"], JsonValue::from("db.local"));
+    }
+
+    #[test]
+    fn merge_keys_copy_the_merged_mapping() {
+        let mappings =
+            read("base: &base\n  host: db.local\n  port: 5432\nprod:\n  <<: *base\n  name: prod\n");
+        assert_eq!(
+            mappings["prod"],
+            serde_json::json!({"host": "db.local", "port": "5432", "name": "prod"})
+        );
+    }
+
+    #[test]
+    fn local_keys_override_merged_ones() {
+        let mappings = read(
+            "base: &base\n  host: db.local\n  port: 5432\nprod:\n  <<: *base\n  host: db.prod\n",
+        );
+        assert_eq!(
+            mappings["prod"],
+            serde_json::json!({"host": "db.prod", "port": "5432"})
+        );
+    }
+
+    #[test]
+    fn earlier_merged_mappings_take_precedence() {
+        let mappings = read("a: &a {host: a}\nb: &b {host: b, port: 1}\nboth:\n  <<: [*a, *b]\n");
+        assert_eq!(
+            mappings["both"],
+            serde_json::json!({"host": "a", "port": "1"})
+        );
+    }
+
+    #[test]
+    fn deep_merges_combine_nested_mappings() {
+        let base = "db:\n  host: db.local\n  port: 5432\nhosts: [a, b]\n";
+        let overrides = "db:\n  host: db.prod\nhosts: [c]\n";
+
+        // A shallow merge replaces the whole `db` mapping.
+        let mut shallow = read(base);
+        shallow.extend(read(overrides));
+        assert_eq!(shallow["db"], serde_json::json!({"host": "db.prod"}));
+
+        let mut base = read(base);
+        deep_merge(&mut base, read(overrides));
+        assert_eq!(
+            base["db"],
+            serde_json::json!({"host": "db.prod", "port": "5432"})
+        );
+        // Lists are replaced, not merged.
+        assert_eq!(base["hosts"], serde_json::json!(["c"]));
+    }
+
+    #[test]
+    fn deep_merges_replace_mappings_with_scalars() {
+        let mut base = read("db:\n  host: db.local\n");
+        deep_merge(&mut base, read("db: sqlite\n"));
+        assert_eq!(base["db"], JsonValue::from("sqlite"));
+    }
+
+    fn merge_files(strategy: MergeStrategy) -> Result<Mappings, ProgramError> {
+        let sources = vec![
+            (
+                Path::new("base.yml"),
+                read("db:\n  host: db.local\n  port: 5432\n"),
+            ),
+            (Path::new("prod.yml"), read("db:\n  host: db.prod\n")),
+        ];
+        merge_sources(sources, strategy)
+    }
+
+    #[test]
+    fn only_shallow_merges_replace_nested_mappings() {
+        let shallow = merge_files(MergeStrategy::Shallow).unwrap();
+        assert_eq!(shallow["db"], serde_json::json!({"host": "db.prod"}));
+
+        let deep = merge_files(MergeStrategy::Deep).unwrap();
+        assert_eq!(
+            deep["db"],
+            serde_json::json!({"host": "db.prod", "port": "5432"})
+        );
+    }
+
+    #[test]
+    fn strict_merges_reject_conflicting_values() {
+        match merge_files(MergeStrategy::Strict) {
+            Err(ProgramError::ConflictingValue(key, source, _, _)) => {
+                assert_eq!(key, "db.host");
+                assert_eq!(source, Path::new("prod.yml"));
+            }
+            other => panic!("Expected a conflict, got {:?}.", other),
+        }
+    }
+
+    #[test]
+    fn strict_merges_allow_agreeing_values() {
+        let sources = vec![
+            (Path::new("a.yml"), read("db:\n  host: db.local\n")),
+            (
+                Path::new("b.yml"),
+                read("db:\n  host: db.local\n  port: 5432\n"),
+            ),
+        ];
+        let merged = merge_sources(sources, MergeStrategy::Strict).unwrap();
+        assert_eq!(
+            merged["db"],
+            serde_json::json!({"host": "db.local", "port": "5432"})
+        );
+    }
+}
@@ -0,0 +1,101 @@
+use std::path::Path;
+
+use crate::helpers::BoxedHelper;
+use crate::writer::ProgramError;
+
+/// Loads every `.wasm` module in `dir` as a helper named after the file, so
+/// `slugify.wasm` becomes `{{slugify title}}`.
+///
+/// A module must export its `memory`, an `alloc(len: i32) -> i32` function
+/// that reserves `len` bytes for the input, and a `render(ptr: i32, len: i32)
+/// -> i64` function. `render` is given the helper's parameters as a JSON
+/// array and returns the location of its UTF-8 output, with the pointer in
+/// the upper 32 bits and the length in the lower 32 bits.
+#[cfg(feature = "wasm-helpers")]
+pub(crate) fn load(dir: &Path) -> Result<Vec<(String, BoxedHelper)>, ProgramError> {
+    use std::fs;
+
+    let entries = fs::read_dir(dir).map_err(|_| ProgramError::FileNotFound(dir.to_path_buf()))?;
+    let mut paths: Vec<_> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "wasm"))
+        .collect();
+    paths.sort();
+
+    let engine = wasmtime::Engine::default();
+    let mut helpers = Vec::new();
+    for path in paths {
+        let name = match path.file_stem().and_then(|stem| stem.to_str()) {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+        let module = wasmtime::Module::from_file(&engine, &path)
+            .map_err(|e| ProgramError::CannotLoadWasmHelper(path.clone(), e.to_string()))?;
+        let helper = helper::WasmHelper {
+            name: name.clone(),
+            engine: engine.clone(),
+            module,
+        };
+        helpers.push((name, Box::new(helper) as BoxedHelper));
+    }
+
+    Ok(helpers)
+}
+
+#[cfg(not(feature = "wasm-helpers"))]
+pub(crate) fn load(_: &Path) -> Result<Vec<(String, BoxedHelper)>, ProgramError> {
+    Err(ProgramError::WasmHelpersUnsupported)
+}
+
+#[cfg(feature = "wasm-helpers")]
+mod helper {
+    use handlebars::{
+        Context, Handlebars, Helper, HelperDef, JsonValue, RenderContext, RenderError, ScopedJson,
+    };
+    use wasmtime::{Engine, Instance, Module, Store};
+
+    /// A helper that hands its parameters to a WASM module. Every call gets
+    /// a fresh instance, so modules can't keep state between calls.
+    pub(super) struct WasmHelper {
+        pub(super) name: String,
+        pub(super) engine: Engine,
+        pub(super) module: Module,
+    }
+
+    impl WasmHelper {
+        fn run(&self, input: &[u8]) -> wasmtime::Result<String> {
+            let mut store = Store::new(&self.engine, ());
+            let instance = Instance::new(&mut store, &self.module, &[])?;
+            let memory = instance
+                .get_memory(&mut store, "memory")
+                .ok_or_else(|| wasmtime::Error::msg("the module doesn't export its memory"))?;
+            let alloc = instance.get_typed_func::<i32, i32>(&mut store, "alloc")?;
+            let render = instance.get_typed_func::<(i32, i32), i64>(&mut store, "render")?;
+
+            let ptr = alloc.call(&mut store, input.len() as i32)?;
+            memory.write(&mut store, ptr as u32 as usize, input)?;
+            let packed = render.call(&mut store, (ptr, input.len() as i32))? as u64;
+
+            let mut output = vec![0; (packed & 0xffff_ffff) as usize];
+            memory.read(&store, (packed >> 32) as usize, &mut output)?;
+            String::from_utf8(output).map_err(|_| wasmtime::Error::msg("the output isn't UTF-8"))
+        }
+    }
+
+    impl HelperDef for WasmHelper {
+        fn call_inner<'reg: 'rc, 'rc>(
+            &self,
+            h: &Helper<'reg, 'rc>,
+            _: &'reg Handlebars<'reg>,
+            _: &'rc Context,
+            _: &mut RenderContext<'reg, 'rc>,
+        ) -> Result<Option<ScopedJson<'reg, 'rc>>, RenderError> {
+            let args: Vec<_> = h.params().iter().map(|param| param.value()).collect();
+            let input = serde_json::to_vec(&args).map_err(|e| RenderError::new(e.to_string()))?;
+
+            self.run(&input)
+                .map(|output| Some(ScopedJson::Derived(JsonValue::String(output))))
+                .map_err(|e| RenderError::new(format!("The `{}` helper failed: {}.", self.name, e)))
+        }
+    }
+}
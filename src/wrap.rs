@@ -0,0 +1,44 @@
+/// Wraps a single line (without its line break) into `wrapped`, breaking it
+/// at the last whitespace that fits, or in the middle of a word that's too
+/// long to fit on a line of its own. The whitespace at a break is dropped.
+fn wrap_line(line: &str, width: usize, line_break: &str, wrapped: &mut String) {
+    let mut rest = line;
+    while rest.chars().count() > width {
+        // The character just past the limit may be whitespace to break at.
+        let (limit, next) = match rest.char_indices().nth(width) {
+            Some(next) => next,
+            None => break,
+        };
+        let fitting = &rest[..limit + next.len_utf8()];
+        let (part, remainder) = match fitting.rfind(char::is_whitespace) {
+            Some(i) if !rest[..i].trim_end().is_empty() => {
+                (rest[..i].trim_end(), rest[i..].trim_start())
+            }
+            _ => (&rest[..limit], &rest[limit..]),
+        };
+        wrapped.push_str(part);
+        wrapped.push_str(line_break);
+        rest = remainder;
+    }
+    wrapped.push_str(rest);
+}
+
+/// Hard-wraps `text` so that no line is longer than `width` characters, for
+/// `--wrap`. Existing line breaks are kept, and lines are broken at
+/// whitespace where possible. Breaks use `\r\n` on lines that end with it.
+pub(crate) fn wrap(text: &str, width: usize) -> String {
+    let mut wrapped = String::with_capacity(text.len());
+    for (index, line) in text.split('\n').enumerate() {
+        if index > 0 {
+            wrapped.push('\n');
+        }
+        match line.strip_suffix('\r') {
+            Some(line) => {
+                wrap_line(line, width, "\r\n", &mut wrapped);
+                wrapped.push('\r');
+            }
+            None => wrap_line(line, width, "\n", &mut wrapped),
+        }
+    }
+    wrapped
+}
@@ -1,22 +1,118 @@
-use handlebars::{Handlebars, TemplateFileError};
-use log::info;
+use fs2::FileExt;
+use log::{info, warn};
+use similar::TextDiff;
 
-use std::io::Write;
-use std::{collections::HashMap, fmt};
+use crate::archive::Archive;
+use crate::cache::Cache;
+use crate::encoding::OutputEncoding;
+use crate::engine::Engine;
+use crate::git::{self, Indexed};
+use crate::hook::PostHook;
+use crate::ownership::Owner;
+use crate::s3::Uploader;
+use crate::snippet::Snippet;
+use crate::transaction::Transaction;
+use crate::values::Mappings;
+use crate::wrap;
+
+use std::io::{self, ErrorKind, Seek, SeekFrom, Write};
+use std::time::{Duration, Instant};
+use std::{fmt, str::FromStr, thread};
 use std::{
-    fs::{DirBuilder, File, OpenOptions},
-    path::PathBuf,
+    fs::{self, DirBuilder, File, OpenOptions, Permissions},
+    path::{Component, Path, PathBuf},
 };
 
 #[derive(Debug)]
 pub(crate) enum ProgramError {
     FileNotFound(PathBuf),
     ReadFailed(PathBuf),
-    RenderError(String),
+    StdinUsedTwice(String),
+    NoConfigFile(Vec<PathBuf>),
+    DuplicateNamespace(String),
+    NamespaceShadowsKey(String),
+    AmbiguousTemplate(String, Vec<PathBuf>),
+    MissingValuesKey(PathBuf, String),
+    UnsetEnvironmentVariable(String),
+    RenderError(String, Option<Snippet>),
     MissingKey(String),
-    InvalidTemplate(String),
+    InvalidTemplate(String, Option<Snippet>),
+    InvalidPattern(String),
+    InputOutsideRoot(PathBuf, PathBuf),
+    UnknownHelper(String),
+    DisallowedHelpers(Vec<String>),
+    UnsafeHelpers(Vec<String>),
     CannotOpenFileForWriting(PathBuf),
+    CannotEncode(PathBuf, char, &'static str),
+    CannotLockOutputFile(PathBuf),
+    LockTimeout(PathBuf, Duration),
+    CannotReadOutputFile(PathBuf),
+    CannotSetPermissions(PathBuf),
+    DuplicateOutputFile(PathBuf),
+    OutputCollision(PathBuf, Vec<PathBuf>),
+    NoSyntaxTree,
+    ConflictingValue(String, PathBuf, String, String),
+    ComputedCycle(Vec<String>),
+    NoValuesFiles(PathBuf),
+    InvalidBase64(String),
+    FlattenedKeyCollision(String),
+    CannotCompute(String, String),
+    PathEscapesRoot(PathBuf, PathBuf),
+    CannotCoerceValue(String, String, &'static str),
+    #[cfg(all(unix, feature = "chown"))]
+    UnknownOwner(String),
+    #[cfg(all(unix, feature = "chown"))]
+    CannotChangeOwner(PathBuf, String),
+    #[cfg(not(all(unix, feature = "chown")))]
+    OwnershipUnsupported,
+    #[cfg(feature = "wasm-helpers")]
+    CannotLoadWasmHelper(PathBuf, String),
+    #[cfg(not(feature = "wasm-helpers"))]
+    WasmHelpersUnsupported,
     CannotCreateOutputDirectories(PathBuf),
+    PostHookFailed(PathBuf, String),
+    NoOutputName(PathBuf),
+    UnterminatedFrontMatter(PathBuf),
+    MissingProfile(PathBuf),
+    ProfileFileNotFound(PathBuf, String),
+    OutsideOutputDir(PathBuf),
+    CannotWriteArchive(PathBuf, String),
+    CannotMoveIntoPlace(PathBuf, String),
+    CannotParseValues(PathBuf, &'static str, String),
+    ValueCommandFailed(String, String),
+    #[cfg(feature = "s3")]
+    InvalidS3Endpoint(String, String),
+    #[cfg(feature = "s3")]
+    NoS3Credentials,
+    #[cfg(feature = "s3")]
+    CannotUpload(String, String, String),
+    #[cfg(not(feature = "s3"))]
+    S3Unsupported,
+    #[cfg(feature = "git")]
+    CannotCommit(String),
+    #[cfg(not(feature = "git"))]
+    GitUnsupported,
+    #[cfg(feature = "sqlite")]
+    CannotOpenDatabase(PathBuf, String),
+    #[cfg(feature = "sqlite")]
+    QueryFailed(PathBuf, String),
+    #[cfg(not(feature = "sqlite"))]
+    SqliteUnsupported,
+    #[cfg(feature = "remote")]
+    CannotFetch(String, String),
+    #[cfg(not(feature = "remote"))]
+    RemoteUnsupported,
+    ArchiveEntryNotFound(PathBuf, String),
+    CannotReadArchive(PathBuf, String),
+    #[cfg(not(feature = "tar"))]
+    TarUnsupported,
+}
+
+/// A snippet to show below an error message, if there is one.
+fn on_next_line(snippet: &Option<Snippet>) -> String {
+    snippet
+        .as_ref()
+        .map_or(String::new(), |snippet| format!("\n{}", snippet))
 }
 
 impl fmt::Display for ProgramError {
@@ -26,94 +122,1012 @@ impl fmt::Display for ProgramError {
             ProgramError::ReadFailed(path) => {
                 format!("Couldn't deserialize {:?} into the expected format.", path)
             }
-            ProgramError::RenderError(description) => {
-                format!("Template Render error: {}.", description)
+            ProgramError::AmbiguousTemplate(name, candidates) => format!(
+                "The template name {:?} matches more than one file: {}. Add the extension to pick one.",
+                name,
+                candidates
+                    .iter()
+                    .map(|candidate| format!("{:?}", candidate))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            ProgramError::DuplicateNamespace(name) => {
+                format!("The namespace {:?} is given more than once.", name)
+            }
+            ProgramError::NamespaceShadowsKey(name) => format!(
+                "The namespace {:?} has the same name as one of the mappings.",
+                name
+            ),
+            ProgramError::NoConfigFile(candidates) => format!(
+                "None of the config files exist: {}.",
+                candidates
+                    .iter()
+                    .map(|candidate| format!("{:?}", candidate))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            ProgramError::StdinUsedTwice(input) => format!(
+                "Can't read the template {:?} from stdin, because the mappings are read from there.",
+                input
+            ),
+            ProgramError::MissingValuesKey(path, key) => {
+                format!("Couldn't find {:?} in {:?}.", key, path)
+            }
+            ProgramError::UnsetEnvironmentVariable(name) => format!(
+                "The config file refers to the environment variable {:?}, which isn't set. Pass --allow-unset-env to treat unset variables as empty.",
+                name
+            ),
+            ProgramError::RenderError(description, snippet) => {
+                format!("Template Render error: {}.{}", description, on_next_line(snippet))
             }
             ProgramError::CannotOpenFileForWriting(path) => {
                 format!("Couldn't open output file {:?}.", path)
             }
+            ProgramError::CannotEncode(path, c, encoding) => format!(
+                "The result for {:?} contains {:?}, which can't be written as {}. Pass --lossy to replace such characters with '?'.",
+                path, c, encoding
+            ),
+            ProgramError::CannotLockOutputFile(path) => {
+                format!("Couldn't lock output file {:?}.", path)
+            }
+            ProgramError::LockTimeout(path, timeout) => format!(
+                "Gave up waiting for another process to finish writing {:?} after {} second(s).",
+                path,
+                timeout.as_secs()
+            ),
+            ProgramError::CannotReadOutputFile(path) => {
+                format!("Couldn't read existing output file {:?}.", path)
+            }
+            ProgramError::CannotSetPermissions(path) => {
+                format!("Couldn't set the permissions of output file {:?}.", path)
+            }
+            ProgramError::OutsideOutputDir(path) => format!(
+                "{:?} isn't inside the output directory, so it has no name to store it under.",
+                path
+            ),
+            ProgramError::ValueCommandFailed(key, reason) => {
+                format!("Couldn't get the value of {:?}: {}.", key, reason)
+            }
+            ProgramError::CannotWriteArchive(path, reason) => {
+                format!("Couldn't write the archive {:?}: {}.", path, reason)
+            }
+            ProgramError::CannotParseValues(path, format, reason) => {
+                format!("Couldn't read {:?} as {}: {}.", path, format, reason)
+            }
+            ProgramError::CannotMoveIntoPlace(path, reason) => format!(
+                "Couldn't move the staged result for {:?} into place: {}. The output files before it have been replaced, and the ones after it haven't.",
+                path, reason
+            ),
+            ProgramError::DuplicateOutputFile(path) => format!(
+                "More than one result would be written to {:?}; make sure the output name is unique for every entry.",
+                path
+            ),
+            ProgramError::OutputCollision(path, inputs) => format!(
+                "The templates {} would all be written to {:?}. Pass --preserve-tree to keep their directories apart in the output, or give each of them its own output name.",
+                inputs
+                    .iter()
+                    .map(|input| format!("{:?}", input))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                path
+            ),
+            ProgramError::NoSyntaxTree => String::from(
+                "--dump-ast needs the handlebars engine; envsubst templates aren't parsed into a tree.",
+            ),
+            ProgramError::ConflictingValue(key, path, value, earlier) => format!(
+                "{:?} sets {:?} to {}, but an earlier replacements file sets it to {}. With --values-merge-strategy strict, the files can't disagree.",
+                path, key, value, earlier
+            ),
+            ProgramError::ComputedCycle(keys) => format!(
+                "The computed values depend on each other in a cycle: {}.",
+                keys.join(" -> ")
+            ),
+            ProgramError::NoValuesFiles(dir) => format!(
+                "There are no YAML files in {:?} to use with --values-dir.",
+                dir
+            ),
+            ProgramError::InvalidBase64(reason) => format!(
+                "The mappings given with --values-b64 aren't valid base64: {}.",
+                reason
+            ),
+            ProgramError::FlattenedKeyCollision(key) => format!(
+                "More than one value would be flattened into {:?}; rename one of them, or pick another --flatten-separator.",
+                key
+            ),
+            ProgramError::CannotCompute(key, reason) => {
+                format!("Couldn't compute {:?}: {}", key, reason)
+            }
+            ProgramError::CannotCoerceValue(key, value, value_type) => format!(
+                "The value {:?} of {:?} isn't a valid {}.",
+                value, key, value_type
+            ),
+            ProgramError::PathEscapesRoot(path, root) => format!(
+                "Refusing to write {:?} because it resolves to a path outside of {:?}.",
+                path, root
+            ),
+            #[cfg(all(unix, feature = "chown"))]
+            ProgramError::UnknownOwner(owner) => format!(
+                "Couldn't find the user or group in {:?}; expected user:group, user, or :group.",
+                owner
+            ),
+            #[cfg(all(unix, feature = "chown"))]
+            ProgramError::CannotChangeOwner(path, reason) => {
+                format!("Couldn't change the owner of {:?}: {}.", path, reason)
+            }
+            #[cfg(not(all(unix, feature = "chown")))]
+            ProgramError::OwnershipUnsupported => String::from(
+                "Changing the owner of output files requires a Unix build with the `chown` feature.",
+            ),
+            #[cfg(feature = "wasm-helpers")]
+            ProgramError::CannotLoadWasmHelper(path, reason) => {
+                format!("Couldn't load the helper in {:?}: {}.", path, reason)
+            }
+            #[cfg(not(feature = "wasm-helpers"))]
+            ProgramError::WasmHelpersUnsupported => String::from(
+                "Loading helpers from a directory requires a build with the `wasm-helpers` feature.",
+            ),
+            #[cfg(feature = "s3")]
+            ProgramError::InvalidS3Endpoint(endpoint, reason) => {
+                format!("The S3 endpoint {:?} isn't valid: {}.", endpoint, reason)
+            }
+            #[cfg(feature = "s3")]
+            ProgramError::NoS3Credentials => String::from(
+                "Couldn't find credentials for S3. Set AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY, or add them to ~/.aws/credentials.",
+            ),
+            #[cfg(feature = "s3")]
+            ProgramError::CannotUpload(bucket, key, reason) => format!(
+                "Couldn't upload {:?} to the bucket {:?}: {}.",
+                key, bucket, reason
+            ),
+            #[cfg(not(feature = "s3"))]
+            ProgramError::S3Unsupported => String::from(
+                "Uploading to S3 requires a build with the `s3` feature.",
+            ),
+            #[cfg(feature = "git")]
+            ProgramError::CannotCommit(reason) => {
+                format!("Couldn't commit the output files: {}.", reason)
+            }
+            #[cfg(not(feature = "git"))]
+            ProgramError::GitUnsupported => String::from(
+                "Committing output files requires a build with the `git` feature.",
+            ),
+            #[cfg(feature = "sqlite")]
+            ProgramError::CannotOpenDatabase(path, reason) => {
+                format!("Couldn't open the database {:?}: {}.", path, reason)
+            }
+            #[cfg(feature = "sqlite")]
+            ProgramError::QueryFailed(path, reason) => format!(
+                "Couldn't read mappings with the --values-query for {:?}: {}.",
+                path, reason
+            ),
+            #[cfg(not(feature = "sqlite"))]
+            ProgramError::SqliteUnsupported => String::from(
+                "Reading mappings from SQLite requires a build with the `sqlite` feature.",
+            ),
+            #[cfg(feature = "remote")]
+            ProgramError::CannotFetch(url, reason) => {
+                format!("Couldn't fetch the mappings from {:?}: {}.", url, reason)
+            }
+            #[cfg(not(feature = "remote"))]
+            ProgramError::RemoteUnsupported => String::from(
+                "Fetching mappings from a URL requires a build with the `remote` feature.",
+            ),
+            ProgramError::ArchiveEntryNotFound(archive, name) => {
+                format!("There's no file {:?} in the archive {:?}.", name, archive)
+            }
+            ProgramError::CannotReadArchive(archive, reason) => {
+                format!("Couldn't read the archive {:?}: {}.", archive, reason)
+            }
+            #[cfg(not(feature = "tar"))]
+            ProgramError::TarUnsupported => String::from(
+                "Reading templates from a tar archive requires a build with the `tar` feature.",
+            ),
             ProgramError::CannotCreateOutputDirectories(path) => {
                 format!("Failed to create directory {:?}", path)
             }
+            ProgramError::PostHookFailed(path, reason) => {
+                format!("The post-hook failed for {:?}: {}.", path, reason)
+            }
+            ProgramError::NoOutputName(path) => format!(
+                "Can't name the output file after {:?}; pass -o to give it a name.",
+                path
+            ),
+            ProgramError::MissingProfile(path) => format!(
+                "The replacements file {:?} depends on the profile; pass --profile to pick one.",
+                path
+            ),
+            ProgramError::ProfileFileNotFound(path, profile) => format!(
+                "Couldn't find the replacements file {:?} for the profile {:?}.",
+                path, profile
+            ),
+            ProgramError::UnterminatedFrontMatter(path) => format!(
+                "The front matter of {:?} isn't closed with a \"---\" line.",
+                path
+            ),
             ProgramError::MissingKey(msg) => msg.clone(),
-            ProgramError::InvalidTemplate(reason) => {
+            ProgramError::InvalidTemplate(reason, snippet) => {
                 format!(
-                    "The provided template file is invalid and cannot be parsed correctly: {}",
-                    reason
+                    "The provided template file is invalid and cannot be parsed correctly: {}{}",
+                    reason,
+                    on_next_line(snippet)
                 )
             }
+            ProgramError::InputOutsideRoot(path, root) => {
+                format!("The input file {:?} isn't inside {:?}.", path, root)
+            }
+            ProgramError::UnknownHelper(name) => {
+                format!("There is no helper called {:?} to allow.", name)
+            }
+            ProgramError::DisallowedHelpers(names) => format!(
+                "The template uses helpers that aren't allowed: {}.",
+                names.join(", ")
+            ),
+            ProgramError::UnsafeHelpers(names) => format!(
+                "The template uses helpers that read the environment or the file system, which are disabled with --safe: {}.",
+                names.join(", ")
+            ),
+            ProgramError::InvalidPattern(reason) => {
+                format!("Invalid input file pattern {}.", reason)
+            }
         };
         write!(f, "{}", msg)
     }
 }
 
 pub(crate) struct Configuration {
-    pub(crate) template: File,
-    pub(crate) mappings: HashMap<String, String>,
+    pub(crate) template: String,
+    /// Where the template was read from.
+    pub(crate) template_path: PathBuf,
+    pub(crate) mappings: Mappings,
     pub(crate) output_file: PathBuf,
+    /// The directory the output file is written to (or below), which its
+    /// name in a `--zip` archive is relative to.
+    pub(crate) output_dir: PathBuf,
+    /// With `--concat`, the other templates that are rendered into the same
+    /// output file, after this one.
+    pub(crate) appended: Vec<Configuration>,
+    /// What goes between this template's result and each appended one's.
+    pub(crate) separator: String,
+    /// The column to hard-wrap the result at, if any.
+    pub(crate) wrap: Option<usize>,
+}
+
+impl Configuration {
+    /// This configuration followed by the ones appended to it.
+    pub(crate) fn parts(&self) -> impl Iterator<Item = &Configuration> {
+        std::iter::once(self).chain(&self.appended)
+    }
+}
+
+/// What happened to a single template during a run.
+pub(crate) enum Outcome {
+    Written {
+        output_file: PathBuf,
+        bytes: usize,
+    },
+    Previewed(PathBuf),
+    /// Added to the `--zip` archive under `name`, instead of being written.
+    Archived {
+        name: String,
+        bytes: usize,
+    },
+    /// Uploaded to `key` with `--output-s3`, instead of being written.
+    Uploaded {
+        key: String,
+        bytes: usize,
+    },
+    /// Written next to the output file with `--transactional`, to be moved
+    /// into place once every template has rendered.
+    Staged {
+        output_file: PathBuf,
+        bytes: usize,
+    },
+    /// Skipped because the output is already up to date, according to the
+    /// cache or to its current contents.
+    Unchanged(PathBuf),
+    Compared {
+        output_file: PathBuf,
+        changed: bool,
+    },
+    Verified {
+        output_file: PathBuf,
+        status: Status,
+    },
+}
+
+/// How an existing output file compares to a fresh render.
+pub(crate) enum Status {
+    UpToDate,
+    Stale,
+    Missing,
+}
+
+/// Settings that control how rendered results are written to disk.
+#[derive(Default)]
+pub(crate) struct WriteOptions {
+    /// The permission bits to give output files. If unset, new files get the
+    /// platform default (on Unix, as limited by the process umask).
+    pub(crate) mode: Option<u32>,
+    /// The user and group to give output files to once they've been written.
+    pub(crate) owner: Option<Owner>,
+    /// If set, output files are locked while they're written, waiting at
+    /// most this long for other processes to release them.
+    pub(crate) lock_timeout: Option<Duration>,
+    /// How many times to retry writing a file after a transient failure.
+    pub(crate) write_retries: u32,
+    /// How long to wait before the first retry. The delay doubles with every
+    /// further retry.
+    pub(crate) retry_delay: Duration,
+    /// The encoding to write output files in.
+    pub(crate) encoding: OutputEncoding,
+    /// Whether to replace characters the encoding can't represent instead of
+    /// failing.
+    pub(crate) lossy: bool,
+    /// If set, outputs whose template and mappings haven't changed since
+    /// they were last written are skipped.
+    pub(crate) cache: Option<Cache>,
+    /// Whether to leave output files that already have the rendered contents
+    /// alone, instead of writing them again.
+    pub(crate) only_if_changed: bool,
+    /// A command to run on every output file once it's been written.
+    pub(crate) post_hook: Option<PostHook>,
+    /// Whether a failing post-hook fails the output, instead of only being
+    /// warned about.
+    pub(crate) post_hook_strict: bool,
 }
 
+impl RenderResult {
+    /// The bytes of the result, as they should end up in the output file.
+    fn encode(&self, options: &WriteOptions) -> Result<Vec<u8>, ProgramError> {
+        options
+            .encoding
+            .encode(&self.result, options.lossy)
+            .map_err(|c| {
+                ProgramError::CannotEncode(self.output_file.clone(), c, options.encoding.name())
+            })
+    }
+}
+
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
 struct RenderResult {
     result: String,
     output_file: PathBuf,
 }
 
-fn render_template(mut config: Configuration) -> Result<RenderResult, ProgramError> {
-    let mut handlebars = Handlebars::new();
-    handlebars.set_strict_mode(true);
+/// Renders a template (and the ones appended to it) without writing
+/// anything, returning the result.
+pub(crate) fn render_to_string(
+    config: &Configuration,
+    engine: &dyn Engine,
+) -> Result<String, ProgramError> {
+    let mut result = engine.render(&config.template, &config.mappings)?;
+    for appended in &config.appended {
+        result.push_str(&config.separator);
+        result.push_str(&render_to_string(appended, engine)?);
+    }
+    Ok(result)
+}
 
-    handlebars
-        .register_template_source("input", &mut config.template)
-        .map_err(|e| match e {
-            TemplateFileError::TemplateError(err) => {
-                ProgramError::InvalidTemplate(err.reason.to_string())
+/// The keys that a template substitutes directly, without rendering it.
+pub(crate) fn referenced_keys(
+    config: &Configuration,
+    engine: &dyn Engine,
+) -> Result<Vec<String>, ProgramError> {
+    engine.referenced_keys(&config.template)
+}
+
+fn render_template(
+    config: Configuration,
+    engine: &dyn Engine,
+) -> Result<RenderResult, ProgramError> {
+    render_to_string(&config, engine).map(|result| RenderResult {
+        result: match config.wrap {
+            Some(width) => wrap::wrap(&result, width),
+            None => result,
+        },
+        output_file: config.output_file,
+    })
+}
+
+#[cfg_attr(not(unix), allow(unused_variables))]
+fn open_output_file(output_file: &Path, options: &WriteOptions) -> io::Result<File> {
+    let mut open_options = OpenOptions::new();
+    // A locked file is only truncated once the lock is held.
+    open_options
+        .create(true)
+        .write(true)
+        .truncate(options.lock_timeout.is_none());
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        if let Some(mode) = options.mode {
+            open_options.mode(mode);
+        }
+    }
+
+    open_options.open(output_file)
+}
+
+// The mode passed when opening the file only applies to newly created files
+// and is still subject to the umask, so set it explicitly as well.
+#[cfg(unix)]
+fn set_permissions(
+    file: &File,
+    output_file: &Path,
+    options: &WriteOptions,
+) -> Result<(), ProgramError> {
+    use std::os::unix::fs::PermissionsExt;
+    match options.mode {
+        Some(mode) => file
+            .set_permissions(Permissions::from_mode(mode))
+            .map_err(|_| ProgramError::CannotSetPermissions(output_file.to_path_buf())),
+        None => Ok(()),
+    }
+}
+
+#[cfg(not(unix))]
+fn set_permissions(_: &File, _: &Path, _: &WriteOptions) -> Result<(), ProgramError> {
+    Ok(())
+}
+
+/// Takes an exclusive (advisory) lock on an output file, so that concurrent
+/// runs don't write to the same file at the same time. The lock is released
+/// when the file is closed.
+fn lock_output_file(
+    file: &File,
+    output_file: &Path,
+    timeout: Duration,
+) -> Result<(), ProgramError> {
+    let start = Instant::now();
+    loop {
+        match FileExt::try_lock_exclusive(file) {
+            Ok(()) => return Ok(()),
+            Err(e) if e.kind() != fs2::lock_contended_error().kind() => {
+                return Err(ProgramError::CannotLockOutputFile(
+                    output_file.to_path_buf(),
+                ))
             }
-            TemplateFileError::IOError(_, _) => {
-                ProgramError::RenderError(String::from("I/O Error when rendering template."))
+            Err(_) if start.elapsed() >= timeout => {
+                return Err(ProgramError::LockTimeout(
+                    output_file.to_path_buf(),
+                    timeout,
+                ))
             }
-        })?;
-    handlebars
-        .render("input", &config.mappings)
-        .map_err(|e| {
-            if e.desc.starts_with("Variable") {
-                ProgramError::MissingKey(e.desc)
-            } else if e.desc.starts_with("Template not found") {
-                ProgramError::InvalidTemplate("Couldn't recognize template.".to_string())
-            } else {
-                ProgramError::RenderError(e.desc)
+            Err(_) => thread::sleep(LOCK_POLL_INTERVAL),
+        }
+    }
+}
+
+fn is_transient(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        ErrorKind::Interrupted | ErrorKind::WouldBlock | ErrorKind::TimedOut
+    )
+}
+
+/// Writes `contents` to `file`, starting over after transient failures (as
+/// seen on some network filesystems) up to `options.write_retries` times.
+fn write_with_retries(
+    file: &mut File,
+    contents: &[u8],
+    output_file: &Path,
+    options: &WriteOptions,
+) -> io::Result<()> {
+    let mut delay = options.retry_delay;
+    for _ in 0..options.write_retries {
+        match file.write_all(contents) {
+            Err(e) if is_transient(&e) => {
+                warn!(
+                    "Writing {:?} failed ({}); retrying in {:?}.",
+                    output_file, e, delay
+                );
+                thread::sleep(delay);
+                delay *= 2;
+                file.set_len(0)?;
+                file.seek(SeekFrom::Start(0))?;
             }
-        })
-        .map(|result| RenderResult {
-            result,
-            output_file: config.output_file,
-        })
+            result => return result,
+        }
+    }
+
+    file.write_all(contents)
 }
 
-fn write_template_file(
-    RenderResult {
-        result,
+/// Whether `output_file` already exists as something other than a regular
+/// file, such as a named pipe, `/dev/stdout`, or `/dev/null`.
+pub(crate) fn is_special_file(output_file: &Path) -> bool {
+    fs::metadata(output_file)
+        .map(|metadata| !metadata.is_file() && !metadata.is_dir())
+        .unwrap_or(false)
+}
+
+/// Writes to a pipe or device as it is: there's nothing to create,
+/// truncate, lock, or change the permissions or owner of, and a failed
+/// write can't be started over.
+fn write_special_file(output_file: PathBuf, contents: &[u8]) -> Result<Outcome, ProgramError> {
+    OpenOptions::new()
+        .write(true)
+        .open(&output_file)
+        .and_then(|mut file| file.write_all(contents))
+        .map_err(|_| ProgramError::CannotOpenFileForWriting(output_file.clone()))?;
+
+    Ok(Outcome::Written {
         output_file,
-    }: RenderResult,
-) -> Result<PathBuf, ProgramError> {
+        bytes: contents.len(),
+    })
+}
+
+/// Whether `output_file` already has `contents` and, with `--only-if-changed`,
+/// can be left alone.
+fn is_unchanged(output_file: &Path, contents: &[u8], options: &WriteOptions) -> bool {
+    let unchanged =
+        options.only_if_changed && fs::read(output_file).is_ok_and(|existing| existing == contents);
+    if unchanged {
+        info!(
+            "{:?} already has the rendered contents; not writing it.",
+            output_file
+        );
+    }
+    unchanged
+}
+
+fn create_output_directories(output_file: &Path) -> Result<(), ProgramError> {
     info!("Creating necessary directories.");
 
-    if let Some(parent_dir) = &output_file.as_path().parent() {
+    if let Some(parent_dir) = output_file.parent() {
         DirBuilder::new()
             .recursive(true)
-            .create(&parent_dir)
+            .create(parent_dir)
             .map_err(|_| ProgramError::CannotCreateOutputDirectories(parent_dir.to_path_buf()))?;
     };
+    Ok(())
+}
 
-    OpenOptions::new()
-        .create(true)
-        .write(true)
-        .open(&output_file)
-        .and_then(|mut f| f.write(&result.as_bytes()))
+/// Writes `contents` to the newly opened (and empty) `file` at `path`, and
+/// gives it the permissions and owner from `options`.
+fn write_contents(
+    file: &mut File,
+    path: &Path,
+    contents: &[u8],
+    options: &WriteOptions,
+) -> Result<(), ProgramError> {
+    set_permissions(file, path, options)?;
+    write_with_retries(file, contents, path, options)
+        .map_err(|_| ProgramError::CannotOpenFileForWriting(path.to_path_buf()))?;
+
+    if let Some(owner) = &options.owner {
+        owner.apply(path)?;
+    }
+    Ok(())
+}
+
+fn write_template_file(
+    render_result: RenderResult,
+    options: &WriteOptions,
+) -> Result<Outcome, ProgramError> {
+    let contents = render_result.encode(options)?;
+    let output_file = render_result.output_file;
+    if is_special_file(&output_file) {
+        return write_special_file(output_file, &contents);
+    }
+    if is_unchanged(&output_file, &contents, options) {
+        return Ok(Outcome::Unchanged(output_file));
+    }
+
+    create_output_directories(&output_file)?;
+    let mut file = open_output_file(&output_file, options)
         .map_err(|_| ProgramError::CannotOpenFileForWriting(output_file.clone()))?;
+    if let Some(timeout) = options.lock_timeout {
+        lock_output_file(&file, &output_file, timeout)?;
+        file.set_len(0)
+            .map_err(|_| ProgramError::CannotOpenFileForWriting(output_file.clone()))?;
+    }
+    write_contents(&mut file, &output_file, &contents, options)?;
+
+    if let Some(hook) = &options.post_hook {
+        hook.run(&output_file, options.post_hook_strict)?;
+    }
+
+    Ok(Outcome::Written {
+        output_file,
+        bytes: contents.len(),
+    })
+}
+
+fn read_existing_output(output_file: &Path) -> Result<Vec<u8>, ProgramError> {
+    match fs::read(output_file) {
+        Ok(contents) => Ok(contents),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+        Err(_) => Err(ProgramError::CannotReadOutputFile(
+            output_file.to_path_buf(),
+        )),
+    }
+}
+
+/// What `--diff` compares rendered results against.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum DiffBase {
+    /// The output files as they are on disk.
+    WorkingTree,
+    /// The output files as they are in the git index.
+    Git,
+}
+
+impl FromStr for DiffBase {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "worktree" => Ok(DiffBase::WorkingTree),
+            "git" => Ok(DiffBase::Git),
+            other => Err(format!(
+                "Unknown diff base {:?}; expected one of \"worktree\" or \"git\".",
+                other
+            )),
+        }
+    }
+}
+
+/// Reads the current version of an output file, along with the name to give
+/// it in the diff header: `/dev/null` if there isn't one.
+fn read_diff_base(output_file: &Path, base: DiffBase) -> Result<(Vec<u8>, String), ProgramError> {
+    if let DiffBase::Git = base {
+        match git::indexed(output_file) {
+            Indexed::Contents(contents) => {
+                return Ok((contents, format!("{} (git)", output_file.display())))
+            }
+            Indexed::Untracked => return Ok((Vec::new(), String::from("/dev/null"))),
+            Indexed::Unavailable => warn!(
+                "{:?} isn't in a git repository; comparing against the file on disk instead.",
+                output_file
+            ),
+        }
+    }
+
+    let header = if output_file.exists() {
+        output_file.display().to_string()
+    } else {
+        String::from("/dev/null")
+    };
+    read_existing_output(output_file).map(|contents| (contents, header))
+}
 
-    Ok(output_file)
+/// Compares a fresh render, encoded the way `options` would write it, with
+/// the current version of its output file, printing a diff if they differ.
+fn diff_template_file(
+    render_result: RenderResult,
+    base: DiffBase,
+    options: &WriteOptions,
+) -> Result<Outcome, ProgramError> {
+    let expected = render_result.encode(options)?;
+    let output_file = render_result.output_file;
+    let (existing, old_header) = read_diff_base(&output_file, base)?;
+    let changed = existing != expected;
+
+    if changed {
+        let existing = options.encoding.decode(&existing);
+        let expected = options.encoding.decode(&expected);
+        print!(
+            "{}",
+            TextDiff::from_lines(&existing, &expected)
+                .unified_diff()
+                .header(&old_header, &output_file.display().to_string())
+        );
+    }
+
+    Ok(Outcome::Compared {
+        output_file,
+        changed,
+    })
+}
+
+fn verify_template_file(
+    render_result: RenderResult,
+    options: &WriteOptions,
+) -> Result<Outcome, ProgramError> {
+    let expected = render_result.encode(options)?;
+    let output_file = render_result.output_file;
+    let status = match fs::read(&output_file) {
+        Ok(existing) if existing == expected => Status::UpToDate,
+        Ok(_) => Status::Stale,
+        Err(e) if e.kind() == ErrorKind::NotFound => Status::Missing,
+        Err(_) => return Err(ProgramError::CannotReadOutputFile(output_file)),
+    };
+
+    Ok(Outcome::Verified {
+        output_file,
+        status,
+    })
+}
+
+/// Identifies what the output of `config` is rendered from, and how it's
+/// written, for the cache.
+/// Outputs of templates that read anything else, like the environment,
+/// can't be cached and have no key.
+fn cache_key(
+    config: &Configuration,
+    engine: &dyn Engine,
+    options: &WriteOptions,
+) -> Option<String> {
+    if !engine.is_cacheable(&config.template) {
+        return None;
+    }
+    let mut settings = format!(
+        "{}:{}:{}",
+        engine.settings(),
+        options.encoding.name(),
+        options.lossy
+    );
+    if let Some(width) = config.wrap {
+        settings.push_str(&format!(":wrap={}", width));
+    }
+    if let Some(mode) = options.mode {
+        settings.push_str(&format!(":mode={:o}", mode));
+    }
+    if let Some(owner) = &options.owner {
+        settings.push_str(&format!(":owner={:?}", owner));
+    }
+    for appended in &config.appended {
+        settings.push_str(&format!(
+            "\0{}\0{}",
+            config.separator,
+            cache_key(appended, engine, options)?
+        ));
+    }
+    Some(Cache::key(&config.template, &config.mappings, &settings))
 }
 
-pub(crate) fn render(config: Configuration) -> Result<PathBuf, ProgramError> {
-    render_template(config).and_then(write_template_file)
+pub(crate) fn render(
+    config: Configuration,
+    engine: &dyn Engine,
+    options: &WriteOptions,
+) -> Result<Outcome, ProgramError> {
+    let cached = options
+        .cache
+        .as_ref()
+        .and_then(|cache| Some((cache, cache_key(&config, engine, options)?)));
+    let (cache, key) = match cached {
+        Some(cached) => cached,
+        None => {
+            return render_template(config, engine)
+                .and_then(|result| write_template_file(result, options))
+        }
+    };
+
+    if cache.is_fresh(&config.output_file, &key) {
+        return Ok(Outcome::Unchanged(config.output_file));
+    }
+
+    let outcome =
+        render_template(config, engine).and_then(|result| write_template_file(result, options))?;
+    match &outcome {
+        Outcome::Written { output_file, .. } | Outcome::Unchanged(output_file) => {
+            cache.store(output_file, &key)
+        }
+        _ => {}
+    }
+    Ok(outcome)
+}
+
+/// Renders a template and writes the result next to its output file, to be
+/// moved into place when `transaction` is committed. The output file itself
+/// isn't touched.
+pub(crate) fn stage(
+    config: Configuration,
+    engine: &dyn Engine,
+    options: &WriteOptions,
+    transaction: &mut Transaction,
+) -> Result<Outcome, ProgramError> {
+    let key = options
+        .cache
+        .as_ref()
+        .and_then(|_| cache_key(&config, engine, options));
+    if let (Some(cache), Some(key)) = (&options.cache, &key) {
+        if cache.is_fresh(&config.output_file, key) {
+            return Ok(Outcome::Unchanged(config.output_file));
+        }
+    }
+
+    let render_result = render_template(config, engine)?;
+    let contents = render_result.encode(options)?;
+    let output_file = render_result.output_file;
+    let bytes = contents.len();
+    if is_special_file(&output_file) {
+        transaction.add_special(output_file.clone(), contents);
+        return Ok(Outcome::Staged { output_file, bytes });
+    }
+    if is_unchanged(&output_file, &contents, options) {
+        if let (Some(cache), Some(key)) = (&options.cache, &key) {
+            cache.store(&output_file, key);
+        }
+        return Ok(Outcome::Unchanged(output_file));
+    }
+
+    create_output_directories(&output_file)?;
+    let temporary = Transaction::temporary_path(&output_file);
+    let written = open_output_file(&temporary, options)
+        .and_then(|file| file.set_len(0).map(|()| file))
+        .map_err(|_| ProgramError::CannotOpenFileForWriting(temporary.clone()))
+        .and_then(|mut file| write_contents(&mut file, &temporary, &contents, options));
+    if written.is_err() {
+        let _ = fs::remove_file(&temporary);
+    }
+    written?;
+
+    transaction.add(output_file.clone(), temporary, key);
+    Ok(Outcome::Staged { output_file, bytes })
+}
+
+/// Prints how an existing output file differs from a fresh render, encoded
+/// the way `options` would write it.
+pub(crate) fn diff(
+    config: Configuration,
+    engine: &dyn Engine,
+    base: DiffBase,
+    options: &WriteOptions,
+) -> Result<Outcome, ProgramError> {
+    render_template(config, engine).and_then(|result| diff_template_file(result, base, options))
+}
+
+/// Checks an existing output file against a fresh render, encoded the way
+/// `options` would write it.
+pub(crate) fn verify(
+    config: Configuration,
+    engine: &dyn Engine,
+    options: &WriteOptions,
+) -> Result<Outcome, ProgramError> {
+    render_template(config, engine).and_then(|result| verify_template_file(result, options))
+}
+
+/// The path of an output file relative to the output directory, with its
+/// parts joined by slashes, for storing it somewhere other than the file
+/// system. Paths that end up outside of the output directory are refused.
+fn output_name(config: &Configuration) -> Result<String, ProgramError> {
+    let outside = || ProgramError::OutsideOutputDir(config.output_file.clone());
+    let name = config
+        .output_file
+        .strip_prefix(&config.output_dir)
+        .map_err(|_| outside())?;
+
+    let mut parts = Vec::new();
+    for component in name.components() {
+        match component {
+            Component::Normal(part) => parts.push(part.to_str().ok_or_else(outside)?),
+            Component::CurDir => {}
+            _ => return Err(outside()),
+        }
+    }
+    Ok(parts.join("/"))
+}
+
+/// Renders a template and adds the result to `archive`, at the path of its
+/// output file relative to the output directory. Only the encoding settings
+/// of `options` apply; the rest are about writing files.
+pub(crate) fn archive(
+    config: Configuration,
+    engine: &dyn Engine,
+    options: &WriteOptions,
+    archive: &mut Archive,
+) -> Result<Outcome, ProgramError> {
+    let name = output_name(&config)?;
+    let contents = render_template(config, engine)?.encode(options)?;
+    let bytes = contents.len();
+    archive.add(name.clone(), contents)?;
+    Ok(Outcome::Archived { name, bytes })
+}
+
+/// Renders a template and uploads the result with `uploader`, under the path
+/// of its output file relative to the output directory. As with `archive`,
+/// only the encoding settings of `options` apply.
+pub(crate) fn upload(
+    config: Configuration,
+    engine: &dyn Engine,
+    options: &WriteOptions,
+    uploader: &Uploader,
+) -> Result<Outcome, ProgramError> {
+    let name = output_name(&config)?;
+    let contents = render_template(config, engine)?.encode(options)?;
+    let key = uploader.upload(&name, &contents)?;
+    Ok(Outcome::Uploaded {
+        key,
+        bytes: contents.len(),
+    })
+}
+
+pub(crate) fn preview(
+    config: Configuration,
+    engine: &dyn Engine,
+    show_output: bool,
+) -> Result<Outcome, ProgramError> {
+    render_template(config, engine).map(
+        |RenderResult {
+             result,
+             output_file,
+         }| {
+            if show_output {
+                eprintln!("==> {} <==", output_file.display());
+                eprintln!("{}", result);
+            }
+            Outcome::Previewed(output_file)
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(output_file: &Path, result: &str) -> Result<Outcome, ProgramError> {
+        let render_result = RenderResult {
+            result: result.to_string(),
+            output_file: output_file.to_path_buf(),
+        };
+        write_template_file(render_result, &WriteOptions::default())
+    }
+
+    fn compare(output_file: &Path, result: &str, options: &WriteOptions) -> bool {
+        let render_result = RenderResult {
+            result: result.to_string(),
+            output_file: output_file.to_path_buf(),
+        };
+        match diff_template_file(render_result, DiffBase::WorkingTree, options) {
+            Ok(Outcome::Compared { changed, .. }) => changed,
+            _ => panic!("Couldn't compare {:?}", output_file),
+        }
+    }
+
+    #[test]
+    fn diffs_compare_outputs_in_their_encoding() {
+        let dir = tempfile::tempdir().unwrap();
+        let output_file = dir.path().join("menu.txt");
+        fs::write(&output_file, b"caf\xe9\n").unwrap();
+        let options = WriteOptions {
+            encoding: OutputEncoding::Latin1,
+            ..WriteOptions::default()
+        };
+
+        assert!(!compare(&output_file, "café\n", &options));
+        assert!(compare(&output_file, "cafe\n", &options));
+        assert!(compare(&output_file, "café\n", &WriteOptions::default()));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn writes_to_devices_directly() {
+        assert!(is_special_file(Path::new("/dev/null")));
+        assert!(matches!(
+            write(Path::new("/dev/null"), "discarded"),
+            Ok(Outcome::Written { bytes: 9, .. })
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn writes_to_named_pipes_directly() {
+        use nix::{sys::stat::Mode, unistd::mkfifo};
+        use std::io::Read;
+
+        let dir = tempfile::tempdir().unwrap();
+        let fifo = dir.path().join("fifo");
+        mkfifo(&fifo, Mode::S_IRUSR | Mode::S_IWUSR).unwrap();
+        assert!(is_special_file(&fifo));
+
+        let reader = {
+            let fifo = fifo.clone();
+            thread::spawn(move || {
+                let mut contents = String::new();
+                File::open(fifo)
+                    .and_then(|mut file| file.read_to_string(&mut contents))
+                    .map(|_| contents)
+            })
+        };
+        assert!(write(&fifo, "through the pipe").is_ok());
+        assert_eq!(reader.join().unwrap().unwrap(), "through the pipe");
+
+        // Nothing was written next to the pipe to be renamed over it.
+        let entries: Vec<_> = fs::read_dir(dir.path()).unwrap().collect();
+        assert_eq!(entries.len(), 1);
+    }
 }